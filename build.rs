@@ -0,0 +1,92 @@
+use std::{env, fs, path::Path};
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+//Void and cluster is too slow to run on every start, so the blue noise table is made once per build
+fn main(){
+    let noise = blue_noise(BLUE_NOISE_SIZE);
+    let bytes: Vec<u8> = noise.iter().flat_map(|v| v.to_le_bytes()).collect();
+    let path = Path::new(&env::var("OUT_DIR").unwrap()).join("blue_noise.bin");
+    fs::write(path, bytes).unwrap();
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+//Has to match BLUE_NOISE_SIZE in ray_tracer.wgsl
+const BLUE_NOISE_SIZE: usize = 64;
+const SIGMA: f32 = 1.5;
+const RADIUS: i32 = 6;
+
+//Tileable blue noise made with void and cluster (Ulichney 1993), each value is the pixel's rank in [0,1)
+fn blue_noise(size: usize) -> Vec<f32>{
+    let n = size * size;
+    let mut rng = StdRng::seed_from_u64(0x5eed);
+
+    let mut pattern = vec![false; n];
+    for _ in 0..n / 10{
+        pattern[rng.gen_range(0..n)] = true;
+    }
+    let mut energy = vec![0.0; n];
+    for (i, _) in pattern.iter().enumerate().filter(|(_, p)| **p){
+        splat(&mut energy, size, i, 1.0);
+    }
+    //Swap the tightest cluster into the largest void until the initial pattern is evenly spread
+    loop{
+        let cluster = tightest_cluster(&energy, &pattern);
+        pattern[cluster] = false;
+        splat(&mut energy, size, cluster, -1.0);
+        let void = largest_void(&energy, &pattern);
+        if void == cluster{
+            pattern[cluster] = true;
+            splat(&mut energy, size, cluster, 1.0);
+            break;
+        }
+        pattern[void] = true;
+        splat(&mut energy, size, void, 1.0);
+    }
+    let ones = pattern.iter().filter(|p| **p).count();
+    let mut ranks = vec![0usize; n];
+
+    //Phase 1: remove the initial points tightest cluster first
+    let mut removing = pattern.clone();
+    let mut removing_energy = energy.clone();
+    for rank in (0..ones).rev(){
+        let cluster = tightest_cluster(&removing_energy, &removing);
+        removing[cluster] = false;
+        splat(&mut removing_energy, size, cluster, -1.0);
+        ranks[cluster] = rank;
+    }
+    //Phase 2 and 3: fill the largest voids until every pixel has a rank
+    for rank in ones..n{
+        let void = largest_void(&energy, &pattern);
+        pattern[void] = true;
+        splat(&mut energy, size, void, 1.0);
+        ranks[void] = rank;
+    }
+    ranks.iter().map(|r| *r as f32 / n as f32).collect()
+}
+
+fn splat(energy: &mut [f32], size: usize, index: usize, sign: f32){
+    let (x, y) = ((index % size) as i32, (index / size) as i32);
+    for dy in -RADIUS..=RADIUS{
+        for dx in -RADIUS..=RADIUS{
+            let px = (x + dx).rem_euclid(size as i32) as usize;
+            let py = (y + dy).rem_euclid(size as i32) as usize;
+            let weight = (-((dx * dx + dy * dy) as f32) / (2.0 * SIGMA * SIGMA)).exp();
+            energy[py * size + px] += sign * weight;
+        }
+    }
+}
+
+fn tightest_cluster(energy: &[f32], pattern: &[bool]) -> usize{
+    (0..energy.len())
+        .filter(|i| pattern[*i])
+        .max_by(|a, b| energy[*a].total_cmp(&energy[*b]))
+        .unwrap()
+}
+
+fn largest_void(energy: &[f32], pattern: &[bool]) -> usize{
+    (0..energy.len())
+        .filter(|i| !pattern[*i])
+        .min_by(|a, b| energy[*a].total_cmp(&energy[*b]))
+        .unwrap()
+}
@@ -0,0 +1,87 @@
+struct Exposure{
+    mode: i32,
+    exposure: f32,
+    compensation: f32,
+    adaptation: f32,
+    min_log_luminance: f32,
+    log_luminance_range: f32,
+    low_percentile: f32,
+    high_percentile: f32,
+    tone_map: i32,
+    white_point: f32,
+};
+
+@group(0) @binding(0)
+var<uniform> exposure: Exposure;
+@group(0) @binding(1)
+var texture: texture_storage_2d<rgba32float,read_write>;
+@group(0) @binding(2)
+var<storage,read_write> histogram: array<atomic<u32>>;
+@group(0) @binding(3)
+var<storage,read_write> luminance: array<f32>;
+
+//Bin 0 collects black pixels, the rest split the log luminance range evenly
+const HISTOGRAM_BINS: u32 = 64u;
+
+var<workgroup> local_histogram: array<atomic<u32>, 64>;
+
+fn bin_log_luminance(bin: u32) -> f32{
+    let t = (f32(bin) - 0.5) / f32(HISTOGRAM_BINS - 1u);
+    return exposure.min_log_luminance + t * exposure.log_luminance_range;
+}
+
+@compute
+@workgroup_size(16,16)
+fn build_histogram(@builtin(global_invocation_id) global_id: vec3<u32>, @builtin(local_invocation_index) local_index: u32){
+    if(local_index < HISTOGRAM_BINS){
+        atomicStore(&local_histogram[local_index], 0u);
+    }
+    workgroupBarrier();
+    let size = vec2<u32>(textureDimensions(texture));
+    if(global_id.x < size.x && global_id.y < size.y){
+        let color = textureLoad(texture, vec2<i32>(global_id.xy)).rgb;
+        let l = dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+        var bin = 0u;
+        if(l > 1e-6){
+            let t = clamp((log2(l) - exposure.min_log_luminance) / exposure.log_luminance_range, 0.0, 1.0);
+            bin = 1u + min(u32(t * f32(HISTOGRAM_BINS - 1u)), HISTOGRAM_BINS - 2u);
+        }
+        atomicAdd(&local_histogram[bin], 1u);
+    }
+    workgroupBarrier();
+    if(local_index < HISTOGRAM_BINS){
+        atomicAdd(&histogram[local_index], atomicLoad(&local_histogram[local_index]));
+    }
+}
+
+//Averages the log luminance between the two percentiles, so a few very bright or dark pixels
+//don't swing the exposure, then eases the adapted luminance towards it
+@compute
+@workgroup_size(1)
+fn average_luminance(){
+    var total = 0u;
+    for(var i = 1u; i < HISTOGRAM_BINS; i += 1u){
+        total += atomicLoad(&histogram[i]);
+    }
+    let low = f32(total) * exposure.low_percentile;
+    let high = f32(total) * exposure.high_percentile;
+    var seen = 0.0;
+    var sum = 0.0;
+    var count = 0.0;
+    for(var i = 1u; i < HISTOGRAM_BINS; i += 1u){
+        let n = f32(atomicLoad(&histogram[i]));
+        let kept = max(min(seen + n, high) - max(seen, low), 0.0);
+        sum += kept * bin_log_luminance(i);
+        count += kept;
+        seen += n;
+    }
+    if(count <= 0.0){
+        return;
+    }
+    let measured = exp2(sum / count);
+    if(luminance[0] <= 0.0){
+        luminance[0] = measured;
+    }else{
+        luminance[0] = mix(luminance[0], measured, exposure.adaptation);
+    }
+}
@@ -0,0 +1,206 @@
+struct Denoise{
+    width: u32,
+    height: u32,
+    frames: i32,
+    temporal: i32,
+    strength: f32,
+    max_history: f32,
+};
+
+//One a-trous pass, a slice of the dynamic offset buffer
+struct Iteration{
+    step: i32,
+    //0 reads ping and writes pong, 1 the other way round
+    source: i32,
+    last: i32,
+};
+
+struct Camera{
+    origin: vec3<f32>,
+    lower_left_corner: vec3<f32>,
+    horizontal: vec3<f32>,
+    vertical: vec3<f32>,
+    near: f32,
+    far: f32,
+    w: vec3<f32>,
+    u: vec3<f32>,
+    v: vec3<f32>,
+    lens_radius: f32,
+    blades: u32,
+    blade_rotation: f32,
+    anamorphic: f32,
+    bokeh: u32,
+    projection: i32,
+    ortho_height: f32,
+    fisheye_fov: f32,
+    cube_face: i32,
+}
+
+//Same layout as in ray_tracer.wgsl
+struct Feature{
+    albedo: vec3<f32>,
+    depth: f32,
+    normal: vec3<f32>,
+    _padding: f32,
+    position: vec3<f32>,
+    _padding2: f32,
+};
+
+@group(0) @binding(0)
+var<uniform> denoise: Denoise;
+@group(0) @binding(1)
+var<uniform> prev_camera: Camera;
+@group(0) @binding(2)
+var texture: texture_storage_2d<rgba32float,read_write>;
+@group(0) @binding(3)
+var<storage,read> features: array<Feature>;
+@group(0) @binding(4)
+var<storage,read> prev_features: array<Feature>;
+//Demodulated colour in rgb and the number of frames behind it in a
+@group(0) @binding(5)
+var<storage,read> history: array<vec4<f32>>;
+@group(0) @binding(6)
+var<storage,read_write> ping: array<vec4<f32>>;
+@group(0) @binding(7)
+var<storage,read_write> pong: array<vec4<f32>>;
+@group(0) @binding(8)
+var output: texture_storage_2d<rgba32float,write>;
+@group(1) @binding(0)
+var<uniform> iteration: Iteration;
+
+const PROJECTION_PERSPECTIVE: i32 = 0;
+const NORMAL_SIGMA: f32 = 128.0;
+const DEPTH_SIGMA: f32 = 0.02;
+const ALBEDO_EPSILON: f32 = 1e-3;
+
+fn luminance(color: vec3<f32>) -> f32{
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+fn in_bounds(pixel: vec2<i32>) -> bool{
+    return all(pixel >= vec2<i32>(0)) && pixel.x < i32(denoise.width) && pixel.y < i32(denoise.height);
+}
+
+fn index(pixel: vec2<i32>) -> u32{
+    return u32(pixel.y) * denoise.width + u32(pixel.x);
+}
+
+//Same as reproject in ray_tracer.wgsl
+fn reproject(point: vec3<f32>) -> vec2<i32>{
+    if(prev_camera.projection != PROJECTION_PERSPECTIVE){
+        return vec2<i32>(-1);
+    }
+    let normal = cross(prev_camera.horizontal, prev_camera.vertical);
+    let dir = point - prev_camera.origin;
+    let denominator = dot(dir, normal);
+    if(abs(denominator) < 1e-8){
+        return vec2<i32>(-1);
+    }
+    let t = dot(prev_camera.lower_left_corner - prev_camera.origin, normal) / denominator;
+    if(t <= 0.0){
+        return vec2<i32>(-1);
+    }
+    let q = prev_camera.origin + dir * t - prev_camera.lower_left_corner;
+    let uv = vec2<f32>(
+        dot(q, prev_camera.horizontal) / dot(prev_camera.horizontal, prev_camera.horizontal),
+        dot(q, prev_camera.vertical) / dot(prev_camera.vertical, prev_camera.vertical),
+    );
+    return vec2<i32>(floor(uv * vec2<f32>(f32(denoise.width), f32(denoise.height))));
+}
+
+//Averaged normals of pixels on an edge are shorter than 1, the sky has none at all
+fn unit(normal: vec3<f32>) -> vec3<f32>{
+    let l = length(normal);
+    return select(vec3<f32>(0.0), normal / l, l > 1e-3);
+}
+
+//Lighting without the surface colour, so the filter doesn't blur textures and material edges
+@compute
+@workgroup_size(8,8)
+fn temporal(@builtin(global_invocation_id) global_id: vec3<u32>){
+    let pixel = vec2<i32>(global_id.xy);
+    if(!in_bounds(pixel)){
+        return;
+    }
+    let i = index(pixel);
+    let feature = features[i];
+    var color = textureLoad(texture, pixel).rgb / max(feature.albedo, vec3<f32>(ALBEDO_EPSILON));
+    var history_length = f32(denoise.frames + 1);
+    //The accumulation already averages a still camera, history only helps the first frame after a move
+    if(denoise.temporal != 0 && denoise.frames == 0){
+        let prev = reproject(feature.position);
+        if(in_bounds(prev)){
+            let p = index(prev);
+            let prev_feature = prev_features[p];
+            let same_surface = dot(unit(feature.normal), unit(prev_feature.normal)) > 0.9
+                && distance(feature.position, prev_feature.position) < DEPTH_SIGMA * 5.0 * feature.depth;
+            if(same_surface && history[p].a > 0.0){
+                history_length = min(history[p].a + 1.0, denoise.max_history);
+                color = mix(history[p].rgb, color, 1.0 / history_length);
+            }
+        }
+    }
+    ping[i] = vec4<f32>(color, history_length);
+}
+
+fn load(pixel: vec2<i32>) -> vec4<f32>{
+    if(iteration.source == 0){
+        return ping[index(pixel)];
+    }
+    return pong[index(pixel)];
+}
+
+fn store(pixel: vec2<i32>, value: vec4<f32>){
+    if(iteration.source == 0){
+        pong[index(pixel)] = value;
+    }else{
+        ping[index(pixel)] = value;
+    }
+}
+
+//Edge-avoiding a-trous wavelet filter (Dammertz et al. 2010), a 5x5 B3 spline kernel spread out by
+//step and weighted by how alike the normals, depths and luminances of the two pixels are
+@compute
+@workgroup_size(8,8)
+fn atrous(@builtin(global_invocation_id) global_id: vec3<u32>){
+    let pixel = vec2<i32>(global_id.xy);
+    if(!in_bounds(pixel)){
+        return;
+    }
+    var kernel = array<f32, 3>(3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0);
+    let center = load(pixel);
+    let feature = features[index(pixel)];
+    let normal = unit(feature.normal);
+    let center_luminance = luminance(center.rgb);
+    //Noise falls off with the square root of the samples, so converged pixels are barely touched
+    let luminance_sigma = max(denoise.strength * (0.1 + center_luminance) / sqrt(center.a), 1e-4);
+    let depth_sigma = DEPTH_SIGMA * f32(iteration.step) * feature.depth + 1e-4;
+
+    var sum = vec3<f32>(0.0);
+    var weights = 0.0;
+    for(var y = -2; y <= 2; y+=1){
+        for(var x = -2; x <= 2; x+=1){
+            let q = pixel + vec2<i32>(x, y) * iteration.step;
+            if(!in_bounds(q)){
+                continue;
+            }
+            let neighbour = load(q);
+            let q_feature = features[index(q)];
+            let q_normal = unit(q_feature.normal);
+            var normal_weight = pow(max(dot(normal, q_normal), 0.0), NORMAL_SIGMA);
+            if(all(normal == vec3<f32>(0.0)) && all(q_normal == vec3<f32>(0.0))){
+                normal_weight = 1.0;
+            }
+            let depth_weight = exp(-abs(feature.depth - q_feature.depth) / depth_sigma);
+            let luminance_weight = exp(-abs(center_luminance - luminance(neighbour.rgb)) / luminance_sigma);
+            let weight = kernel[abs(x)] * kernel[abs(y)] * normal_weight * depth_weight * luminance_weight;
+            sum += neighbour.rgb * weight;
+            weights += weight;
+        }
+    }
+    let filtered = vec4<f32>(sum / max(weights, 1e-8), center.a);
+    store(pixel, filtered);
+    if(iteration.last != 0){
+        textureStore(output, pixel, vec4<f32>(filtered.rgb * max(feature.albedo, vec3<f32>(ALBEDO_EPSILON)), 1.0));
+    }
+}
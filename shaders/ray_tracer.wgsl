@@ -5,18 +5,55 @@ struct Params{
     rays_per_pixel: i32,
     toggle: i32,
     frames: i32,
+    accumulate: i32,
+    termination: i32,
+    min_bounces: i32,
+    max_bounces: i32,
+    sampler_kind: i32,
+    light_sampling: i32,
+    spectral: i32,
+    photon_count: i32,
+    photon_radius: f32,
+    restir_candidates: i32,
+    restir_temporal: i32,
+    restir_spatial_samples: i32,
+    restir_spatial_radius: i32,
+    restir_max_history: i32,
+    ao_distance: f32,
+    debug_mode: i32,
+    debug_scale: f32,
+    motion_blur: i32,
+    shutter_open: f32,
+    shutter_close: f32,
+    //Writes the primary hit of every pixel to features for the denoiser
+    features: i32,
+    //Bit per AOV in aov.rs, enabled ones are packed into the layers of aovs in that order
+    aovs: u32,
+    adaptive: i32,
+    //Pixels whose estimated error falls under this stop being sampled
+    adaptive_threshold: f32,
+    //Frames every pixel gets before it may stop, the variance of fewer isn't trustworthy
+    adaptive_min_frames: i32,
 };
 struct Material{
     color: vec4<f32>,
     emission_color: vec4<f32>,
     emission_strength: f32,
     smoothness: f32,
+    dispersion: u32,
+    //Used as is when there is no dispersion, otherwise the ior at the sodium d line for rgb mode
+    ior: f32,
+    ior_b: vec3<f32>,
+    ior_c: vec3<f32>,
 }
 
 struct Sphere{
     position: vec3<f32>,
     radius: f32,
     material: Material,
+    light: u32,
+    //Offset reached at the end of the motion
+    motion: vec3<f32>,
 };
 
 struct Vertex{
@@ -28,8 +65,14 @@ struct Mesh{
     first: u32,
     triangles: u32,
     offset: u32,
+    light: u32,
     pos: vec3<f32>,
     material: Material,
+    motion: vec3<f32>,
+    bounds_min: vec3<f32>,
+    bounds_max: vec3<f32>,
+    //Rotation about pos reached at the end of the motion, axis times angle in radians
+    spin: vec3<f32>,
 };
 
 @group(0) @binding(0)
@@ -46,23 +89,246 @@ var<storage,read> vertices: array<Vertex>;
 var<storage,read> indices: array<u32>;
 @group(0) @binding(6)
 var<storage,read> meshes: array<Mesh>;
+@group(0) @binding(7)
+var<storage,read> blue_noise: array<f32>;
+@group(0) @binding(8)
+var<storage,read> lights: array<Light>;
+@group(0) @binding(9)
+var<storage,read> light_nodes: array<LightNode>;
+@group(0) @binding(10)
+var<storage,read_write> photons: array<Photon>;
+@group(0) @binding(11)
+var<storage,read_write> photon_grid: array<atomic<u32>>;
+@group(0) @binding(12)
+var<storage,read_write> reservoirs: array<Reservoir>;
+@group(0) @binding(13)
+var<storage,read_write> reservoir_history: array<Reservoir>;
+@group(0) @binding(14)
+var<uniform> prev_camera: Camera;
+@group(0) @binding(15)
+var<storage,read> bokeh: array<f32>;
+@group(0) @binding(16)
+var<storage,read_write> features: array<Feature>;
+@group(0) @binding(17)
+var aovs: texture_storage_2d_array<rgba32float,read_write>;
+//Mean luminance, mean squared luminance, frames and estimated error of every pixel
+@group(0) @binding(18)
+var<storage,read_write> moments: array<vec4<f32>>;
+//Pixels under the noise threshold this frame
+@group(0) @binding(19)
+var<storage,read_write> converged_pixels: atomic<u32>;
+//Light tracing contributions in 16.16 fixed point, rgb per pixel
+@group(0) @binding(20)
+var<storage,read_write> splats: array<atomic<u32>>;
+
+//Same layout as Feature in denoiser.rs
+struct Feature{
+    albedo: vec3<f32>,
+    depth: f32,
+    normal: vec3<f32>,
+    _padding: f32,
+    position: vec3<f32>,
+    _padding2: f32,
+};
 
 @compute
 @workgroup_size(8,8)
-fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
-    var i: FragInput;
+fn render(@builtin(global_invocation_id) global_id: vec3<u32>) {
+    render_pixel(global_id);
+}
 
-    i.pos = vec2<f32>(f32(global_id.x),f32(global_id.y));
-    i.size = vec2<f32>(f32(params.width),f32(params.height));
+//Frames accumulated into this pixel so far, behind params.frames once adaptive sampling skips it
+var<private> pixel_frames: i32;
+
+fn render_pixel(global_id: vec3<u32>){
+    let i = frag_input(global_id);
 
     let pos = vec2<i32>(i32(i.pos.x),i32(i.pos.y));
-    if(params.frames >= 1){
-        let weight = 1.0 / f32(params.frames + 1);
+    pixel_frames = params.frames;
+    var moment = vec4<f32>(0.0);
+    if(params.adaptive != 0){
+        if(params.frames >= 1){
+            moment = moments[pixel_index(i.pixel)];
+        }
+        pixel_frames = i32(moment.z);
+        if(adaptive_converged(moment)){
+            atomicAdd(&converged_pixels, 1u);
+            return;
+        }
+    }
+    let color = frag(i);
+    if(pixel_frames >= 1){
+        let weight = 1.0 / f32(pixel_frames + 1);
         let prev_color = textureLoad(texture,pos);
-        let new_color = prev_color * vec4<f32>(1.0 - weight) + frag(i) * weight;
+        let new_color = prev_color * vec4<f32>(1.0 - weight) + color * weight;
         textureStore(texture, pos, new_color);
     }else{
-        textureStore(texture, pos, frag(i));
+        textureStore(texture, pos, color);
+    }
+    if(params.adaptive != 0){
+        moment = update_moment(moment, luminance(color));
+        moments[pixel_index(i.pixel)] = moment;
+        if(adaptive_converged(moment)){
+            atomicAdd(&converged_pixels, 1u);
+        }
+    }
+    if(params.features != 0 || params.aovs != 0u){
+        //Same ray as the first sample of the frame
+        var rng = start_sample(i.pixel, u32(max(params.frames, 0) * params.rays_per_pixel));
+        let ray = camera_ray(i, &rng);
+        var hit: Hit;
+        if(any(ray.dir != vec3<f32>(0.0))){
+            hit = calculate_ray_collions(ray);
+        }
+        if(params.features != 0){
+            write_features(i, ray, hit);
+        }
+        if(params.aovs != 0u){
+            write_aovs(pos, ray, hit);
+        }
+    }
+}
+
+//Adds this frame's luminance to the running moments, every frame being one sample of the pixel. The
+//error is the standard error of the mean over the square root of the mean, so dark pixels need less
+//absolute but more relative precision, roughly like the eye sees noise. Samples that all agree don't
+//rule out light found less than once in that many samples, so the error never drops below 1 / frames
+//and a black pixel with no variance yet keeps sampling until that's under the threshold too
+fn update_moment(moment: vec4<f32>, value: f32) -> vec4<f32>{
+    let frames = moment.z + 1.0;
+    let mean = mix(moment.x, value, 1.0 / frames);
+    let mean_squared = mix(moment.y, value * value, 1.0 / frames);
+    let variance = max(mean_squared - mean * mean, 0.0);
+    let error = max(sqrt(variance / frames) / sqrt(max(mean, 1e-4)), 1.0 / frames);
+    return vec4<f32>(mean, mean_squared, frames, error);
+}
+
+fn adaptive_converged(moment: vec4<f32>) -> bool{
+    return i32(moment.z) >= params.adaptive_min_frames && moment.w < params.adaptive_threshold;
+}
+
+//Albedo, normal and depth of the primary hit, averaged over the frames like the colour. The sky
+//gets a white albedo so demodulating leaves it alone, and no normal so it never blends with surfaces
+fn write_features(i: FragInput, ray: Ray, hit: Hit){
+    var feature: Feature;
+    feature.albedo = vec3<f32>(1.0);
+    feature.depth = camera.far;
+    feature.position = ray.origin + ray.dir * camera.far;
+    if(hit.hit){
+        feature.albedo = hit.material.color.rgb;
+        feature.depth = distance(ray.origin, hit.hit_point);
+        feature.normal = hit.normal;
+        feature.position = hit.hit_point;
+    }
+    let index = i.pixel.y * params.width + i.pixel.x;
+    if(pixel_frames >= 1){
+        let weight = 1.0 / f32(pixel_frames + 1);
+        let prev = features[index];
+        feature.albedo = mix(prev.albedo, feature.albedo, weight);
+        feature.depth = mix(prev.depth, feature.depth, weight);
+        feature.normal = mix(prev.normal, feature.normal, weight);
+        feature.position = mix(prev.position, feature.position, weight);
+    }
+    features[index] = feature;
+}
+
+//Same order as aov.rs
+const AOV_ALBEDO: u32 = 0u;
+const AOV_NORMAL: u32 = 1u;
+const AOV_DEPTH: u32 = 2u;
+const AOV_POSITION: u32 = 3u;
+const AOV_OBJECT_ID: u32 = 4u;
+const AOV_MATERIAL_ID: u32 = 5u;
+const AOV_DIRECT_DIFFUSE: u32 = 6u;
+const AOV_COUNT: u32 = 11u;
+//Lighting AOVs of a single path, indexed from AOV_DIRECT_DIFFUSE
+const PATH_DIRECT_DIFFUSE: i32 = 0;
+const PATH_INDIRECT_DIFFUSE: i32 = 2;
+const PATH_EMISSION: i32 = 4;
+const PATH_AOVS: i32 = 5;
+const LOBE_DIFFUSE: u32 = 0u;
+const LOBE_SPECULAR: u32 = 1u;
+
+//Only the path tracer and photon mapping fill these, Integrator::aov_mask keeps them off for the others
+var<private> path_aovs: array<vec4<f32>, 5>;
+//Averaged over the samples of this frame, already rgb
+var<private> pixel_aovs: array<vec4<f32>, 5>;
+//Lobe the primary hit scattered into, decides between the diffuse and specular AOVs
+var<private> primary_lobe: u32;
+//Part of the last sample_direct_light result that came from the diffuse lobe
+var<private> direct_diffuse_fraction: f32;
+
+fn aov_layer(aov: u32) -> i32{
+    return i32(countOneBits(params.aovs & ((1u << aov) - 1u)));
+}
+
+//The diffuse term of eval_bsdf over both terms
+fn diffuse_fraction(material: Material, normal: vec3<f32>, wo: vec3<f32>, wi: vec3<f32>) -> f32{
+    let n = faceForward(normal, -wo, normal);
+    let s = max(material.smoothness, 0.0);
+    let exponent = specular_exponent(s);
+    let cos_alpha = max(dot(reflect(-wo, n), wi), 0.0);
+    let diffuse = (1.0 - s) / PI;
+    let glossy = s * (exponent + 2.0) / (2.0 * PI) * pow(cos_alpha, exponent);
+    return diffuse / max(diffuse + glossy, 1e-8);
+}
+
+fn primary_diffuse() -> f32{
+    return select(1.0, 0.0, primary_lobe == LOBE_SPECULAR);
+}
+
+//Light added to a path, sorted by the vertex it was gathered at (the primary hit is direct, the rest
+//indirect) and split between diffuse and specular by diffuse
+fn add_path_light(vertex: i32, light: vec4<f32>, diffuse: f32){
+    if(params.aovs == 0u){
+        return;
+    }
+    let aov = select(PATH_INDIRECT_DIFFUSE, PATH_DIRECT_DIFFUSE, vertex == 0);
+    path_aovs[aov] += light * diffuse;
+    path_aovs[aov + 1] += light * (1.0 - diffuse);
+}
+
+//Emission or sky hit by the path at depth, seen directly or reached from the vertex before it
+fn add_path_emission(depth: i32, light: vec4<f32>){
+    if(depth == 0){
+        path_aovs[PATH_EMISSION] += light;
+    }else{
+        add_path_light(depth - 1, light, primary_diffuse());
+    }
+}
+
+//Geometry AOVs come from the primary hit, alpha is coverage. Ids aren't averaged, the first frame's
+//value is kept so edges don't blend into ids that don't exist
+fn write_aovs(pos: vec2<i32>, ray: Ray, hit: Hit){
+    var values: array<vec4<f32>, 11>;
+    let coverage = select(0.0, 1.0, hit.hit);
+    values[AOV_ALBEDO] = vec4<f32>(hit.material.color.rgb, coverage);
+    values[AOV_NORMAL] = vec4<f32>(hit.normal, coverage);
+    let depth = select(camera.far, distance(ray.origin, hit.hit_point), hit.hit);
+    values[AOV_DEPTH] = vec4<f32>(vec3<f32>(depth), coverage);
+    values[AOV_POSITION] = vec4<f32>(hit.hit_point, coverage);
+    if(hit.hit){
+        //Same colours as the debug views, the id itself is in alpha and 0 is the sky
+        values[AOV_OBJECT_ID] = vec4<f32>(id_color(hit.object), f32(hit.object + 1u));
+        let material = material_id(hit.material);
+        values[AOV_MATERIAL_ID] = vec4<f32>(id_color(material), f32(material % 65535u + 1u));
+    }
+    for(var k = 0; k < PATH_AOVS; k+=1){
+        values[AOV_DIRECT_DIFFUSE + u32(k)] = vec4<f32>(pixel_aovs[k].rgb, 1.0);
+    }
+    let weight = 1.0 / f32(pixel_frames + 1);
+    for(var aov = 0u; aov < AOV_COUNT; aov+=1u){
+        if((params.aovs & (1u << aov)) == 0u){
+            continue;
+        }
+        let layer = aov_layer(aov);
+        var value = values[aov];
+        let id = aov == AOV_OBJECT_ID || aov == AOV_MATERIAL_ID;
+        if(pixel_frames >= 1){
+            let prev = textureLoad(aovs, pos, layer);
+            value = select(mix(prev, value, weight), prev, id);
+        }
+        textureStore(aovs, pos, layer, value);
     }
 }
 
@@ -77,13 +343,30 @@ struct Camera{
     u: vec3<f32>,
     v: vec3<f32>,
     lens_radius: f32,
+    blades: u32,
+    blade_rotation: f32,
+    anamorphic: f32,
+    bokeh: u32,
+    projection: i32,
+    ortho_height: f32,
+    fisheye_fov: f32,
+    cube_face: i32,
 }
 
 struct FragInput{
+    pixel: vec2<u32>,
     pos: vec2<f32>,
     size: vec2<f32>,
 };
 
+fn frag_input(global_id: vec3<u32>) -> FragInput{
+    var i: FragInput;
+    i.pixel = global_id.xy;
+    i.pos = vec2<f32>(f32(global_id.x),f32(global_id.y));
+    i.size = vec2<f32>(f32(params.width),f32(params.height));
+    return i;
+}
+
 struct Ray{
     origin: vec3<f32>,
     dir: vec3<f32>,
@@ -95,6 +378,12 @@ struct Hit{
     hit_point: vec3<f32>,
     normal: vec3<f32>, 
     material: Material,
+    light: u32,
+    geometric_normal: vec3<f32>,
+    barycentric: vec3<f32>,
+    //Spheres first, then meshes
+    object: u32,
+    triangle: u32,
 }
 
 const SKY_HORIZON: vec4<f32> = vec4<f32>(1.0,1.0,1.0,0.0);
@@ -102,21 +391,61 @@ const SKY_ZENITH: vec4<f32> = vec4<f32>(0.0788092, 0.36480793, 0.7264151, 0.0);
 const GROUND_COLOR: vec4<f32> = vec4<f32>(0.35,0.3,0.35, 0.0);
 const SUN_INTENSITY: f32 = 0.1;
 const SUN_FOCUS: f32 = 500.0;
+const PI: f32 = 3.1415926;
+const TERMINATION_FIXED: i32 = 0;
+const TERMINATION_RUSSIAN_ROULETTE: i32 = 1;
+const LIGHT_SAMPLING_NONE: i32 = 0;
+const LIGHT_SAMPLING_UNIFORM: i32 = 1;
+const LIGHT_SAMPLING_BVH: i32 = 2;
+//Same order as Integrator in integrator.rs
+const INTEGRATOR_PATH: i32 = 0;
+const INTEGRATOR_BDPT: i32 = 1;
+const INTEGRATOR_PHOTON: i32 = 2;
+const INTEGRATOR_RESTIR: i32 = 3;
+const INTEGRATOR_DIRECT: i32 = 4;
+const INTEGRATOR_AO: i32 = 5;
+const INTEGRATOR_WHITTED: i32 = 6;
+const INTEGRATOR_DEBUG: i32 = 7;
+//The integrator this module renders with. ray_tracer.rs builds the shader once per integrator with this line
+//replaced, so every branch on it is constant and the other integrators' code is dropped by the compiler
+const INTEGRATOR: i32 = INTEGRATOR_PATH;
+const EPSILON: f32 = 1e-4;
+
+//Slab test, true when the ray enters the box closer than closest
+fn ray_box(ray: Ray, box_min: vec3<f32>, box_max: vec3<f32>, closest: f32) -> bool{
+    let inv_dir = 1.0 / ray.dir;
+    let t0 = (box_min - ray.origin) * inv_dir;
+    let t1 = (box_max - ray.origin) * inv_dir;
+    let t_min = min(t0, t1);
+    let t_max = max(t0, t1);
+    let t_near = max(max(t_min.x, t_min.y), t_min.z);
+    let t_far = min(min(t_max.x, t_max.y), t_max.z);
+    return t_near <= t_far && t_far >= 0.0 && t_near < closest;
+}
 
 fn ray_sphere(ray: Ray, pos: vec3<f32>, radius: f32) -> Hit{
     var hit: Hit;
+    //The placeholder uploaded for scenes without spheres
+    if(radius <= 0.0){
+        return hit;
+    }
     let oc = ray.origin - pos;
     let a = dot(ray.dir,ray.dir);
     let b = 2.0 * dot(oc, ray.dir);
     let c = dot(oc,oc) - pow(radius,2.0);
     let discriminant = b * b - 4.0 * a * c;
     if discriminant >= 0.0{
-        let dst = (-b - sqrt(discriminant))/(2.0*a);
+        var dst = (-b - sqrt(discriminant))/(2.0*a);
+        //Inside the sphere (e.g. after refracting into glass) the far root is the exit point
+        if dst < 0.0{
+            dst = (-b + sqrt(discriminant))/(2.0*a);
+        }
         if dst >= 0.0{
             hit.hit = true;
             hit.hit_point = ray.origin + ray.dir * dst;
             hit.dst = dst;
             hit.normal = normalize(hit.hit_point - pos);
+            hit.geometric_normal = hit.normal;
         }
     } 
     return hit;
@@ -141,42 +470,108 @@ fn ray_triangle(ray: Ray, a: Vertex, b: Vertex, c: Vertex) -> Hit{
     hit.hit = determinant >= 1e-6 && dst >= 0.0 && u >= 0.0 && v >= 0.0 && w >= 0.0;
     hit.hit_point = ray.origin + ray.dir * dst;
     hit.normal = normalize(a.normal * w + b.normal * u + c.normal * v);
+    hit.geometric_normal = normalize(normal);
+    hit.barycentric = vec3<f32>(w, u, v);
     hit.dst = dst;
 
     return hit;
 }
 
+struct Triangle{
+    a: Vertex,
+    b: Vertex,
+    c: Vertex,
+}
+
+fn mesh_triangle(mesh_index: u32, triangle: u32) -> Triangle{
+    let first = meshes[mesh_index].first;
+    let offset = meshes[mesh_index].offset;
+
+    var t: Triangle;
+    t.a = vertices[offset + indices[first + triangle*3u]];
+    t.b = vertices[offset + indices[first + triangle*3u+1u]];
+    t.c = vertices[offset + indices[first + triangle*3u+2u]];
+
+    let pos = meshes[mesh_index].pos + meshes[mesh_index].motion * ray_time;
+    let spin = meshes[mesh_index].spin * ray_time;
+    t.a.pos = rotate(t.a.pos, spin) + pos;
+    t.b.pos = rotate(t.b.pos, spin) + pos;
+    t.c.pos = rotate(t.c.pos, spin) + pos;
+    t.a.normal = rotate(t.a.normal, spin);
+    t.b.normal = rotate(t.b.normal, spin);
+    t.c.normal = rotate(t.c.normal, spin);
+    return t;
+}
+
+//Rotates v by the axis times angle r (Rodrigues' formula)
+fn rotate(v: vec3<f32>, r: vec3<f32>) -> vec3<f32>{
+    let angle = length(r);
+    if(angle < 1e-6){
+        return v;
+    }
+    let k = r / angle;
+    return v * cos(angle) + cross(k, v) * sin(angle) + k * dot(k, v) * (1.0 - cos(angle));
+}
+
+//Where in its motion everything is for the current path, 0 at the start and 1 at the end.
+//Set once per camera ray or photon so every ray along a path sees the same scene
+var<private> ray_time: f32;
+
+fn sphere_at(i: u32) -> Sphere{
+    var sphere = spheres[i];
+    sphere.position += sphere.motion * ray_time;
+    return sphere;
+}
+
+fn sample_shutter(u: f32) -> f32{
+    if(params.motion_blur == 0){
+        return 0.0;
+    }
+    return mix(params.shutter_open, params.shutter_close, u);
+}
+
+//Primitives tested since it was last reset, for the intersection heatmap of a whole path
+var<private> intersection_tests: u32;
+
 fn calculate_ray_collions(ray: Ray) -> Hit{
     var closest_hit: Hit; 
     closest_hit.dst = 0x1.fffffep+127f;
+    closest_hit.light = NO_LIGHT;
     for(var i: u32 = 0u; i < arrayLength(&spheres); i+=1u){
-        var hit: Hit = ray_sphere(ray, spheres[i].position, spheres[i].radius);
+        let sphere = sphere_at(i);
+        var hit: Hit = ray_sphere(ray, sphere.position, sphere.radius);
+        intersection_tests += 1u;
         if hit.hit && hit.dst < closest_hit.dst{
             closest_hit = hit;
             closest_hit.material = spheres[i].material;
+            closest_hit.light = spheres[i].light;
+            closest_hit.object = i;
         }
     }
     for(var mesh_index: u32 = 0u; mesh_index< arrayLength(&meshes); mesh_index+=1u){
+        let mesh_pos = meshes[mesh_index].pos + meshes[mesh_index].motion * ray_time;
+        var bounds_min = meshes[mesh_index].bounds_min;
+        var bounds_max = meshes[mesh_index].bounds_max;
+        //The box turns with the mesh, so a spinning one is bounded by the cube around every orientation of it
+        if(any(meshes[mesh_index].spin != vec3<f32>(0.0))){
+            bounds_max = vec3<f32>(length(max(abs(bounds_min), abs(bounds_max))));
+            bounds_min = -bounds_max;
+        }
+        if(!ray_box(ray, bounds_min + mesh_pos, bounds_max + mesh_pos, closest_hit.dst)){
+            continue;
+        }
         for(var i: u32 = 0u; i < meshes[mesh_index].triangles; i+=1u){
-            let first = meshes[mesh_index].first;
-            let offset = meshes[mesh_index].offset;
-
-            let index1 = indices[first + i*3u];
-            let index2 = indices[first + i*3u+1u];
-            let index3 = indices[first + i*3u+2u];
-
-            var v1 = vertices[offset + index1];
-            var v2 = vertices[offset + index2];
-            var v3 = vertices[offset + index3];
+            let triangle = mesh_triangle(mesh_index, i);
 
-            v1.pos += meshes[mesh_index].pos;
-            v2.pos += meshes[mesh_index].pos;
-            v3.pos += meshes[mesh_index].pos;
-
-            var hit: Hit = ray_triangle(ray, v1,v2,v3);
+            var hit: Hit = ray_triangle(ray, triangle.a, triangle.b, triangle.c);
+            intersection_tests += 1u;
             if hit.hit && hit.dst < closest_hit.dst{
                 closest_hit = hit;
                 closest_hit.material = meshes[mesh_index].material;
+                closest_hit.object = arrayLength(&spheres) + mesh_index;
+                closest_hit.triangle = i;
+                let light = meshes[mesh_index].light;
+                closest_hit.light = select(light + i, NO_LIGHT, light == NO_LIGHT);
             }
         }
     }
@@ -184,96 +579,625 @@ fn calculate_ray_collions(ray: Ray) -> Hit{
     return closest_hit;
 }
 
-fn rand(seed: ptr<function,u32>) -> f32 {
-    return f32(next_random_number(seed)) / 4294967295.0; // 2^32 - 1
+fn rand(rng: ptr<function, Sampler>) -> f32 {
+    return f32(next_random_number(rng)) / 4294967295.0; // 2^32 - 1
+}
+
+fn next_random_number(rng: ptr<function, Sampler>) -> u32 {
+    let seed = (*rng).state * 747796405u + 2891336453u;
+    (*rng).state = seed;
+    var result: u32 = ((seed >> ((seed >> 28u) + 4u)) ^ seed) * 277803737u;
+    result = (result >> 22u) ^ result;
+    return result;
+}
+
+const SAMPLER_INDEPENDENT: i32 = 0;
+const SAMPLER_STRATIFIED: i32 = 1;
+const SAMPLER_SOBOL: i32 = 2;
+const SAMPLER_BLUE_NOISE: i32 = 3;
+//Size of the pattern the stratified sampler cycles through, must be a square power of two
+const STRATA: u32 = 256u;
+const STRATA_SIDE: u32 = 16u;
+const BLUE_NOISE_SIZE: u32 = 64u;
+
+//Per pixel sample generator, every call consumes the next dimension of the current sample
+struct Sampler{
+    pixel: vec2<u32>,
+    index: u32,
+    dimension: u32,
+    seed: u32,
+    state: u32,
 }
 
-fn rand_unit_sphere(seed: ptr<function, u32>) -> vec3<f32> {
-    let x = rand_normal_dist(seed);
-    let y = rand_normal_dist(seed);
-    let z = rand_normal_dist(seed);
+fn hash(x: u32) -> u32{
+    var state = x * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
 
-    return normalize(vec3(x, y, z));
+fn hash_combine(seed: u32, v: u32) -> u32{
+    return seed ^ (hash(v) + 0x9e3779b9u + (seed << 6u) + (seed >> 2u));
 }
 
-fn rand_normal_dist(seed: ptr<function, u32>) -> f32 {
-    let theta = 2.0 * 3.1415926 * rand(seed);
-    let rho = sqrt(-2.0 * log(rand(seed)));
-    return rho * cos(theta);
+fn to_unit_float(x: u32) -> f32{
+    return f32(x >> 8u) * (1.0 / 16777216.0);
 }
 
-fn next_random_number(seed: ptr<function,u32>) -> u32 {
-    *seed = *seed * 747796405u + 2891336453u;
-    var result: u32 = ((*seed >> ((*seed >> 28u) + 4u)) ^ *seed) * 277803737u;
-    result = (result >> 22u) ^ result;
-    return result;
+fn start_sample(pixel: vec2<u32>, index: u32) -> Sampler{
+    var sample: Sampler;
+    sample.pixel = pixel;
+    sample.index = index;
+    sample.dimension = 0u;
+    sample.seed = hash_combine(hash(pixel.x), pixel.y);
+    sample.state = hash_combine(sample.seed, index);
+    return sample;
+}
+
+fn sample_1d(rng: ptr<function, Sampler>) -> f32{
+    if(params.sampler_kind == SAMPLER_STRATIFIED){
+        return stratified_1d(rng);
+    }else if(params.sampler_kind == SAMPLER_SOBOL){
+        return sobol_2d(rng).x;
+    }else if(params.sampler_kind == SAMPLER_BLUE_NOISE){
+        return blue_noise_2d(rng).x;
+    }
+    return rand(rng);
+}
+
+fn sample_2d(rng: ptr<function, Sampler>) -> vec2<f32>{
+    if(params.sampler_kind == SAMPLER_STRATIFIED){
+        return stratified_2d(rng);
+    }else if(params.sampler_kind == SAMPLER_SOBOL){
+        return sobol_2d(rng);
+    }else if(params.sampler_kind == SAMPLER_BLUE_NOISE){
+        return blue_noise_2d(rng);
+    }
+    return vec2<f32>(rand(rng), rand(rng));
+}
+
+//Each dimension gets its own seed so patterns don't correlate between dimensions
+fn dimension_seed(rng: ptr<function, Sampler>) -> u32{
+    let seed = hash_combine((*rng).seed, (*rng).dimension);
+    (*rng).dimension += 1u;
+    return seed;
+}
+
+//Random permutation of [0, n) for n a power of two
+fn permute(i: u32, n: u32, seed: u32) -> u32{
+    let mask = n - 1u;
+    var x = i;
+    x = (x ^ seed) & mask;
+    x = (x * (hash(seed) | 1u)) & mask;
+    x = (x ^ (hash(seed + 1u) & mask)) & mask;
+    x = (x * (hash(seed + 2u) | 1u)) & mask;
+    return x;
+}
+
+fn stratified_1d(rng: ptr<function, Sampler>) -> f32{
+    let seed = dimension_seed(rng);
+    let round = (*rng).index / STRATA;
+    let stratum = permute((*rng).index % STRATA, STRATA, hash_combine(seed, round));
+    let jitter = to_unit_float(hash_combine(seed, (*rng).index));
+    return (f32(stratum) + jitter) / f32(STRATA);
+}
+
+fn stratified_2d(rng: ptr<function, Sampler>) -> vec2<f32>{
+    let seed = dimension_seed(rng);
+    let round = (*rng).index / STRATA;
+    let stratum = permute((*rng).index % STRATA, STRATA, hash_combine(seed, round));
+    let cell = vec2<f32>(f32(stratum % STRATA_SIDE), f32(stratum / STRATA_SIDE));
+    let jitter = vec2<f32>(
+        to_unit_float(hash_combine(seed, (*rng).index * 2u)),
+        to_unit_float(hash_combine(seed, (*rng).index * 2u + 1u)),
+    );
+    return (cell + jitter) / f32(STRATA_SIDE);
+}
+
+//Practical hash-based Owen scrambling (Burley 2020)
+fn laine_karras_permutation(x: u32, seed: u32) -> u32{
+    var v = x + seed;
+    v ^= v * 0x6c50b47cu;
+    v ^= v * 0xb82f1e52u;
+    v ^= v * 0xc7afe638u;
+    v ^= v * 0x8d22f6e6u;
+    return v;
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32{
+    return reverseBits(laine_karras_permutation(reverseBits(x), seed));
+}
+
+//The first dimension is the van der Corput sequence, the second uses the direction numbers of Sobol dimension 2
+fn sobol(index: u32) -> vec2<u32>{
+    let x = reverseBits(index);
+    var y = 0u;
+    var v = 0x80000000u;
+    var i = index;
+    loop{
+        if(i == 0u){
+            break;
+        }
+        if((i & 1u) != 0u){
+            y ^= v;
+        }
+        v ^= v >> 1u;
+        i >>= 1u;
+    }
+    return vec2<u32>(x, y);
+}
+
+//Every pair of dimensions is a shuffled and independently scrambled 2D Sobol sequence
+fn sobol_2d(rng: ptr<function, Sampler>) -> vec2<f32>{
+    let seed = dimension_seed(rng);
+    let index = nested_uniform_scramble((*rng).index, seed);
+    let point = sobol(index);
+    return vec2<f32>(
+        to_unit_float(nested_uniform_scramble(point.x, hash_combine(seed, 0u))),
+        to_unit_float(nested_uniform_scramble(point.y, hash_combine(seed, 1u))),
+    );
+}
+
+//Blue noise tile offset per dimension, rotated over time with the R2 sequence
+fn blue_noise_2d(rng: ptr<function, Sampler>) -> vec2<f32>{
+    let seed = dimension_seed(rng);
+    let offset_x = vec2<u32>(hash(seed), hash(seed + 1u)) % BLUE_NOISE_SIZE;
+    let offset_y = vec2<u32>(hash(seed + 2u), hash(seed + 3u)) % BLUE_NOISE_SIZE;
+    let px = ((*rng).pixel + offset_x) % BLUE_NOISE_SIZE;
+    let py = ((*rng).pixel + offset_y) % BLUE_NOISE_SIZE;
+    let noise = vec2<f32>(
+        blue_noise[px.y * BLUE_NOISE_SIZE + px.x],
+        blue_noise[py.y * BLUE_NOISE_SIZE + py.x],
+    );
+    let r2 = vec2<f32>(0.7548776662, 0.5698402910) * f32((*rng).index);
+    return fract(noise + r2);
+}
+
+const BOKEH_SIZE: u32 = 64u;
+
+//Shirley-Chiu concentric mapping of the square onto the unit disk
+fn concentric_disk(u: vec2<f32>) -> vec2<f32>{
+    let p = u * 2.0 - 1.0;
+    if(p.x == 0.0 && p.y == 0.0){
+        return vec2<f32>(0.0);
+    }
+    if(abs(p.x) > abs(p.y)){
+        let theta = PI / 4.0 * (p.y / p.x);
+        return p.x * vec2<f32>(cos(theta), sin(theta));
+    }
+    let theta = PI / 2.0 - PI / 4.0 * (p.x / p.y);
+    return p.y * vec2<f32>(cos(theta), sin(theta));
+}
+
+//Uniform point in a regular polygon inscribed in the unit circle, one triangle per blade
+fn polygon_aperture(u: vec2<f32>, blades: u32) -> vec2<f32>{
+    let x = u.x * f32(blades);
+    let blade = floor(x);
+    let r = sqrt(fract(x));
+    let a0 = 2.0 * PI * blade / f32(blades);
+    let a1 = 2.0 * PI * (blade + 1.0) / f32(blades);
+    return r * mix(vec2<f32>(cos(a0), sin(a0)), vec2<f32>(cos(a1), sin(a1)), u.y);
+}
+
+//Picks a texel of the bokeh image in proportion to its brightness by searching its cdf
+fn bokeh_aperture(u: vec2<f32>) -> vec2<f32>{
+    let count = BOKEH_SIZE * BOKEH_SIZE;
+    let target_cdf = u.x * bokeh[count - 1u];
+    var lo = 0u;
+    var hi = count - 1u;
+    while(lo < hi){
+        let mid = (lo + hi) / 2u;
+        if(bokeh[mid] < target_cdf){
+            lo = mid + 1u;
+        }else{
+            hi = mid;
+        }
+    }
+    //Reuse the remainder of the search for the position inside the texel
+    let before = select(0.0, bokeh[lo - 1u], lo > 0u);
+    let jitter = vec2<f32>(clamp((target_cdf - before) / max(bokeh[lo] - before, 1e-8), 0.0, 1.0), u.y);
+    let texel = vec2<f32>(f32(lo % BOKEH_SIZE), f32(lo / BOKEH_SIZE)) + jitter;
+    //Image rows go down
+    return vec2<f32>(texel.x, f32(BOKEH_SIZE) - texel.y) / f32(BOKEH_SIZE) * 2.0 - 1.0;
+}
+
+//Point on the lens in units of the lens radius
+fn sample_aperture(rng: ptr<function, Sampler>) -> vec2<f32>{
+    let u = sample_2d(rng);
+    var p: vec2<f32>;
+    if(camera.bokeh != 0u){
+        p = bokeh_aperture(u);
+    }else if(camera.blades >= 3u){
+        p = polygon_aperture(u, camera.blades);
+    }else{
+        p = concentric_disk(u);
+    }
+    let c = cos(camera.blade_rotation);
+    let s = sin(camera.blade_rotation);
+    p = vec2<f32>(c * p.x - s * p.y, s * p.x + c * p.y);
+    return vec2<f32>(p.x / camera.anamorphic, p.y);
+}
+
+const NO_LIGHT: u32 = 0xffffffffu;
+const LIGHT_SPHERE: u32 = 0u;
+const LIGHT_TRIANGLE: u32 = 1u;
+
+struct Light{
+    kind: u32,
+    index: u32,
+    triangle: u32,
+    bit_trail: u32,
+}
+
+struct LightNode{
+    bounds_min: vec3<f32>,
+    power: f32,
+    bounds_max: vec3<f32>,
+    cos_theta_o: f32,
+    axis: vec3<f32>,
+    cos_theta_e: f32,
+    second_child: u32,
+    light: u32,
+    leaf: u32,
+}
+
+struct LightSample{
+    point: vec3<f32>,
+    dir: vec3<f32>,
+    dst: f32,
+    emission: vec4<f32>,
+    //Solid angle pdf including the probability of picking the light
+    pdf: f32,
+    light: u32,
+}
+
+fn light_sampling_enabled() -> bool{
+    return params.light_sampling != LIGHT_SAMPLING_NONE && light_nodes[0].power > 0.0;
+}
+
+fn power_heuristic(pdf_a: f32, pdf_b: f32) -> f32{
+    let a = pdf_a * pdf_a;
+    let b = pdf_b * pdf_b;
+    if(a + b <= 0.0){
+        return 0.0;
+    }
+    return a / (a + b);
+}
+
+//cos(max(0, a - b)) from the sines and cosines of a and b
+fn cos_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32{
+    if(cos_a > cos_b){
+        return 1.0;
+    }
+    return cos_a * cos_b + sin_a * sin_b;
+}
+
+fn sin_sub_clamped(sin_a: f32, cos_a: f32, sin_b: f32, cos_b: f32) -> f32{
+    if(cos_a > cos_b){
+        return 0.0;
+    }
+    return sin_a * cos_b - cos_a * sin_b;
+}
+
+//Estimated contribution of a light cluster to a point (Conty & Kulla 2018)
+fn light_importance(node: LightNode, point: vec3<f32>, normal: vec3<f32>) -> f32{
+    if(node.power <= 0.0){
+        return 0.0;
+    }
+    let center = (node.bounds_min + node.bounds_max) * 0.5;
+    let radius = length(node.bounds_max - node.bounds_min) * 0.5;
+    let to_light = center - point;
+    let dst_sq = max(dot(to_light, to_light), radius * radius);
+    let dst = length(to_light);
+    if(dst <= radius){
+        return node.power / dst_sq;
+    }
+    let wi = to_light / dst;
+    let sin_theta_u = radius / dst;
+    let cos_theta_u = sqrt(max(0.0, 1.0 - sin_theta_u * sin_theta_u));
+
+    //Angle between the emitters and the point, shrunk by the cone and the cluster's bounding sphere
+    let cos_theta = dot(node.axis, -wi);
+    let sin_theta = sqrt(max(0.0, 1.0 - cos_theta * cos_theta));
+    let sin_theta_o = sqrt(max(0.0, 1.0 - node.cos_theta_o * node.cos_theta_o));
+    let cos_x = cos_sub_clamped(sin_theta, cos_theta, sin_theta_o, node.cos_theta_o);
+    let sin_x = sin_sub_clamped(sin_theta, cos_theta, sin_theta_o, node.cos_theta_o);
+    let cos_theta_p = cos_sub_clamped(sin_x, cos_x, sin_theta_u, cos_theta_u);
+    if(cos_theta_p <= node.cos_theta_e){
+        return 0.0;
+    }
+
+    var cos_theta_i = 1.0;
+    if(any(normal != vec3<f32>(0.0))){
+        let cos_i = abs(dot(wi, normal));
+        let sin_i = sqrt(max(0.0, 1.0 - cos_i * cos_i));
+        cos_theta_i = cos_sub_clamped(sin_i, cos_i, sin_theta_u, cos_theta_u);
+    }
+    return node.power * cos_theta_p * cos_theta_i / dst_sq;
 }
-fn rand_hemisphere_dir_dist(normal: vec3<f32>, seed: ptr<function, u32>) -> vec3<f32>{
-    let dir = rand_unit_sphere(seed);
-    return dir * sign(dot(normal, dir));
+
+//Walks down the light BVH picking children proportional to their importance, u is reused at every level
+fn sample_light_tree(point: vec3<f32>, normal: vec3<f32>, u: f32, pmf: ptr<function, f32>) -> u32{
+    var node_index = 0u;
+    var u = u;
+    *pmf = 1.0;
+    loop{
+        let node = light_nodes[node_index];
+        if(node.leaf != 0u){
+            return node.light;
+        }
+        let left = light_importance(light_nodes[node_index + 1u], point, normal);
+        let right = light_importance(light_nodes[node.second_child], point, normal);
+        if(left + right <= 0.0){
+            return NO_LIGHT;
+        }
+        let p_left = left / (left + right);
+        if(u < p_left){
+            u = min(u / p_left, 0.99999994);
+            node_index += 1u;
+            *pmf *= p_left;
+        }else{
+            u = min((u - p_left) / (1.0 - p_left), 0.99999994);
+            node_index = node.second_child;
+            *pmf *= 1.0 - p_left;
+        }
+    }
+    return NO_LIGHT;
 }
 
-fn rand_in_unit_disk(seed: ptr<function, u32>)-> vec3<f32>{
-    for(var i = 0; i < 1000; i+=1){
-        let r1 = (rand(seed)*2.0);
-        let r2 = (rand(seed)*2.0);
-        let p = vec3<f32>(r1, r2, 1.0) - 1.0;
-        if(length(p) <= 1.0){
-            return p;
+fn light_tree_pmf(light_index: u32, point: vec3<f32>, normal: vec3<f32>) -> f32{
+    let bit_trail = lights[light_index].bit_trail;
+    var node_index = 0u;
+    var pmf = 1.0;
+    var depth = 0u;
+    loop{
+        let node = light_nodes[node_index];
+        if(node.leaf != 0u){
+            return pmf;
+        }
+        let left = light_importance(light_nodes[node_index + 1u], point, normal);
+        let right = light_importance(light_nodes[node.second_child], point, normal);
+        if(left + right <= 0.0){
+            return 0.0;
+        }
+        let p_left = left / (left + right);
+        if(((bit_trail >> depth) & 1u) != 0u){
+            node_index = node.second_child;
+            pmf *= 1.0 - p_left;
+        }else{
+            node_index += 1u;
+            pmf *= p_left;
         }
-        i -=1;
+        depth += 1u;
+    }
+    return 0.0;
+}
+
+//1 - cos of the cone a sphere subtends, with a series expansion for far away spheres
+fn sphere_cone_solid_angle(sphere: Sphere, point: vec3<f32>) -> f32{
+    let to_center = sphere.position - point;
+    let sin2_theta_max = sphere.radius * sphere.radius / dot(to_center, to_center);
+    if(sin2_theta_max >= 1.0){
+        return 0.0;
+    }
+    if(sin2_theta_max < 0.00068523){
+        return 2.0 * PI * (sin2_theta_max / 2.0);
+    }
+    return 2.0 * PI * (1.0 - sqrt(1.0 - sin2_theta_max));
+}
+
+//Samples the cone of directions the sphere subtends from point
+fn sample_sphere_light(sphere: Sphere, point: vec3<f32>, u: vec2<f32>) -> LightSample{
+    var ls: LightSample;
+    let solid_angle = sphere_cone_solid_angle(sphere, point);
+    if(solid_angle <= 0.0){
+        return ls;
+    }
+    let to_center = sphere.position - point;
+    let one_minus_cos_max = solid_angle / (2.0 * PI);
+    let cos_theta = 1.0 - u.x * one_minus_cos_max;
+    let sin_theta = sqrt(max(0.0, 1.0 - cos_theta * cos_theta));
+    let phi = 2.0 * PI * u.y;
+    ls.dir = orthonormal_basis(normalize(to_center)) * vec3<f32>(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+
+    let b = dot(to_center, ls.dir);
+    let discriminant = b * b - dot(to_center, to_center) + sphere.radius * sphere.radius;
+    ls.dst = b - sqrt(max(discriminant, 0.0));
+    ls.point = point + ls.dir * ls.dst;
+    ls.emission = to_spectrum(sphere.material.emission_color) * sphere.material.emission_strength;
+    ls.pdf = 1.0 / solid_angle;
+    return ls;
+}
+
+fn triangle_light_pdf(t: Triangle, point: vec3<f32>, light_point: vec3<f32>) -> f32{
+    let cross_ab_ac = cross(t.b.pos - t.a.pos, t.c.pos - t.a.pos);
+    let area = length(cross_ab_ac) * 0.5;
+    let to_light = light_point - point;
+    let dst_sq = dot(to_light, to_light);
+    //Triangles only emit from their front face, the side ray_triangle can hit
+    let cos_light = dot(normalize(cross_ab_ac), -to_light * inverseSqrt(dst_sq));
+    if(cos_light <= 0.0 || area <= 0.0){
+        return 0.0;
+    }
+    return dst_sq / (cos_light * area);
+}
+
+fn sample_triangle_light(t: Triangle, point: vec3<f32>, u: vec2<f32>) -> LightSample{
+    var ls: LightSample;
+    let su = sqrt(u.x);
+    let b0 = 1.0 - su;
+    let b1 = u.y * su;
+    ls.point = t.a.pos * b0 + t.b.pos * b1 + t.c.pos * (1.0 - b0 - b1);
+    let to_light = ls.point - point;
+    ls.dst = length(to_light);
+    ls.dir = to_light / ls.dst;
+    ls.pdf = triangle_light_pdf(t, point, ls.point);
+    return ls;
+}
+
+fn pick_light(point: vec3<f32>, normal: vec3<f32>, u: f32, pmf: ptr<function, f32>) -> u32{
+    if(params.light_sampling == LIGHT_SAMPLING_UNIFORM){
+        let count = arrayLength(&lights);
+        *pmf = 1.0 / f32(count);
+        return min(u32(u * f32(count)), count - 1u);
+    }
+    return sample_light_tree(point, normal, u, pmf);
+}
+
+fn sample_light(point: vec3<f32>, normal: vec3<f32>, rng: ptr<function, Sampler>) -> LightSample{
+    var ls: LightSample;
+    var pmf = 0.0;
+    let light_index = pick_light(point, normal, sample_1d(rng), &pmf);
+    let u = sample_2d(rng);
+    if(light_index == NO_LIGHT || pmf <= 0.0){
+        return ls;
+    }
+    let light = lights[light_index];
+    if(light.kind == LIGHT_SPHERE){
+        ls = sample_sphere_light(sphere_at(light.index), point, u);
+    }else{
+        let mesh = meshes[light.index];
+        ls = sample_triangle_light(mesh_triangle(light.index, light.triangle), point, u);
+        ls.emission = to_spectrum(mesh.material.emission_color) * mesh.material.emission_strength;
+    }
+    ls.pdf *= pmf;
+    ls.light = light_index;
+    return ls;
+}
+
+//Solid angle pdf of sample_light picking light_point on the given light
+fn light_pdf(light_index: u32, point: vec3<f32>, normal: vec3<f32>, light_point: vec3<f32>) -> f32{
+    var pmf = 1.0 / f32(arrayLength(&lights));
+    if(params.light_sampling == LIGHT_SAMPLING_BVH){
+        pmf = light_tree_pmf(light_index, point, normal);
     }
-    return vec3<f32>(0.0,0.0,0.0);
+    let light = lights[light_index];
+    if(light.kind == LIGHT_SPHERE){
+        let solid_angle = sphere_cone_solid_angle(sphere_at(light.index), point);
+        return select(0.0, pmf / solid_angle, solid_angle > 0.0);
+    }
+    return pmf * triangle_light_pdf(mesh_triangle(light.index, light.triangle), point, light_point);
+}
+
+fn visible(origin: vec3<f32>, dir: vec3<f32>, dst: f32) -> bool{
+    var ray: Ray;
+    ray.origin = origin;
+    ray.dir = dir;
+    let hit = calculate_ray_collions(ray);
+    return !hit.hit || hit.dst >= dst * (1.0 - 1e-3);
+}
+
+//Next event estimation, weighted against bsdf sampling with the power heuristic when mis is set
+fn sample_direct_light(hit: Hit, wo: vec3<f32>, mis: bool, rng: ptr<function, Sampler>) -> vec4<f32>{
+    let n = faceForward(hit.normal, -wo, hit.normal);
+    let ls = sample_light(hit.hit_point, n, rng);
+    if(ls.pdf <= 0.0){
+        return vec4<f32>(0.0);
+    }
+    let cos_theta = dot(ls.dir, n);
+    let f = eval_bsdf(hit.material, hit.normal, wo, ls.dir);
+    if(cos_theta <= 0.0 || all(f == vec4<f32>(0.0))){
+        return vec4<f32>(0.0);
+    }
+    if(!visible(offset_ray_origin(hit.hit_point, hit.normal, ls.dir), ls.dir, ls.dst)){
+        return vec4<f32>(0.0);
+    }
+    var weight = 1.0;
+    if(mis){
+        weight = power_heuristic(ls.pdf, pdf_bsdf(hit.material, hit.normal, wo, ls.dir));
+    }
+    direct_diffuse_fraction = diffuse_fraction(hit.material, hit.normal, wo, ls.dir);
+    return f * cos_theta * ls.emission * weight / ls.pdf;
+}
+
+struct BsdfSample{
+    dir: vec3<f32>,
+    value: vec4<f32>,
+    pdf: f32,
+    //Delta lobes (mirror, glass) can't be evaluated for an arbitrary direction, only sampled
+    delta: bool,
+    //The phong lobe was sampled rather than the diffuse one
+    glossy: bool,
 }
 
-fn trace(ray: Ray, seed: ptr<function, u32>) -> vec4<f32>{
+fn trace(ray: Ray, rng: ptr<function, Sampler>) -> vec4<f32>{
+    return trace_path(ray, vec4<f32>(1.0), 0, rng);
+}
+
+//Continues a path from first_depth. Past the camera the previous vertex already sampled the lights on
+//its own, so light list emission found at the first hit is left to it
+fn trace_path(ray: Ray, throughput: vec4<f32>, first_depth: i32, rng: ptr<function, Sampler>) -> vec4<f32>{
     var ray: Ray = ray;
-    var ray_color = vec4<f32>(1.0);
+    var ray_color = throughput;
     var incoming_light = vec4<f32>(0.0);
-    for (var i = 0; i <= params.number_of_bounces; i +=1){
-        var hit = calculate_ray_collions(ray);
+    //Previous vertex, needed to weight emission found by bsdf sampling against light sampling
+    var prev_point = ray.origin;
+    var prev_normal = vec3<f32>(0.0);
+    var prev_pdf = 0.0;
+    var prev_delta = true;
+    //Light reaching a diffuse vertex through only specular bounces is a caustic, the photon map has it
+    let photon_mapping = INTEGRATOR == INTEGRATOR_PHOTON;
+    var diffuse_seen = false;
+    if(first_depth == 0){
+        path_length = 0;
+        primary_lobe = LOBE_DIFFUSE;
+    }
+    for (var depth = first_depth; within_depth_limit(depth); depth +=1){
+        let hit = calculate_ray_collions(ray);
         if (hit.hit){
-            ray.origin = hit.hit_point;
-            let unit_dir = normalize(ray.dir);
-            //TODO look into different random hemisphere generators
-            //-1.0 is for glass
-            if(hit.material.smoothness == -1.0){
-                hit.material.color = vec4<f32>(1.0);
-
-                var front_face = false;
-                if(dot(ray.dir, hit.normal) > 0.0){
-                    front_face = false;
-                }else{
-                    front_face = true;
+            path_length = depth + 1;
+            let emitted_light = to_spectrum(hit.material.emission_color) * hit.material.emission_strength;
+            var weight = 1.0;
+            if(!prev_delta && hit.light != NO_LIGHT && light_sampling_enabled()){
+                weight = power_heuristic(prev_pdf, light_pdf(hit.light, prev_point, prev_normal, hit.hit_point));
+            }
+            if(photon_mapping && diffuse_seen && prev_delta){
+                weight = 0.0;
+            }
+            if(depth == first_depth && depth > 0 && hit.light != NO_LIGHT){
+                weight = 0.0;
+            }
+            let emitted = emitted_light * ray_color * weight;
+            incoming_light += emitted;
+            add_path_emission(depth, emitted);
+
+            let wo = -normalize(ray.dir);
+            if(!is_glass(hit.material) && !is_mirror(hit.material)){
+                if(light_sampling_enabled()){
+                    let direct = ray_color * sample_direct_light(hit, wo, true, rng);
+                    incoming_light += direct;
+                    add_path_light(depth, direct, select(primary_diffuse(), direct_diffuse_fraction, depth == 0));
                 }
-                //TODO get refraction_ratio from material 
-                var refraction_ratio = 1.5;
-                if(front_face){
-                    refraction_ratio = 1.0/1.5;
+                if(photon_mapping){
+                    //Photons have bounced at least once, so even at the primary hit they are indirect
+                    let photons = ray_color * gather_photons(hit, wo);
+                    incoming_light += photons;
+                    add_path_light(max(depth, 1), photons, select(primary_diffuse(), 1.0, depth == 0));
+                    diffuse_seen = true;
                 }
-                
-                let cos_theta = min(dot(-unit_dir, hit.normal),1.0);
-                let sin_theta = sqrt(1.0 - cos_theta*cos_theta);
-
-                let cannot_refract = refraction_ratio * sin_theta > 1.0;
+            }
 
-                if(cannot_refract || reflectance(cos_theta,refraction_ratio) > rand(seed)){
-                    ray.dir = reflect(unit_dir, hit.normal);
-                }else{
-                    ray.dir = refract(unit_dir,hit.normal,refraction_ratio);
-                }
-            } else{
-                var diffuse_dir = rand_hemisphere_dir_dist(hit.normal, seed);
-                let specular_dir = reflect(unit_dir, hit.normal);
-                ray.dir = mix(diffuse_dir,specular_dir,hit.material.smoothness);
+            let bsdf = sample_bsdf(hit.material, hit.normal, wo, rng);
+            if(bsdf.pdf <= 0.0){
+                break;
+            }
+            if(depth == 0 && (bsdf.delta || bsdf.glossy)){
+                primary_lobe = LOBE_SPECULAR;
+            }
+            ray_color *= bsdf.value * abs(dot(bsdf.dir, hit.normal)) / bsdf.pdf;
+            ray.origin = offset_ray_origin(hit.hit_point, hit.normal, bsdf.dir);
+            ray.dir = bsdf.dir;
+            prev_point = hit.hit_point;
+            prev_normal = faceForward(hit.normal, -wo, hit.normal);
+            prev_pdf = bsdf.pdf;
+            prev_delta = bsdf.delta;
+            if(!russian_roulette(depth, &ray_color, rng)){
+                break;
             }
-            
-            let emitted_light = hit.material.emission_color * hit.material.emission_strength;
-            incoming_light += emitted_light * ray_color;
-            ray_color *= hit.material.color;
         }else{
             if(params.toggle != 0){
-                incoming_light += get_environment_light(ray) * ray_color;
+                let environment = get_environment_light(ray) * ray_color;
+                incoming_light += environment;
+                add_path_emission(depth, environment);
             }
             break;
         }
@@ -281,47 +1205,1420 @@ fn trace(ray: Ray, seed: ptr<function, u32>) -> vec4<f32>{
     return incoming_light;
 }
 
-fn reflectance(cosine: f32, refraction_ratio: f32) -> f32{
-    var r0 = (1.0 - refraction_ratio) / (1.0 + refraction_ratio);
-    r0 = r0 * r0;
-    return r0 + (1.0 - r0) * pow((1.0-cosine),5.0);
+//Bidirectional path tracing (Veach 1997), connects every camera subpath vertex to every light subpath vertex.
+//Light subpath vertices connected straight to the camera (t = 1) land in another pixel, they're splatted and
+//added by bdpt_splat after the frame. Subpaths are capped at BDPT_VERTICES, has to match bdpt.rs
+const BDPT_VERTICES: i32 = 8;
+const SPLAT_SCALE: f32 = 65536.0;
+//Keeps a single firefly from overflowing a pixel's fixed point sum
+const MAX_SPLAT: f32 = 1024.0;
+const VERTEX_CAMERA: u32 = 0u;
+const VERTEX_LIGHT: u32 = 1u;
+const VERTEX_SURFACE: u32 = 2u;
+
+struct PathVertex{
+    kind: u32,
+    point: vec3<f32>,
+    normal: vec3<f32>,
+    throughput: vec4<f32>,
+    material: Material,
+    light: u32,
+    //Area measure densities of reaching this vertex from the previous and the next vertex
+    pdf_fwd: f32,
+    pdf_rev: f32,
+    delta: bool,
+    //Set once a dispersive bounce has dropped the secondary wavelengths
+    terminated: bool,
 }
 
-fn refract(uv: vec3<f32>, normal: vec3<f32>, refraction_ratio: f32) -> vec3<f32>{
-    let cos_theta = min(dot(-uv, normal),1.0);
-    let r_out_perp = refraction_ratio * (uv + cos_theta * normal);
-    let r_out_parallel = -sqrt(abs(1.0 - length(r_out_perp))) * normal;
-    return r_out_perp + r_out_parallel;
+var<private> camera_path: array<PathVertex, BDPT_VERTICES>;
+var<private> light_path: array<PathVertex, BDPT_VERTICES>;
+var<private> escaped_light: vec4<f32>;
+
+fn get_vertex(camera_walk: bool, i: i32) -> PathVertex{
+    if(camera_walk){
+        return camera_path[i];
+    }
+    return light_path[i];
 }
 
-fn get_environment_light(ray: Ray) -> vec4<f32>{
-    let sky_gradient_t = pow(smoothstep(0.0, 0.4, ray.dir.y), 0.35);
-    let ground_to_sky_t = smoothstep(-0.01, 0.0, ray.dir.y);
-    let sky_gradient = mix(SKY_HORIZON,SKY_ZENITH, sky_gradient_t);
-    let sun = pow(max(0.0, dot(ray.dir, vec3<f32>(0.1,1.0,0.1))),SUN_FOCUS) * SUN_INTENSITY;
-    let composite = mix(GROUND_COLOR, sky_gradient,ground_to_sky_t) + sun * f32(ground_to_sky_t >=1.0);
-    return composite;
+fn set_vertex(camera_walk: bool, i: i32, v: PathVertex){
+    if(camera_walk){
+        camera_path[i] = v;
+    }else{
+        light_path[i] = v;
+    }
 }
 
-fn frag(i: FragInput) -> vec4<f32>{
-    let pixel_coord = i.pos * i.size;
-    var rng_state = u32(pixel_coord.y * i.size.x + pixel_coord.x)+u32(abs(params.frames)) * 71939u;
+fn convert_density(pdf: f32, origin: PathVertex, to: PathVertex) -> f32{
+    let d = to.point - origin.point;
+    let dst_sq = dot(d, d);
+    if(dst_sq <= 0.0){
+        return 0.0;
+    }
+    if(to.kind == VERTEX_CAMERA){
+        return pdf / dst_sq;
+    }
+    return pdf * abs(dot(to.normal, d * inverseSqrt(dst_sq))) / dst_sq;
+}
 
-    var total_incoming_light = vec4<f32>(0.0);
+fn light_area(light_index: u32) -> f32{
+    let light = lights[light_index];
+    if(light.kind == LIGHT_SPHERE){
+        let r = spheres[light.index].radius;
+        return 4.0 * PI * r * r;
+    }
+    let t = mesh_triangle(light.index, light.triangle);
+    return length(cross(t.b.pos - t.a.pos, t.c.pos - t.a.pos)) * 0.5;
+}
 
-    for (var j = 0; j <= params.rays_per_pixel; j+=1){
-        let anti_aliasing = vec2<f32>(rand(&rng_state),rand(&rng_state));
-        let pos = (i.pos + anti_aliasing) / i.size;
-        
-        let rd = camera.lens_radius * rand_in_unit_disk(&rng_state);
-        let offset = camera.u * rd.x + camera.v * rd.y;
+fn light_normal(light_index: u32, point: vec3<f32>) -> vec3<f32>{
+    let light = lights[light_index];
+    if(light.kind == LIGHT_SPHERE){
+        return normalize(point - sphere_at(light.index).position);
+    }
+    let t = mesh_triangle(light.index, light.triangle);
+    return normalize(cross(t.b.pos - t.a.pos, t.c.pos - t.a.pos));
+}
 
-        var ray: Ray;
-        ray.origin = camera.origin + offset;
-        ray.dir = camera.lower_left_corner + pos.x * camera.horizontal + pos.y * camera.vertical - ray.origin;
+fn light_material(light_index: u32) -> Material{
+    let light = lights[light_index];
+    if(light.kind == LIGHT_SPHERE){
+        return spheres[light.index].material;
+    }
+    return meshes[light.index].material;
+}
 
-        total_incoming_light += trace(ray, &rng_state);
-    } 
+//Light subpaths start from a uniformly picked light
+fn light_origin_pdf(light_index: u32) -> f32{
+    return 1.0 / (f32(arrayLength(&lights)) * light_area(light_index));
+}
 
-    return total_incoming_light/f32(params.rays_per_pixel);
-}
\ No newline at end of file
+//Emitted directions are cosine distributed around the light's normal
+fn light_emission_pdf(v: PathVertex, next: PathVertex) -> f32{
+    let cos_theta = dot(v.normal, normalize(next.point - v.point));
+    if(cos_theta <= 0.0){
+        return 0.0;
+    }
+    return convert_density(cos_theta / PI, v, next);
+}
+
+//Area density of v sampling next when it was reached from prev
+fn vertex_pdf(v: PathVertex, prev: PathVertex, next: PathVertex) -> f32{
+    if(v.kind == VERTEX_LIGHT){
+        return light_emission_pdf(v, next);
+    }
+    let wo = normalize(prev.point - v.point);
+    let wi = normalize(next.point - v.point);
+    return convert_density(pdf_bsdf(v.material, v.normal, wo, wi), v, next);
+}
+
+//Extends the subpath whose first vertex is already stored, returns the number of vertices
+fn random_walk(ray: Ray, throughput: vec4<f32>, pdf: f32, camera_walk: bool, rng: ptr<function, Sampler>) -> i32{
+    var ray: Ray = ray;
+    var beta = throughput;
+    var pdf_fwd = pdf;
+    var count = 1;
+    for (; count < BDPT_VERTICES && within_depth_limit(count - 1); count += 1){
+        let hit = calculate_ray_collions(ray);
+        if(!hit.hit){
+            if(camera_walk && params.toggle != 0){
+                escaped_light += get_environment_light(ray) * beta;
+            }
+            break;
+        }
+        var prev = get_vertex(camera_walk, count - 1);
+        var v: PathVertex;
+        v.kind = VERTEX_SURFACE;
+        v.point = hit.hit_point;
+        v.normal = hit.normal;
+        v.throughput = beta;
+        v.material = hit.material;
+        v.light = hit.light;
+        v.pdf_fwd = convert_density(pdf_fwd, prev, v);
+        v.terminated = secondary_terminated;
+
+        let wo = -normalize(ray.dir);
+        let bsdf = sample_bsdf(hit.material, hit.normal, wo, rng);
+        if(bsdf.pdf <= 0.0){
+            set_vertex(camera_walk, count, v);
+            count += 1;
+            break;
+        }
+        beta *= bsdf.value * abs(dot(bsdf.dir, hit.normal)) / bsdf.pdf;
+        pdf_fwd = bsdf.pdf;
+        var pdf_rev = pdf_bsdf(hit.material, hit.normal, bsdf.dir, wo);
+        if(bsdf.delta){
+            v.delta = true;
+            pdf_fwd = 0.0;
+            pdf_rev = 0.0;
+        }
+        set_vertex(camera_walk, count, v);
+        prev.pdf_rev = convert_density(pdf_rev, v, prev);
+        set_vertex(camera_walk, count - 1, prev);
+        if(!russian_roulette(count, &beta, rng)){
+            count += 1;
+            break;
+        }
+
+        ray.origin = offset_ray_origin(hit.hit_point, hit.normal, bsdf.dir);
+        ray.dir = bsdf.dir;
+    }
+    return count;
+}
+
+fn generate_light_path(rng: ptr<function, Sampler>) -> i32{
+    let pick = sample_1d(rng);
+    let u = sample_2d(rng);
+    if(light_nodes[0].power <= 0.0){
+        return 0;
+    }
+    let v = sample_emitter(pick, u);
+    light_path[0] = v;
+
+    var ray: Ray;
+    ray.dir = sample_cosine_hemisphere(v.normal, rng);
+    ray.origin = offset_ray_origin(v.point, v.normal, ray.dir);
+    let cos_theta = dot(ray.dir, v.normal);
+    if(cos_theta <= 0.0){
+        return 1;
+    }
+    //Le * cos / (pdf_pos * cos / pi)
+    return random_walk(ray, v.throughput * PI, cos_theta / PI, false, rng);
+}
+
+//Uniformly picks a light and a point on it, throughput is Le over the area density
+fn sample_emitter(pick: f32, u: vec2<f32>) -> PathVertex{
+    let count = arrayLength(&lights);
+    let light_index = min(u32(pick * f32(count)), count - 1u);
+    let light = lights[light_index];
+
+    var v: PathVertex;
+    v.kind = VERTEX_LIGHT;
+    v.light = light_index;
+    v.material = light_material(light_index);
+    if(light.kind == LIGHT_SPHERE){
+        let sphere = sphere_at(light.index);
+        let z = 1.0 - 2.0 * u.x;
+        let r = sqrt(max(0.0, 1.0 - z * z));
+        let phi = 2.0 * PI * u.y;
+        v.normal = vec3<f32>(r * cos(phi), r * sin(phi), z);
+        v.point = sphere.position + v.normal * sphere.radius;
+    }else{
+        let t = mesh_triangle(light.index, light.triangle);
+        let su = sqrt(u.x);
+        let b0 = 1.0 - su;
+        let b1 = u.y * su;
+        v.point = t.a.pos * b0 + t.b.pos * b1 + t.c.pos * (1.0 - b0 - b1);
+        v.normal = light_normal(light_index, v.point);
+    }
+    v.pdf_fwd = light_origin_pdf(light_index);
+    let emission = to_spectrum(v.material.emission_color) * v.material.emission_strength;
+    v.throughput = emission / v.pdf_fwd;
+    return v;
+}
+
+fn remap0(pdf: f32) -> f32{
+    return select(1.0, pdf, pdf != 0.0);
+}
+
+//Balance heuristic over every strategy that could have made the same path, using ratios of the
+//densities along it. The vertices next to the connection get their reverse densities recomputed
+fn mis_weight(s: i32, t: i32) -> f32{
+    if(s + t == 2){
+        return 1.0;
+    }
+    var pt_rev = 0.0;
+    var pt_minus_rev = 0.0;
+    var qs_rev = 0.0;
+    var qs_minus_rev = 0.0;
+    let pt = camera_path[max(t - 1, 0)];
+    let pt_minus = camera_path[max(t - 2, 0)];
+    if(t == 1){
+        //Only the light subpath has vertices to reweight, s is at least 2
+        let qs = light_path[s - 1];
+        qs_rev = convert_density(camera_direction_pdf(qs.point - camera.origin), pt, qs);
+        qs_minus_rev = vertex_pdf(qs, pt, light_path[s - 2]);
+    }else if(s > 0){
+        let qs = light_path[s - 1];
+        if(s == 1){
+            pt_rev = light_emission_pdf(qs, pt);
+        }else{
+            pt_rev = vertex_pdf(qs, light_path[s - 2], pt);
+            qs_minus_rev = vertex_pdf(qs, pt, light_path[s - 2]);
+        }
+        pt_minus_rev = vertex_pdf(pt, qs, pt_minus);
+        qs_rev = vertex_pdf(pt, pt_minus, qs);
+    }else{
+        var emitter = pt;
+        emitter.kind = VERTEX_LIGHT;
+        emitter.normal = light_normal(pt.light, pt.point);
+        pt_rev = light_origin_pdf(pt.light);
+        pt_minus_rev = light_emission_pdf(emitter, pt_minus);
+    }
+
+    var sum = 0.0;
+    var ri = 1.0;
+    //The first vertex past the camera only counts as its own strategy when t = 1 is traced
+    let first = select(2, 1, light_tracing());
+    for (var i = t - 1; i >= first; i -= 1){
+        var rev = camera_path[i].pdf_rev;
+        if(i == t - 1){
+            rev = pt_rev;
+        }else if(i == t - 2){
+            rev = pt_minus_rev;
+        }
+        ri *= remap0(rev) / remap0(camera_path[i].pdf_fwd);
+        let delta = i != t - 1 && camera_path[i].delta;
+        if(!delta && !camera_path[i - 1].delta){
+            sum += ri;
+        }
+    }
+    ri = 1.0;
+    for (var i = s - 1; i >= 0; i -= 1){
+        var rev = light_path[i].pdf_rev;
+        if(i == s - 1){
+            rev = qs_rev;
+        }else if(i == s - 2){
+            rev = qs_minus_rev;
+        }
+        ri *= remap0(rev) / remap0(light_path[i].pdf_fwd);
+        let delta = i != s - 1 && light_path[i].delta;
+        let delta_prev = i > 0 && light_path[i - 1].delta;
+        if(!delta && !delta_prev){
+            sum += ri;
+        }
+    }
+    return 1.0 / (1.0 + sum);
+}
+
+//Contribution of the path made of the first s light and t camera vertices
+fn connect_bdpt(s: i32, t: i32) -> vec4<f32>{
+    let pt = camera_path[t - 1];
+    var contribution: vec4<f32>;
+    if(s == 0){
+        if(pt.light == NO_LIGHT){
+            return vec4<f32>(0.0);
+        }
+        contribution = pt.throughput * to_spectrum(pt.material.emission_color) * pt.material.emission_strength;
+    }else{
+        let qs = light_path[s - 1];
+        if(pt.delta || qs.delta){
+            return vec4<f32>(0.0);
+        }
+        let d = qs.point - pt.point;
+        let dst = length(d);
+        let dir = d / dst;
+        let f_pt = eval_bsdf(pt.material, pt.normal, normalize(camera_path[t - 2].point - pt.point), dir);
+        var f_qs = vec4<f32>(1.0);
+        var cos_qs = abs(dot(qs.normal, dir));
+        if(s == 1){
+            cos_qs = max(dot(qs.normal, -dir), 0.0);
+        }else{
+            f_qs = eval_bsdf(qs.material, qs.normal, normalize(light_path[s - 2].point - qs.point), -dir);
+        }
+        contribution = qs.throughput * f_qs * f_pt * pt.throughput * abs(dot(pt.normal, dir)) * cos_qs / (dst * dst);
+        if(all(contribution == vec4<f32>(0.0))){
+            return contribution;
+        }
+        if(!visible(offset_ray_origin(pt.point, pt.normal, dir), dir, dst)){
+            return vec4<f32>(0.0);
+        }
+        //Both halves already scaled the hero wavelength up
+        if(pt.terminated && qs.terminated){
+            contribution /= f32(WAVELENGTHS);
+        }
+    }
+    return contribution * mis_weight(s, t);
+}
+
+fn bdpt(ray: Ray, rng: ptr<function, Sampler>) -> vec4<f32>{
+    escaped_light = vec4<f32>(0.0);
+    secondary_terminated = false;
+    let light_vertices = generate_light_path(rng);
+
+    secondary_terminated = false;
+    var v: PathVertex;
+    v.kind = VERTEX_CAMERA;
+    v.point = ray.origin;
+    v.throughput = vec4<f32>(1.0);
+    v.light = NO_LIGHT;
+    camera_path[0] = v;
+    //The camera's direction density only matters to the weights when t = 1 is one of the strategies
+    let camera_pdf = select(1.0, camera_direction_pdf(ray.dir), light_tracing());
+    let camera_vertices = random_walk(ray, vec4<f32>(1.0), camera_pdf, true, rng);
+
+    var incoming_light = escaped_light;
+    for (var t = 2; t <= camera_vertices; t += 1){
+        for (var s = 0; s <= light_vertices; s += 1){
+            incoming_light += connect_bdpt(s, t);
+        }
+    }
+    if(light_tracing()){
+        for (var s = 2; s <= light_vertices; s += 1){
+            splat_light_vertex(s);
+        }
+    }
+    return incoming_light;
+}
+
+//Splatting needs the pixel a point lands on, so t = 1 only works through a pinhole perspective camera.
+//Adaptive sampling would stop tracing pixels that other pixels still splat into, so it turns it off too
+fn light_tracing() -> bool{
+    return camera.projection == PROJECTION_PERSPECTIVE && camera.lens_radius <= 0.0 && params.adaptive == 0;
+}
+
+//Density of a perspective camera ray in solid angle, 1 / (A cos^3) with A the image plane's area at distance 1.
+//It's also the camera's importance over the cosine at the camera, which is what a connection to it needs
+fn camera_direction_pdf(dir: vec3<f32>) -> f32{
+    let cos_theta = dot(normalize(dir), -camera.w);
+    if(cos_theta <= 0.0){
+        return 0.0;
+    }
+    let plane_distance = dot(camera.lower_left_corner - camera.origin, -camera.w);
+    let area = length(camera.horizontal) * length(camera.vertical) / (plane_distance * plane_distance);
+    return 1.0 / (area * cos_theta * cos_theta * cos_theta);
+}
+
+//Connects light subpath vertex s - 1 straight to the camera (t = 1) and splats it into the pixel it's seen through
+fn splat_light_vertex(s: i32){
+    let qs = light_path[s - 1];
+    if(qs.delta){
+        return;
+    }
+    let pixel = project(camera, qs.point);
+    if(!in_bounds(pixel)){
+        return;
+    }
+    let d = camera.origin - qs.point;
+    let dst = length(d);
+    let dir = d / dst;
+    let f = eval_bsdf(qs.material, qs.normal, normalize(light_path[s - 2].point - qs.point), dir);
+    let contribution = qs.throughput * f * abs(dot(qs.normal, dir)) * camera_direction_pdf(-d) / (dst * dst);
+    if(all(contribution == vec4<f32>(0.0))){
+        return;
+    }
+    if(!visible(offset_ray_origin(qs.point, qs.normal, dir), dir, dst)){
+        return;
+    }
+    //Every pixel traces rays_per_pixel light subpaths a frame, so that's what the splats average over
+    let weighted = contribution * mis_weight(s, 1) / f32(params.rays_per_pixel);
+    var rgb = weighted.rgb;
+    if(params.spectral != 0){
+        rgb = spectrum_to_rgb(weighted);
+    }
+    let index = pixel_index(vec2<u32>(pixel)) * 3u;
+    for (var c = 0; c < 3; c += 1){
+        //Also skips NaNs
+        if(rgb[c] > 0.0){
+            atomicAdd(&splats[index + u32(c)], u32(min(rgb[c], MAX_SPLAT) * SPLAT_SCALE + 0.5));
+        }
+    }
+}
+
+//Adds this frame's light tracing splats on top of what the bidirectional pass accumulated, with the same weight
+@compute
+@workgroup_size(8,8)
+fn bdpt_splat(@builtin(global_invocation_id) global_id: vec3<u32>){
+    if(global_id.x >= params.width || global_id.y >= params.height || !light_tracing()){
+        return;
+    }
+    let pos = vec2<i32>(global_id.xy);
+    let index = pixel_index(global_id.xy) * 3u;
+    let splat = vec4<f32>(
+        f32(atomicLoad(&splats[index])),
+        f32(atomicLoad(&splats[index + 1u])),
+        f32(atomicLoad(&splats[index + 2u])),
+        0.0,
+    ) / SPLAT_SCALE;
+    let weight = select(1.0, 1.0 / f32(params.frames + 1), params.frames >= 1);
+    textureStore(texture, pos, textureLoad(texture, pos) + splat * weight);
+}
+
+fn within_depth_limit(depth: i32) -> bool{
+    if(params.termination == TERMINATION_FIXED){
+        return depth <= params.number_of_bounces;
+    }
+    //A max_bounces of 0 leaves roulette as the only way a path ends
+    return params.max_bounces <= 0 || depth <= params.max_bounces;
+}
+
+//Terminates paths with probability based on their throughput and reweights the survivors so the estimate stays unbiased
+fn russian_roulette(depth: i32, throughput: ptr<function, vec4<f32>>, rng: ptr<function, Sampler>) -> bool{
+    if(params.termination != TERMINATION_RUSSIAN_ROULETTE || depth < params.min_bounces){
+        return true;
+    }
+    let survival = clamp(max((*throughput).r, max((*throughput).g, (*throughput).b)), 0.05, 0.95);
+    if(sample_1d(rng) >= survival){
+        return false;
+    }
+    *throughput /= survival;
+    return true;
+}
+
+fn offset_ray_origin(point: vec3<f32>, normal: vec3<f32>, dir: vec3<f32>) -> vec3<f32>{
+    return point + normal * EPSILON * sign(dot(dir, normal));
+}
+
+//Builds an orthonormal basis around n (Duff et al. 2017)
+fn orthonormal_basis(n: vec3<f32>) -> mat3x3<f32>{
+    let s = select(-1.0, 1.0, n.z >= 0.0);
+    let a = -1.0 / (s + n.z);
+    let b = n.x * n.y * a;
+    let t = vec3<f32>(1.0 + s * n.x * n.x * a, s * b, -s * n.x);
+    let bt = vec3<f32>(b, s + n.y * n.y * a, -n.y);
+    return mat3x3<f32>(t, bt, n);
+}
+
+fn sample_cosine_hemisphere(normal: vec3<f32>, rng: ptr<function, Sampler>) -> vec3<f32>{
+    let u = sample_2d(rng);
+    let r = sqrt(u.x);
+    let phi = 2.0 * PI * u.y;
+    let local = vec3<f32>(r * cos(phi), r * sin(phi), sqrt(max(0.0, 1.0 - r * r)));
+    return orthonormal_basis(normal) * local;
+}
+
+//Samples a normalized Phong lobe cos^n around axis, pdf = (n+1)/(2pi) cos^n
+fn sample_phong_lobe(axis: vec3<f32>, exponent: f32, rng: ptr<function, Sampler>) -> vec3<f32>{
+    let u = sample_2d(rng);
+    let cos_alpha = pow(u.x, 1.0 / (exponent + 1.0));
+    let sin_alpha = sqrt(max(0.0, 1.0 - cos_alpha * cos_alpha));
+    let phi = 2.0 * PI * u.y;
+    let local = vec3<f32>(sin_alpha * cos(phi), sin_alpha * sin(phi), cos_alpha);
+    return orthonormal_basis(axis) * local;
+}
+
+//Maps smoothness [0,1) to a Phong exponent, smoothness 1 is a perfect mirror
+fn specular_exponent(smoothness: f32) -> f32{
+    return pow(2.0, 1.0 + 12.0 * smoothness);
+}
+
+fn is_glass(material: Material) -> bool{
+    return material.smoothness == -1.0;
+}
+
+fn is_mirror(material: Material) -> bool{
+    return material.smoothness >= 1.0;
+}
+
+//Diffuse + glossy mixture, the glossy lobe is chosen with probability smoothness
+fn eval_bsdf(material: Material, normal: vec3<f32>, wo: vec3<f32>, wi: vec3<f32>) -> vec4<f32>{
+    if(is_glass(material) || is_mirror(material)){
+        return vec4<f32>(0.0);
+    }
+    let n = faceForward(normal, -wo, normal);
+    if(dot(wi, n) <= 0.0){
+        return vec4<f32>(0.0);
+    }
+    let s = max(material.smoothness, 0.0);
+    let exponent = specular_exponent(s);
+    let cos_alpha = max(dot(reflect(-wo, n), wi), 0.0);
+    let diffuse = (1.0 - s) / PI;
+    let glossy = s * (exponent + 2.0) / (2.0 * PI) * pow(cos_alpha, exponent);
+    return to_spectrum(material.color) * (diffuse + glossy);
+}
+
+fn pdf_bsdf(material: Material, normal: vec3<f32>, wo: vec3<f32>, wi: vec3<f32>) -> f32{
+    if(is_glass(material) || is_mirror(material)){
+        return 0.0;
+    }
+    let n = faceForward(normal, -wo, normal);
+    let cos_theta = dot(wi, n);
+    if(cos_theta <= 0.0){
+        return 0.0;
+    }
+    let s = max(material.smoothness, 0.0);
+    let exponent = specular_exponent(s);
+    let cos_alpha = max(dot(reflect(-wo, n), wi), 0.0);
+    let diffuse = cos_theta / PI;
+    let glossy = (exponent + 1.0) / (2.0 * PI) * pow(cos_alpha, exponent);
+    return (1.0 - s) * diffuse + s * glossy;
+}
+
+fn sample_bsdf(material: Material, normal: vec3<f32>, wo: vec3<f32>, rng: ptr<function, Sampler>) -> BsdfSample{
+    var bsdf: BsdfSample;
+    if(is_glass(material)){
+        var bsdf = sample_dielectric(normal, wo, material_ior(material), rng);
+        //Every wavelength refracts in a different direction so only the hero can follow this one
+        if(params.spectral != 0 && material.dispersion != DISPERSION_NONE && !secondary_terminated){
+            bsdf.value *= vec4<f32>(f32(WAVELENGTHS), 0.0, 0.0, 0.0);
+            secondary_terminated = true;
+        }
+        return bsdf;
+    }
+    let n = faceForward(normal, -wo, normal);
+    if(is_mirror(material)){
+        bsdf.dir = reflect(-wo, n);
+        bsdf.value = to_spectrum(material.color) / max(dot(bsdf.dir, n), EPSILON);
+        bsdf.pdf = 1.0;
+        bsdf.delta = true;
+        return bsdf;
+    }
+    let s = max(material.smoothness, 0.0);
+    if(sample_1d(rng) < s){
+        bsdf.dir = sample_phong_lobe(reflect(-wo, n), specular_exponent(s), rng);
+        bsdf.glossy = true;
+    }else{
+        bsdf.dir = sample_cosine_hemisphere(n, rng);
+    }
+    bsdf.value = eval_bsdf(material, normal, wo, bsdf.dir);
+    bsdf.pdf = pdf_bsdf(material, normal, wo, bsdf.dir);
+    bsdf.delta = false;
+    return bsdf;
+}
+
+//Smooth dielectric, reflection or refraction is picked proportional to fresnel so the weight stays 1
+fn sample_dielectric(normal: vec3<f32>, wo: vec3<f32>, ior: f32, rng: ptr<function, Sampler>) -> BsdfSample{
+    var bsdf: BsdfSample;
+    let front_face = dot(wo, normal) > 0.0;
+    let n = select(-normal, normal, front_face);
+    let refraction_ratio = select(ior, 1.0 / ior, front_face);
+
+    let cos_theta = min(dot(wo, n), 1.0);
+    let sin_theta = sqrt(1.0 - cos_theta*cos_theta);
+    let cannot_refract = refraction_ratio * sin_theta > 1.0;
+    let fresnel = reflectance(cos_theta, refraction_ratio);
+
+    if(cannot_refract || fresnel > sample_1d(rng)){
+        bsdf.dir = reflect(-wo, n);
+        bsdf.pdf = select(fresnel, 1.0, cannot_refract);
+        bsdf.value = vec4<f32>(bsdf.pdf);
+    }else{
+        bsdf.dir = refract(-wo, n, refraction_ratio);
+        bsdf.pdf = 1.0 - fresnel;
+        bsdf.value = vec4<f32>(bsdf.pdf);
+    }
+    bsdf.value /= max(abs(dot(bsdf.dir, n)), EPSILON);
+    bsdf.delta = true;
+    return bsdf;
+}
+
+fn reflectance(cosine: f32, refraction_ratio: f32) -> f32{
+    var r0 = (1.0 - refraction_ratio) / (1.0 + refraction_ratio);
+    r0 = r0 * r0;
+    return r0 + (1.0 - r0) * pow((1.0-cosine),5.0);
+}
+
+fn refract(uv: vec3<f32>, normal: vec3<f32>, refraction_ratio: f32) -> vec3<f32>{
+    let cos_theta = min(dot(-uv, normal),1.0);
+    let r_out_perp = refraction_ratio * (uv + cos_theta * normal);
+    let r_out_parallel = -sqrt(abs(1.0 - dot(r_out_perp, r_out_perp))) * normal;
+    return r_out_perp + r_out_parallel;
+}
+
+fn get_environment_light(ray: Ray) -> vec4<f32>{
+    let sky_gradient_t = pow(smoothstep(0.0, 0.4, ray.dir.y), 0.35);
+    let ground_to_sky_t = smoothstep(-0.01, 0.0, ray.dir.y);
+    let sky_gradient = mix(SKY_HORIZON,SKY_ZENITH, sky_gradient_t);
+    let sun = pow(max(0.0, dot(ray.dir, vec3<f32>(0.1,1.0,0.1))),SUN_FOCUS) * SUN_INTENSITY;
+    let composite = mix(GROUND_COLOR, sky_gradient,ground_to_sky_t) + sun * f32(ground_to_sky_t >=1.0);
+    return to_spectrum(composite);
+}
+
+fn frag(i: FragInput) -> vec4<f32>{
+    var total_incoming_light = vec4<f32>(0.0);
+
+    //The reservoirs only describe the first sample's primary hit
+    let samples = select(params.rays_per_pixel, 1, INTEGRATOR == INTEGRATOR_RESTIR);
+    for(var k = 0; k < PATH_AOVS; k+=1){
+        pixel_aovs[k] = vec4<f32>(0.0);
+    }
+    for (var j = 0; j < samples; j+=1){
+        for(var k = 0; k < PATH_AOVS; k+=1){
+            path_aovs[k] = vec4<f32>(0.0);
+        }
+        let sample_index = u32(max(params.frames, 0) * params.rays_per_pixel + j);
+        var rng = start_sample(i.pixel, sample_index);
+        let ray = camera_ray(i, &rng);
+        if(all(ray.dir == vec3<f32>(0.0))){
+            continue;
+        }
+
+        var incoming_light: vec4<f32>;
+        if(INTEGRATOR == INTEGRATOR_BDPT){
+            incoming_light = bdpt(ray, &rng);
+        }else if(INTEGRATOR == INTEGRATOR_RESTIR){
+            incoming_light = restir_shade(i.pixel, ray, &rng);
+        }else if(INTEGRATOR == INTEGRATOR_DIRECT){
+            incoming_light = direct_only(ray, &rng);
+        }else if(INTEGRATOR == INTEGRATOR_AO){
+            incoming_light = occlusion(ray, &rng);
+        }else if(INTEGRATOR == INTEGRATOR_WHITTED){
+            incoming_light = whitted_trace(ray);
+        }else if(INTEGRATOR == INTEGRATOR_DEBUG){
+            incoming_light = debug_shade(ray, &rng);
+        }else{
+            incoming_light = trace(ray, &rng);
+        }
+        //Occlusion and debug output are already rgb
+        if(params.spectral != 0 && INTEGRATOR != INTEGRATOR_AO && INTEGRATOR != INTEGRATOR_DEBUG){
+            total_incoming_light += vec4<f32>(spectrum_to_rgb(incoming_light), 1.0);
+            for(var k = 0; k < PATH_AOVS; k+=1){
+                pixel_aovs[k] += vec4<f32>(spectrum_to_rgb(path_aovs[k]), 1.0) / f32(samples);
+            }
+        }else{
+            total_incoming_light += incoming_light;
+            for(var k = 0; k < PATH_AOVS; k+=1){
+                pixel_aovs[k] += path_aovs[k] / f32(samples);
+            }
+        }
+    } 
+
+    return total_incoming_light/f32(samples);
+}
+
+const PROJECTION_PERSPECTIVE: i32 = 0;
+const PROJECTION_ORTHOGRAPHIC: i32 = 1;
+const PROJECTION_FISHEYE: i32 = 2;
+const PROJECTION_EQUIRECTANGULAR: i32 = 3;
+const PROJECTION_CUBE_FACE: i32 = 4;
+
+//Has to match Camera::pixel_ray. Rays outside the fisheye image circle get a zero direction
+fn camera_ray(i: FragInput, rng: ptr<function, Sampler>) -> Ray{
+    let anti_aliasing = sample_2d(rng);
+    let pos = (i.pos + anti_aliasing) / i.size;
+    let p = pos * 2.0 - 1.0;
+    let aspect = length(camera.horizontal) / length(camera.vertical);
+    if(params.spectral != 0){
+        sample_wavelengths(sample_1d(rng));
+    }
+    ray_time = sample_shutter(sample_1d(rng));
+
+    var ray: Ray;
+    ray.origin = camera.origin;
+    if(camera.projection == PROJECTION_ORTHOGRAPHIC){
+        ray.origin += 0.5 * camera.ortho_height * (p.x * aspect * camera.u + p.y * camera.v);
+        ray.dir = -camera.w;
+    }else if(camera.projection == PROJECTION_FISHEYE){
+        let r = length(vec2<f32>(p.x * aspect, p.y));
+        if(r > 1.0){
+            ray.dir = vec3<f32>(0.0);
+            return ray;
+        }
+        let theta = r * camera.fisheye_fov / 2.0;
+        let phi = atan2(p.y, p.x * aspect);
+        ray.dir = sin(theta) * (cos(phi) * camera.u + sin(phi) * camera.v) - cos(theta) * camera.w;
+    }else if(camera.projection == PROJECTION_EQUIRECTANGULAR){
+        let phi = p.x * PI;
+        let theta = p.y * PI / 2.0;
+        ray.dir = cos(theta) * (sin(phi) * camera.u - cos(phi) * camera.w) + sin(theta) * camera.v;
+    }else if(camera.projection == PROJECTION_CUBE_FACE){
+        var faces = array<vec3<f32>, 6>(
+            vec3<f32>(1.0, p.y, -p.x),
+            vec3<f32>(-1.0, p.y, p.x),
+            vec3<f32>(p.x, 1.0, -p.y),
+            vec3<f32>(p.x, -1.0, p.y),
+            vec3<f32>(p.x, p.y, 1.0),
+            vec3<f32>(-p.x, p.y, -1.0),
+        );
+        ray.dir = normalize(faces[clamp(camera.cube_face, 0, 5)]);
+    }else{
+        let rd = camera.lens_radius * sample_aperture(rng);
+        ray.origin += camera.u * rd.x + camera.v * rd.y;
+        ray.dir = camera.lower_left_corner + pos.x * camera.horizontal + pos.y * camera.vertical - ray.origin;
+    }
+    return ray;
+}
+
+//Spectral mode traces WAVELENGTHS wavelengths per path, one in each channel of the usual vec4.
+//The first (hero) is sampled uniformly and the rest are evenly rotated from it (Wilkie et al. 2014)
+const WAVELENGTHS: i32 = 4;
+const LAMBDA_MIN: f32 = 380.0;
+const LAMBDA_MAX: f32 = 780.0;
+const CIE_Y_INTEGRAL: f32 = 106.856895;
+const DISPERSION_NONE: u32 = 0u;
+const DISPERSION_CAUCHY: u32 = 1u;
+const DISPERSION_SELLMEIER: u32 = 2u;
+const SMITS_BINS: i32 = 10;
+
+var<private> wavelengths: vec4<f32>;
+var<private> secondary_terminated: bool;
+//The 7 Smits basis spectra evaluated at the current wavelengths
+var<private> spectral_basis: array<vec4<f32>, 7>;
+
+//Smits 1999 white, cyan, magenta, yellow, red, green and blue reflectances over 380-720nm
+var<private> SMITS: array<f32, 70> = array<f32, 70>(
+    1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000,
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000,
+    1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959,
+    0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840,
+    0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149,
+    0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025,
+    1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496,
+);
+
+fn sample_wavelengths(u: f32){
+    let offsets = vec4<f32>(0.0, 1.0, 2.0, 3.0) / f32(WAVELENGTHS);
+    wavelengths = LAMBDA_MIN + fract(u + offsets) * (LAMBDA_MAX - LAMBDA_MIN);
+    secondary_terminated = false;
+    for(var b = 0; b < 7; b++){
+        spectral_basis[b] = vec4<f32>(
+            smits(b, wavelengths.x),
+            smits(b, wavelengths.y),
+            smits(b, wavelengths.z),
+            smits(b, wavelengths.w),
+        );
+    }
+}
+
+fn smits(basis: i32, lambda: f32) -> f32{
+    let t = clamp((lambda - LAMBDA_MIN) / (720.0 - LAMBDA_MIN), 0.0, 1.0) * f32(SMITS_BINS - 1);
+    let bin = min(i32(t), SMITS_BINS - 2);
+    let a = SMITS[basis * SMITS_BINS + bin];
+    let b = SMITS[basis * SMITS_BINS + bin + 1];
+    return mix(a, b, t - f32(bin));
+}
+
+//Rgb colors are left alone unless rendering spectrally, then they are uplifted with Smits' method
+fn to_spectrum(color: vec4<f32>) -> vec4<f32>{
+    if(params.spectral == 0){
+        return color;
+    }
+    let r = color.r;
+    let g = color.g;
+    let b = color.b;
+    var spectrum: vec4<f32>;
+    if(r <= g && r <= b){
+        spectrum = r * spectral_basis[0];
+        if(g <= b){
+            spectrum += (g - r) * spectral_basis[1] + (b - g) * spectral_basis[6];
+        }else{
+            spectrum += (b - r) * spectral_basis[1] + (g - b) * spectral_basis[5];
+        }
+    }else if(g <= r && g <= b){
+        spectrum = g * spectral_basis[0];
+        if(r <= b){
+            spectrum += (r - g) * spectral_basis[2] + (b - r) * spectral_basis[6];
+        }else{
+            spectrum += (b - g) * spectral_basis[2] + (r - b) * spectral_basis[4];
+        }
+    }else{
+        spectrum = b * spectral_basis[0];
+        if(r <= g){
+            spectrum += (r - b) * spectral_basis[3] + (g - r) * spectral_basis[5];
+        }else{
+            spectrum += (g - b) * spectral_basis[3] + (r - g) * spectral_basis[4];
+        }
+    }
+    return max(spectrum, vec4<f32>(0.0));
+}
+
+//Glass follows the hero wavelength, in rgb mode the precomputed d line ior is used
+fn material_ior(material: Material) -> f32{
+    if(params.spectral == 0 || material.dispersion == DISPERSION_NONE){
+        return material.ior;
+    }
+    let l = wavelengths.x / 1000.0;
+    let l2 = l * l;
+    if(material.dispersion == DISPERSION_CAUCHY){
+        return material.ior_b.x + material.ior_b.y / l2 + material.ior_b.z / (l2 * l2);
+    }
+    let terms = material.ior_b * l2 / (vec3<f32>(l2) - material.ior_c);
+    return sqrt(1.0 + terms.x + terms.y + terms.z);
+}
+
+//Piecewise gaussian fit of the CIE 1931 observer (Wyman et al. 2013)
+fn cie_lobe(x: f32, mu: f32, sigma_low: f32, sigma_high: f32) -> f32{
+    let t = (x - mu) / select(sigma_high, sigma_low, x < mu);
+    return exp(-0.5 * t * t);
+}
+
+fn cie_xyz(lambda: f32) -> vec3<f32>{
+    let x = 1.056 * cie_lobe(lambda, 599.8, 37.9, 31.0) + 0.362 * cie_lobe(lambda, 442.0, 16.0, 26.7) - 0.065 * cie_lobe(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * cie_lobe(lambda, 568.8, 46.9, 40.5) + 0.286 * cie_lobe(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * cie_lobe(lambda, 437.0, 11.8, 36.0) + 0.681 * cie_lobe(lambda, 459.0, 26.0, 13.8);
+    return vec3<f32>(x, y, z);
+}
+
+fn spectrum_to_rgb(radiance: vec4<f32>) -> vec3<f32>{
+    let pdf = 1.0 / (LAMBDA_MAX - LAMBDA_MIN);
+    var xyz = vec3<f32>(0.0);
+    for(var i = 0; i < WAVELENGTHS; i++){
+        xyz += cie_xyz(wavelengths[i]) * radiance[i];
+    }
+    xyz /= pdf * f32(WAVELENGTHS) * CIE_Y_INTEGRAL;
+    //Columns of the XYZ to linear sRGB matrix
+    let xyz_to_srgb = mat3x3<f32>(
+        vec3<f32>(3.2404542, -0.9692660, 0.0556434),
+        vec3<f32>(-1.5371385, 1.8760108, -0.2040259),
+        vec3<f32>(-0.4985314, 0.0415560, 1.0572252),
+    );
+    //The uplifted spectra are relative to an equal energy white, scale so it stays white in sRGB
+    let white = xyz_to_srgb * vec3<f32>(1.0);
+    return xyz_to_srgb * xyz / white;
+}
+
+//Progressive photon mapping (Knaus & Zwicker 2011) for caustics only. Photons are stored at the first
+//diffuse surface after at least one specular bounce, every other path is left to trace
+const MAX_PHOTONS: u32 = 524288u;
+const HASH_CELLS: u32 = 262144u;
+const MAX_PHOTONS_PER_CELL: i32 = 256;
+
+struct Photon{
+    position: vec3<f32>,
+    next: u32,
+    dir: vec3<f32>,
+    //Rgb even in spectral mode, the photon's own wavelengths don't match the camera path's
+    power: vec4<f32>,
+}
+
+fn photon_cell(point: vec3<f32>) -> vec3<i32>{
+    return vec3<i32>(floor(point / params.photon_radius));
+}
+
+fn photon_hash(cell: vec3<i32>) -> u32{
+    let c = bitcast<vec3<u32>>(cell);
+    return ((c.x * 73856093u) ^ (c.y * 19349663u) ^ (c.z * 83492791u)) % HASH_CELLS;
+}
+
+fn store_photon(point: vec3<f32>, dir: vec3<f32>, power: vec4<f32>){
+    let slot = atomicAdd(&photon_grid[0], 1u);
+    if(slot >= MAX_PHOTONS){
+        return;
+    }
+    photons[slot].position = point;
+    photons[slot].dir = dir;
+    photons[slot].power = power;
+    photons[slot].next = atomicExchange(&photon_grid[1u + photon_hash(photon_cell(point))], slot + 1u);
+}
+
+@compute
+@workgroup_size(64)
+fn emit_photons(@builtin(global_invocation_id) global_id: vec3<u32>){
+    if(global_id.x >= u32(params.photon_count) || light_nodes[0].power <= 0.0){
+        return;
+    }
+    //A row past the image so photons don't reuse the camera rays' scrambles
+    var rng = start_sample(vec2<u32>(global_id.x, params.height), u32(max(params.frames, 0)));
+    secondary_terminated = false;
+    if(params.spectral != 0){
+        sample_wavelengths(sample_1d(&rng));
+    }
+    ray_time = sample_shutter(sample_1d(&rng));
+    let v = sample_emitter(sample_1d(&rng), sample_2d(&rng));
+    var ray: Ray;
+    ray.dir = sample_cosine_hemisphere(v.normal, &rng);
+    ray.origin = offset_ray_origin(v.point, v.normal, ray.dir);
+    var power = v.throughput * PI;
+    for (var depth = 0; within_depth_limit(depth); depth +=1){
+        let hit = calculate_ray_collions(ray);
+        if(!hit.hit){
+            break;
+        }
+        let wo = -normalize(ray.dir);
+        if(!is_glass(hit.material) && !is_mirror(hit.material)){
+            if(depth > 0){
+                if(params.spectral != 0){
+                    power = vec4<f32>(spectrum_to_rgb(power), 1.0);
+                }
+                store_photon(hit.hit_point, wo, power);
+            }
+            break;
+        }
+        let bsdf = sample_bsdf(hit.material, hit.normal, wo, &rng);
+        if(bsdf.pdf <= 0.0){
+            break;
+        }
+        power *= bsdf.value * abs(dot(bsdf.dir, hit.normal)) / bsdf.pdf;
+        ray.origin = offset_ray_origin(hit.hit_point, hit.normal, bsdf.dir);
+        ray.dir = bsdf.dir;
+    }
+}
+
+//Density estimate over a disc of the current radius, cells are the radius wide so 3x3x3 covers it
+fn gather_photons(hit: Hit, wo: vec3<f32>) -> vec4<f32>{
+    let radius_sq = params.photon_radius * params.photon_radius;
+    let center = photon_cell(hit.hit_point);
+    let n = faceForward(hit.normal, -wo, hit.normal);
+    var flux = vec4<f32>(0.0);
+    for (var z = -1; z <= 1; z += 1){
+        for (var y = -1; y <= 1; y += 1){
+            for (var x = -1; x <= 1; x += 1){
+                let cell = center + vec3<i32>(x, y, z);
+                var next = atomicLoad(&photon_grid[1u + photon_hash(cell)]);
+                for (var i = 0; i < MAX_PHOTONS_PER_CELL && next != 0u; i += 1){
+                    let photon = photons[next - 1u];
+                    next = photon.next;
+                    //Other cells can share the hash bucket, skip them so nothing is counted twice
+                    if(any(photon_cell(photon.position) != cell)){
+                        continue;
+                    }
+                    let d = photon.position - hit.hit_point;
+                    if(dot(d, d) > radius_sq || dot(photon.dir, n) <= 0.0){
+                        continue;
+                    }
+                    flux += eval_bsdf(hit.material, hit.normal, wo, photon.dir) * to_spectrum(photon.power);
+                }
+            }
+        }
+    }
+    return flux / (PI * radius_sq * f32(params.photon_count));
+}
+
+//Spatiotemporal reservoir resampling of direct light (Bitterli et al. 2020). restir_initial picks a light
+//sample per pixel with RIS and merges last frame's reservoir, restir_spatial merges random neighbours
+//and restir_shade shades the result, path tracing everything past the first bounce
+struct Reservoir{
+    light_point: vec3<f32>,
+    light: u32,
+    point: vec3<f32>,
+    w_sum: f32,
+    normal: vec3<f32>,
+    m: f32,
+    weight: f32,
+    target_pdf: f32,
+}
+
+fn pixel_index(pixel: vec2<u32>) -> u32{
+    return pixel.y * params.width + pixel.x;
+}
+
+fn luminance(color: vec4<f32>) -> f32{
+    if(params.spectral != 0){
+        return dot(color, vec4<f32>(0.25));
+    }
+    return dot(color.rgb, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+fn restir_surface(hit: Hit) -> bool{
+    return hit.hit && !is_glass(hit.material) && !is_mirror(hit.material);
+}
+
+fn empty_reservoir(hit: Hit) -> Reservoir{
+    var r: Reservoir;
+    r.light = NO_LIGHT;
+    r.point = hit.hit_point;
+    r.normal = hit.normal;
+    return r;
+}
+
+//Unshadowed light reflected towards wo, in area measure over the light's surface
+fn restir_contribution(hit: Hit, wo: vec3<f32>, light: u32, light_point: vec3<f32>) -> vec4<f32>{
+    if(light >= arrayLength(&lights)){
+        return vec4<f32>(0.0);
+    }
+    let d = light_point - hit.hit_point;
+    let dst_sq = dot(d, d);
+    let dir = d * inverseSqrt(dst_sq);
+    let cos_light = dot(light_normal(light, light_point), -dir);
+    let n = faceForward(hit.normal, -wo, hit.normal);
+    let cos_theta = dot(n, dir);
+    if(cos_light <= 0.0 || cos_theta <= 0.0){
+        return vec4<f32>(0.0);
+    }
+    let material = light_material(light);
+    let emission = to_spectrum(material.emission_color) * material.emission_strength;
+    return eval_bsdf(hit.material, hit.normal, wo, dir) * emission * cos_theta * cos_light / dst_sq;
+}
+
+fn update_reservoir(r: ptr<function, Reservoir>, light: u32, light_point: vec3<f32>, w: f32, target_pdf: f32, m: f32, u: f32){
+    (*r).w_sum += w;
+    (*r).m += m;
+    if(w > 0.0 && u * (*r).w_sum < w){
+        (*r).light = light;
+        (*r).light_point = light_point;
+        (*r).target_pdf = target_pdf;
+    }
+}
+
+//Merges another reservoir whose sample has target_pdf at this reservoir's surface
+fn combine_reservoir(r: ptr<function, Reservoir>, other: Reservoir, target_pdf: f32, u: f32){
+    update_reservoir(r, other.light, other.light_point, target_pdf * other.weight * other.m, target_pdf, other.m, u);
+}
+
+fn finalize_reservoir(r: ptr<function, Reservoir>){
+    if((*r).target_pdf > 0.0 && (*r).m > 0.0){
+        (*r).weight = (*r).w_sum / ((*r).m * (*r).target_pdf);
+    }else{
+        (*r).weight = 0.0;
+    }
+}
+
+fn similar_surface(r: Reservoir, hit: Hit) -> bool{
+    return r.m > 0.0 && dot(r.normal, hit.normal) > 0.9 && distance(r.point, hit.hit_point) < 0.1 * distance(camera.origin, hit.hit_point);
+}
+
+//Pixel a perspective camera sees the point through, -1 when it's behind the camera
+fn project(c: Camera, point: vec3<f32>) -> vec2<i32>{
+    let normal = cross(c.horizontal, c.vertical);
+    let dir = point - c.origin;
+    let denominator = dot(dir, normal);
+    if(abs(denominator) < 1e-8){
+        return vec2<i32>(-1);
+    }
+    let t = dot(c.lower_left_corner - c.origin, normal) / denominator;
+    if(t <= 0.0){
+        return vec2<i32>(-1);
+    }
+    let q = c.origin + dir * t - c.lower_left_corner;
+    let uv = vec2<f32>(
+        dot(q, c.horizontal) / dot(c.horizontal, c.horizontal),
+        dot(q, c.vertical) / dot(c.vertical, c.vertical),
+    );
+    return vec2<i32>(floor(uv * vec2<f32>(f32(params.width), f32(params.height))));
+}
+
+//Pixel the point was seen through last frame, -1 when it was behind the previous camera
+//Only the perspective projection can be inverted this way, the rest go without temporal reuse
+fn reproject(point: vec3<f32>) -> vec2<i32>{
+    if(prev_camera.projection != PROJECTION_PERSPECTIVE){
+        return vec2<i32>(-1);
+    }
+    return project(prev_camera, point);
+}
+
+fn in_bounds(pixel: vec2<i32>) -> bool{
+    return all(pixel >= vec2<i32>(0)) && pixel.x < i32(params.width) && pixel.y < i32(params.height);
+}
+
+@compute
+@workgroup_size(8,8)
+fn restir_initial(@builtin(global_invocation_id) global_id: vec3<u32>){
+    if(global_id.x >= params.width || global_id.y >= params.height){
+        return;
+    }
+    let i = frag_input(global_id);
+    //Same sample as frag so every pass sees the same primary hit
+    var rng = start_sample(i.pixel, u32(max(params.frames, 0) * params.rays_per_pixel));
+    let ray = camera_ray(i, &rng);
+    let hit = calculate_ray_collions(ray);
+    var r = empty_reservoir(hit);
+    if(!restir_surface(hit) || light_nodes[0].power <= 0.0){
+        reservoirs[pixel_index(i.pixel)] = r;
+        return;
+    }
+    let wo = -normalize(ray.dir);
+    let n = faceForward(hit.normal, -wo, hit.normal);
+
+    for (var c = 0; c < params.restir_candidates; c += 1){
+        let ls = sample_light(hit.hit_point, n, &rng);
+        let u = sample_1d(&rng);
+        var w = 0.0;
+        var target_pdf = 0.0;
+        if(ls.pdf > 0.0){
+            let cos_light = dot(light_normal(ls.light, ls.point), -ls.dir);
+            if(cos_light > 0.0){
+                target_pdf = luminance(restir_contribution(hit, wo, ls.light, ls.point));
+                w = target_pdf / (ls.pdf * cos_light / (ls.dst * ls.dst));
+            }
+        }
+        update_reservoir(&r, ls.light, ls.point, w, target_pdf, 1.0, u);
+    }
+    finalize_reservoir(&r);
+    if(r.weight > 0.0){
+        let d = r.light_point - hit.hit_point;
+        let dst = length(d);
+        if(!visible(offset_ray_origin(hit.hit_point, hit.normal, d / dst), d / dst, dst)){
+            r.weight = 0.0;
+        }
+    }
+
+    if(params.restir_temporal != 0){
+        let prev = reproject(hit.hit_point);
+        if(in_bounds(prev)){
+            var history = reservoir_history[pixel_index(vec2<u32>(prev))];
+            if(similar_surface(history, hit)){
+                //Capping the history keeps stale samples from dominating after lighting changes
+                history.m = min(history.m, f32(params.restir_max_history) * r.m);
+                var merged = empty_reservoir(hit);
+                combine_reservoir(&merged, r, r.target_pdf, sample_1d(&rng));
+                let target_pdf = luminance(restir_contribution(hit, wo, history.light, history.light_point));
+                combine_reservoir(&merged, history, target_pdf, sample_1d(&rng));
+                finalize_reservoir(&merged);
+                r = merged;
+            }
+        }
+    }
+    reservoirs[pixel_index(i.pixel)] = r;
+}
+
+@compute
+@workgroup_size(8,8)
+fn restir_spatial(@builtin(global_invocation_id) global_id: vec3<u32>){
+    if(global_id.x >= params.width || global_id.y >= params.height){
+        return;
+    }
+    let i = frag_input(global_id);
+    var rng = start_sample(i.pixel, u32(max(params.frames, 0) * params.rays_per_pixel));
+    let ray = camera_ray(i, &rng);
+    let hit = calculate_ray_collions(ray);
+    let center = reservoirs[pixel_index(i.pixel)];
+    if(!restir_surface(hit)){
+        reservoir_history[pixel_index(i.pixel)] = center;
+        return;
+    }
+    let wo = -normalize(ray.dir);
+
+    var r = empty_reservoir(hit);
+    combine_reservoir(&r, center, center.target_pdf, sample_1d(&rng));
+    for (var k = 0; k < params.restir_spatial_samples; k += 1){
+        let offset = vec2<i32>((sample_2d(&rng) * 2.0 - 1.0) * f32(params.restir_spatial_radius));
+        let neighbour = vec2<i32>(i.pixel) + offset;
+        let u = sample_1d(&rng);
+        if(!in_bounds(neighbour) || all(offset == vec2<i32>(0))){
+            continue;
+        }
+        let other = reservoirs[pixel_index(vec2<u32>(neighbour))];
+        if(!similar_surface(other, hit)){
+            continue;
+        }
+        var target_pdf = luminance(restir_contribution(hit, wo, other.light, other.light_point));
+        //A neighbour's light can be hidden from this pixel, reusing it anyway darkens contact shadows
+        if(target_pdf > 0.0){
+            let d = other.light_point - hit.hit_point;
+            let dst = length(d);
+            if(!visible(offset_ray_origin(hit.hit_point, hit.normal, d / dst), d / dst, dst)){
+                target_pdf = 0.0;
+            }
+        }
+        combine_reservoir(&r, other, target_pdf, u);
+    }
+    finalize_reservoir(&r);
+    reservoir_history[pixel_index(i.pixel)] = r;
+}
+
+fn restir_shade(pixel: vec2<u32>, ray: Ray, rng: ptr<function, Sampler>) -> vec4<f32>{
+    let hit = calculate_ray_collions(ray);
+    //Misses and specular surfaces have no reservoir, they are path traced instead
+    if(!restir_surface(hit)){
+        return trace(ray, rng);
+    }
+    let wo = -normalize(ray.dir);
+    var incoming_light = to_spectrum(hit.material.emission_color) * hit.material.emission_strength;
+    let r = reservoir_history[pixel_index(pixel)];
+    if(r.weight > 0.0 && r.light < arrayLength(&lights)){
+        let d = r.light_point - hit.hit_point;
+        let dst = length(d);
+        if(visible(offset_ray_origin(hit.hit_point, hit.normal, d / dst), d / dst, dst)){
+            incoming_light += restir_contribution(hit, wo, r.light, r.light_point) * r.weight;
+        }
+    }
+    //The reservoir only covers direct light, the indirect light is path traced from the first bounce
+    let bsdf = sample_bsdf(hit.material, hit.normal, wo, rng);
+    if(bsdf.pdf > 0.0){
+        var bounce: Ray;
+        bounce.origin = offset_ray_origin(hit.hit_point, hit.normal, bsdf.dir);
+        bounce.dir = bsdf.dir;
+        incoming_light += trace_path(bounce, bsdf.value * abs(dot(bsdf.dir, hit.normal)) / bsdf.pdf, 1, rng);
+    }
+    return incoming_light;
+}
+
+//Emission plus one light sample at the first non specular surface, specular bounces are followed
+fn direct_only(ray: Ray, rng: ptr<function, Sampler>) -> vec4<f32>{
+    var ray: Ray = ray;
+    var ray_color = vec4<f32>(1.0);
+    var incoming_light = vec4<f32>(0.0);
+    for (var depth = 0; within_depth_limit(depth); depth +=1){
+        let hit = calculate_ray_collions(ray);
+        if(!hit.hit){
+            if(params.toggle != 0){
+                incoming_light += get_environment_light(ray) * ray_color;
+            }
+            break;
+        }
+        incoming_light += to_spectrum(hit.material.emission_color) * hit.material.emission_strength * ray_color;
+        let wo = -normalize(ray.dir);
+        if(!is_glass(hit.material) && !is_mirror(hit.material)){
+            if(light_nodes[0].power > 0.0){
+                incoming_light += ray_color * sample_direct_light(hit, wo, false, rng);
+            }
+            break;
+        }
+        let bsdf = sample_bsdf(hit.material, hit.normal, wo, rng);
+        if(bsdf.pdf <= 0.0){
+            break;
+        }
+        ray_color *= bsdf.value * abs(dot(bsdf.dir, hit.normal)) / bsdf.pdf;
+        ray.origin = offset_ray_origin(hit.hit_point, hit.normal, bsdf.dir);
+        ray.dir = bsdf.dir;
+    }
+    return incoming_light;
+}
+
+//Fraction of cosine weighted directions that escape within ao_distance of the primary hit
+fn occlusion(ray: Ray, rng: ptr<function, Sampler>) -> vec4<f32>{
+    let hit = calculate_ray_collions(ray);
+    if(!hit.hit){
+        return vec4<f32>(1.0);
+    }
+    let n = faceForward(hit.normal, ray.dir, hit.normal);
+    let dir = sample_cosine_hemisphere(n, rng);
+    let escaped = visible(offset_ray_origin(hit.hit_point, n, dir), dir, params.ao_distance);
+    return vec4<f32>(vec3<f32>(select(0.0, 1.0, escaped)), 1.0);
+}
+
+const WHITTED_STACK: i32 = 8;
+
+struct WhittedRay{
+    ray: Ray,
+    weight: vec4<f32>,
+    depth: i32,
+}
+
+//Lights act as points at their centre, sphere lights are weighted by the solid angle they cover
+fn whitted_lights(hit: Hit, wo: vec3<f32>) -> vec4<f32>{
+    var incoming_light = vec4<f32>(0.0);
+    let n = faceForward(hit.normal, -wo, hit.normal);
+    for (var l = 0u; l < arrayLength(&lights) && light_nodes[0].power > 0.0; l += 1u){
+        let light = lights[l];
+        var point: vec3<f32>;
+        var solid_angle = 0.0;
+        if(light.kind == LIGHT_SPHERE){
+            let sphere = sphere_at(light.index);
+            point = sphere.position;
+            solid_angle = sphere_cone_solid_angle(sphere, hit.hit_point);
+        }else{
+            let t = mesh_triangle(light.index, light.triangle);
+            point = (t.a.pos + t.b.pos + t.c.pos) / 3.0;
+            let d = point - hit.hit_point;
+            let cos_light = dot(light_normal(l, point), -normalize(d));
+            solid_angle = max(cos_light, 0.0) * light_area(l) / dot(d, d);
+        }
+        let d = point - hit.hit_point;
+        let dst = length(d);
+        let dir = d / dst;
+        let cos_theta = dot(n, dir);
+        if(solid_angle <= 0.0 || cos_theta <= 0.0){
+            continue;
+        }
+        //Sphere lights would block their own centre, stop at their surface instead
+        var shadow_dst = dst;
+        if(light.kind == LIGHT_SPHERE){
+            shadow_dst = dst - spheres[light.index].radius;
+        }
+        if(!visible(offset_ray_origin(hit.hit_point, n, dir), dir, shadow_dst)){
+            continue;
+        }
+        let material = light_material(l);
+        let emission = to_spectrum(material.emission_color) * material.emission_strength;
+        incoming_light += eval_bsdf(hit.material, hit.normal, wo, dir) * emission * cos_theta * solid_angle;
+    }
+    return incoming_light;
+}
+
+//Whitted (1980) ray tracing, mirrors and glass spawn secondary rays on a small stack instead of recursing
+fn whitted_trace(ray: Ray) -> vec4<f32>{
+    var stack: array<WhittedRay, WHITTED_STACK>;
+    stack[0].ray = ray;
+    stack[0].weight = vec4<f32>(1.0);
+    stack[0].depth = 0;
+    var top = 1;
+    var incoming_light = vec4<f32>(0.0);
+    while(top > 0){
+        top -= 1;
+        let current = stack[top];
+        let hit = calculate_ray_collions(current.ray);
+        if(!hit.hit){
+            if(params.toggle != 0){
+                incoming_light += get_environment_light(current.ray) * current.weight;
+            }
+            continue;
+        }
+        incoming_light += to_spectrum(hit.material.emission_color) * hit.material.emission_strength * current.weight;
+        let wo = -normalize(current.ray.dir);
+        if(!is_glass(hit.material) && !is_mirror(hit.material)){
+            incoming_light += whitted_lights(hit, wo) * current.weight;
+            continue;
+        }
+        if(!within_depth_limit(current.depth + 1) || top + 2 > WHITTED_STACK){
+            continue;
+        }
+        let front_face = dot(wo, hit.normal) > 0.0;
+        let n = select(-hit.normal, hit.normal, front_face);
+        var reflected = 1.0;
+        var next: WhittedRay;
+        next.depth = current.depth + 1;
+        if(is_glass(hit.material)){
+            let ior = material_ior(hit.material);
+            let refraction_ratio = select(ior, 1.0 / ior, front_face);
+            let cos_theta = min(dot(wo, n), 1.0);
+            let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+            if(refraction_ratio * sin_theta <= 1.0){
+                reflected = reflectance(cos_theta, refraction_ratio);
+                next.ray.dir = refract(-wo, n, refraction_ratio);
+                next.ray.origin = offset_ray_origin(hit.hit_point, n, next.ray.dir);
+                next.weight = current.weight * (1.0 - reflected);
+                stack[top] = next;
+                top += 1;
+            }
+            next.weight = current.weight * reflected;
+        }else{
+            next.weight = current.weight * to_spectrum(hit.material.color);
+        }
+        next.ray.dir = reflect(-wo, n);
+        next.ray.origin = offset_ray_origin(hit.hit_point, n, next.ray.dir);
+        stack[top] = next;
+        top += 1;
+    }
+    return incoming_light;
+}
+
+//Same order as DEBUG_MODE_NAMES in integrator.rs
+const DEBUG_SHADING_NORMAL: i32 = 0;
+const DEBUG_GEOMETRIC_NORMAL: i32 = 1;
+const DEBUG_DEPTH: i32 = 2;
+const DEBUG_BARYCENTRIC: i32 = 3;
+const DEBUG_OBJECT_ID: i32 = 4;
+const DEBUG_MATERIAL_ID: i32 = 5;
+const DEBUG_PATH_LENGTH: i32 = 6;
+const DEBUG_INTERSECTIONS: i32 = 7;
+
+//Bounces of the last path traced
+var<private> path_length: i32;
+
+fn id_color(id: u32) -> vec3<f32>{
+    let h = hash(id + 1u);
+    return vec3<f32>(vec3<u32>(h, h >> 8u, h >> 16u) & vec3<u32>(255u)) / 255.0;
+}
+
+fn material_id(material: Material) -> u32{
+    var h = hash(bitcast<u32>(material.smoothness));
+    h = hash_combine(h, bitcast<u32>(material.emission_strength));
+    h = hash_combine(h, hash(bitcast<u32>(material.color.r)));
+    h = hash_combine(h, hash(bitcast<u32>(material.color.g)));
+    h = hash_combine(h, hash(bitcast<u32>(material.color.b)));
+    return h;
+}
+
+//Blue to green to red as t goes from 0 to 1, white past 1
+fn heatmap(t: f32) -> vec3<f32>{
+    if(t > 1.0){
+        return vec3<f32>(1.0);
+    }
+    let t = clamp(t, 0.0, 1.0);
+    return clamp(vec3<f32>(2.0 * t - 1.0, 1.0 - abs(2.0 * t - 1.0), 1.0 - 2.0 * t), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+fn debug_shade(ray: Ray, rng: ptr<function, Sampler>) -> vec4<f32>{
+    //Every ray the path tracer casts for the pixel counts, shadow rays included
+    if(params.debug_mode == DEBUG_INTERSECTIONS){
+        intersection_tests = 0u;
+        trace(ray, rng);
+        return vec4<f32>(heatmap(f32(intersection_tests) / params.debug_scale), 1.0);
+    }
+    if(params.debug_mode == DEBUG_PATH_LENGTH){
+        trace(ray, rng);
+        return vec4<f32>(heatmap(f32(path_length) / params.debug_scale), 1.0);
+    }
+    let hit = calculate_ray_collions(ray);
+    if(!hit.hit){
+        return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    }
+    let mode = params.debug_mode;
+    var color = vec3<f32>(1.0, 0.0, 1.0);
+    if(mode == DEBUG_SHADING_NORMAL){
+        color = hit.normal * 0.5 + 0.5;
+    }else if(mode == DEBUG_GEOMETRIC_NORMAL){
+        color = hit.geometric_normal * 0.5 + 0.5;
+    }else if(mode == DEBUG_DEPTH){
+        color = vec3<f32>(1.0 - exp(-distance(camera.origin, hit.hit_point) / params.debug_scale));
+    }else if(mode == DEBUG_BARYCENTRIC){
+        color = hit.barycentric;
+    }else if(mode == DEBUG_OBJECT_ID){
+        color = id_color(hit.object);
+    }else if(mode == DEBUG_MATERIAL_ID){
+        color = id_color(material_id(hit.material));
+    }
+    return vec4<f32>(color, 1.0);
+}
@@ -30,11 +30,99 @@ var<uniform> params: Params;
 @group(0) @binding(1)
 var texture: texture_storage_2d<rgba32float,read_write>;
 
+struct Exposure{
+    mode: i32,
+    exposure: f32,
+    compensation: f32,
+    adaptation: f32,
+    min_log_luminance: f32,
+    log_luminance_range: f32,
+    low_percentile: f32,
+    high_percentile: f32,
+    tone_map: i32,
+    white_point: f32,
+};
+
+const EXPOSURE_AUTO: i32 = 2;
+
+@group(0) @binding(2)
+var<uniform> exposure: Exposure;
+@group(0) @binding(3)
+var<storage,read> luminance: array<f32>;
+
+//Auto exposure maps the average luminance to middle grey
+fn exposure_scale() -> f32{
+    if(exposure.mode == EXPOSURE_AUTO && luminance[0] > 0.0){
+        return 0.18 / luminance[0] * exp2(exposure.compensation);
+    }
+    return exposure.exposure;
+}
+
+//Same order and curves as tonemap.rs
+const TONEMAP_REINHARD: i32 = 1;
+const TONEMAP_ACES: i32 = 2;
+const TONEMAP_AGX: i32 = 3;
+const TONEMAP_UNCHARTED2: i32 = 4;
+
+//Narkowicz 2015 fit of the ACES reference rendering transform
+fn aces(x: vec3<f32>) -> vec3<f32>{
+    return (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14);
+}
+
+//Hable 2010
+fn uncharted2(x: vec3<f32>) -> vec3<f32>{
+    let a = 0.15;
+    let b = 0.50;
+    let c = 0.10;
+    let d = 0.20;
+    let e = 0.02;
+    let f = 0.30;
+    return ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f;
+}
+
+//Sobotka's AgX with the polynomial contrast fit from Wrensch 2023
+fn agx(color: vec3<f32>) -> vec3<f32>{
+    let min_ev = -12.47393;
+    let max_ev = 4.026069;
+    let inset = mat3x3<f32>(
+        0.84247906, 0.042328242, 0.042375655,
+        0.0784336, 0.87846864, 0.0784336,
+        0.079223745, 0.07916613, 0.879143,
+    );
+    let outset = mat3x3<f32>(
+        1.196879, -0.052896852, -0.052971636,
+        -0.09802088, 1.1519031, -0.09804345,
+        -0.09902974, -0.098961177, 1.1510737,
+    );
+    let v = inset * max(color, vec3<f32>(1e-10));
+    let x = (clamp(log2(v), vec3<f32>(min_ev), vec3<f32>(max_ev)) - min_ev) / (max_ev - min_ev);
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    let curve = 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232;
+    //The curve targets a 2.2 gamma display, the surface encodes to srgb itself
+    return pow(max(outset * curve, vec3<f32>(0.0)), vec3<f32>(2.2));
+}
+
+fn tone_map(color: vec3<f32>) -> vec3<f32>{
+    let white = max(exposure.white_point, 1e-3);
+    var mapped = color;
+    if(exposure.tone_map == TONEMAP_REINHARD){
+        mapped = color * (1.0 + color / (white * white)) / (1.0 + color);
+    }else if(exposure.tone_map == TONEMAP_ACES){
+        mapped = aces(color) / aces(vec3<f32>(white));
+    }else if(exposure.tone_map == TONEMAP_AGX){
+        mapped = agx(color);
+    }else if(exposure.tone_map == TONEMAP_UNCHARTED2){
+        mapped = uncharted2(color * 2.0) / uncharted2(vec3<f32>(2.0 * white));
+    }
+    return clamp(mapped, vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
 @fragment
 fn frag(i: VertexOutput) -> @location(0) vec4<f32>{
     var color = textureLoad(texture, vec2<i32>(
         i32(i.tex_coord.x * f32(params.width)),
         i32(i.tex_coord.y * f32(params.height))
     ));
-    return color;
+    return vec4<f32>(tone_map(color.rgb * exposure_scale()), color.a);
 }
\ No newline at end of file
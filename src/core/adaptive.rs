@@ -0,0 +1,122 @@
+use std::{mem, sync::{Arc, atomic::{AtomicBool, Ordering}}};
+
+//Where the converged pixel count is on its way back from the gpu
+#[derive(Clone, Copy, PartialEq)]
+enum Readback{
+    Idle,
+    //Copied into the staging buffer by a submission that hasn't been mapped yet
+    Copied,
+    Mapping,
+}
+
+//Adaptive sampling, pixels keep the running mean and mean square of their luminance and stop tracing
+//once the error of their mean is under the threshold. The number of converged pixels is read back
+//without stalling, so the progress shown lags a frame or two behind
+pub struct Adaptive{
+    //Mean luminance, mean squared luminance, frames and error per pixel
+    pub moments_buffer: wgpu::Buffer,
+    //Converged pixels of the current frame, cleared before every dispatch
+    pub counter_buffer: wgpu::Buffer,
+    staging_buffer: wgpu::Buffer,
+    readback: Readback,
+    mapped: Arc<AtomicBool>,
+    pub enabled: bool,
+    pub threshold: f32,
+    pub min_frames: i32,
+    pub converged: u32,
+    pixels: u32,
+}
+
+impl Adaptive{
+    pub fn new(device: &wgpu::Device, width: u32, height: u32) -> Self{
+        let size = mem::size_of::<u32>() as u64;
+        let counter_buffer = device.create_buffer(&wgpu::BufferDescriptor{
+            label: Some("Converged Pixels Buffer"),
+            size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let staging_buffer = device.create_buffer(&wgpu::BufferDescriptor{
+            label: Some("Converged Pixels Readback Buffer"),
+            size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        Self{
+            moments_buffer: Self::moments_buffer(device, width, height),
+            counter_buffer,
+            staging_buffer,
+            readback: Readback::Idle,
+            mapped: Arc::new(AtomicBool::new(false)),
+            enabled: false,
+            threshold: 0.01,
+            min_frames: 16,
+            converged: 0,
+            pixels: width * height,
+        }
+    }
+
+    fn moments_buffer(device: &wgpu::Device, width: u32, height: u32) -> wgpu::Buffer{
+        device.create_buffer(&wgpu::BufferDescriptor{
+            label: Some("Moments Buffer"),
+            size: (width * height) as u64 * mem::size_of::<[f32;4]>() as u64,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        })
+    }
+
+    //The ray tracer's bind group has to be rebuilt after this
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32){
+        self.moments_buffer = Self::moments_buffer(device, width, height);
+        self.pixels = width * height;
+        self.converged = 0;
+    }
+
+    pub fn clear(&self, encoder: &mut wgpu::CommandEncoder){
+        if self.enabled{
+            encoder.clear_buffer(&self.counter_buffer, 0, None);
+        }
+    }
+
+    //Queues a copy of this frame's count, skipped while the previous one is still on its way
+    pub fn readback(&mut self, encoder: &mut wgpu::CommandEncoder){
+        if self.enabled && self.readback == Readback::Idle{
+            encoder.copy_buffer_to_buffer(&self.counter_buffer, 0, &self.staging_buffer, 0, mem::size_of::<u32>() as u64);
+            self.readback = Readback::Copied;
+        }
+    }
+
+    //Has to be called after the encoder with the copy was submitted
+    pub fn map(&mut self){
+        if self.readback == Readback::Copied{
+            let mapped = self.mapped.clone();
+            self.staging_buffer.slice(..).map_async(wgpu::MapMode::Read, move |result|{
+                mapped.store(result.is_ok(), Ordering::Release);
+            });
+            self.readback = Readback::Mapping;
+        }
+    }
+
+    //Picks up the count once the gpu has finished with it, returns whether it changed
+    pub fn poll(&mut self, device: &wgpu::Device) -> bool{
+        if self.readback != Readback::Mapping{
+            return false;
+        }
+        device.poll(wgpu::Maintain::Poll);
+        if !self.mapped.swap(false, Ordering::Acquire){
+            return false;
+        }
+        self.converged = bytemuck::cast_slice::<u8, u32>(&self.staging_buffer.slice(..).get_mapped_range())[0];
+        self.staging_buffer.unmap();
+        self.readback = Readback::Idle;
+        true
+    }
+
+    pub fn progress(&self) -> f32{
+        self.converged as f32 / self.pixels.max(1) as f32
+    }
+
+    pub fn done(&self) -> bool{
+        self.enabled && self.converged >= self.pixels
+    }
+}
@@ -0,0 +1,223 @@
+use glam::Vec3;
+
+use super::camera::Camera;
+
+//Playback position shared by everything that is animated, in seconds
+pub struct Timeline{
+    pub time: f32,
+    pub start: f32,
+    pub end: f32,
+    pub fps: f32,
+    pub playing: bool,
+    //Time the scene was last posed for, None when it has to be posed again
+    pub evaluated: Option<f32>,
+}
+
+impl Default for Timeline{
+    fn default() -> Self{
+        Self{
+            time: 0.0,
+            start: 0.0,
+            end: 5.0,
+            fps: 24.0,
+            playing: false,
+            evaluated: None,
+        }
+    }
+}
+
+impl Timeline{
+    //Wraps back to the start once playback passes the end
+    pub fn advance(&mut self, dt: f32){
+        if !self.playing{
+            return;
+        }
+        self.time += dt;
+        if self.time > self.end{
+            self.time = self.start;
+        }
+    }
+
+    pub fn frame_time(&self, frame: i32) -> f32{
+        self.start + frame as f32 / self.fps
+    }
+
+    pub fn frame_count(&self) -> i32{
+        ((self.end - self.start) * self.fps).floor() as i32 + 1
+    }
+}
+
+//Anything that can be placed on a Track and splined between its neighbours
+pub trait Keyframe: Copy{
+    fn time(&self) -> f32;
+    fn at(self, time: f32) -> Self;
+    fn spline(k0: &Self, k1: &Self, k2: &Self, k3: &Self, t: f32, time: f32) -> Self;
+}
+
+//Keyframes sorted by time, played back along a Catmull-Rom spline through them
+pub struct Track<K: Keyframe>{
+    pub keyframes: Vec<K>,
+}
+
+impl<K: Keyframe> Default for Track<K>{
+    fn default() -> Self{
+        Self{keyframes: vec![]}
+    }
+}
+
+impl<K: Keyframe> Track<K>{
+    //Replaces any keyframe already at that time
+    pub fn insert(&mut self, keyframe: K){
+        self.keyframes.retain(|k| (k.time() - keyframe.time()).abs() > 1e-4);
+        let index = self.keyframes.partition_point(|k| k.time() < keyframe.time());
+        self.keyframes.insert(index, keyframe);
+    }
+
+    pub fn evaluate(&self, time: f32) -> Option<K>{
+        let last = self.keyframes.len().checked_sub(1)?;
+        let i = self.keyframes.partition_point(|k| k.time() <= time);
+        if i == 0{
+            return Some(self.keyframes[0].at(time));
+        }
+        if i > last{
+            return Some(self.keyframes[last].at(time));
+        }
+        let (k1, k2) = (&self.keyframes[i - 1], &self.keyframes[i]);
+        //The ends are repeated so the spline stops at the first and last keyframe
+        let k0 = &self.keyframes[i.saturating_sub(2)];
+        let k3 = &self.keyframes[(i + 1).min(last)];
+        let t = (time - k1.time()) / (k2.time() - k1.time());
+        Some(K::spline(k0, k1, k2, k3, t, time))
+    }
+}
+
+fn spline_scalar(k: [f32; 4], t: f32) -> f32{
+    catmull_rom(Vec3::splat(k[0]), Vec3::splat(k[1]), Vec3::splat(k[2]), Vec3::splat(k[3]), t).x
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct CameraKeyframe{
+    pub time: f32,
+    pub origin: Vec3,
+    pub look_at: Vec3,
+    pub fov: f32,
+    pub focus_dist: f32,
+    pub f_stop: f32,
+}
+
+impl CameraKeyframe{
+    pub fn from_camera(camera: &Camera, time: f32) -> Self{
+        Self{
+            time,
+            origin: camera.origin,
+            look_at: camera.look_at,
+            fov: camera.fov,
+            focus_dist: camera.focus_dist,
+            f_stop: camera.f_stop,
+        }
+    }
+
+    pub fn apply(&self, camera: &mut Camera){
+        camera.origin = self.origin;
+        camera.look_at = self.look_at;
+        camera.fov = self.fov;
+        camera.focus_dist = self.focus_dist;
+        camera.f_stop = self.f_stop;
+        camera.focus_target = None;
+    }
+}
+
+impl Keyframe for CameraKeyframe{
+    fn time(&self) -> f32{
+        self.time
+    }
+
+    fn at(self, time: f32) -> Self{
+        Self{time, ..self}
+    }
+
+    fn spline(k0: &Self, k1: &Self, k2: &Self, k3: &Self, t: f32, time: f32) -> Self{
+        let scalar = |f: fn(&Self) -> f32| spline_scalar([f(k0), f(k1), f(k2), f(k3)], t);
+        Self{
+            time,
+            origin: catmull_rom(k0.origin, k1.origin, k2.origin, k3.origin, t),
+            look_at: catmull_rom(k0.look_at, k1.look_at, k2.look_at, k3.look_at, t),
+            fov: scalar(|k| k.fov).clamp(1.0, 179.0),
+            focus_dist: scalar(|k| k.focus_dist).max(0.0),
+            f_stop: scalar(|k| k.f_stop).max(0.1),
+        }
+    }
+}
+
+pub type CameraPath = Track<CameraKeyframe>;
+
+//Animatable state of a sphere or mesh, radius is ignored for meshes
+#[derive(Debug, Copy, Clone)]
+pub struct ObjectKeyframe{
+    pub time: f32,
+    pub position: Vec3,
+    pub radius: f32,
+    pub emission_color: Vec3,
+    pub emission_strength: f32,
+    pub smoothness: f32,
+}
+
+impl Keyframe for ObjectKeyframe{
+    fn time(&self) -> f32{
+        self.time
+    }
+
+    fn at(self, time: f32) -> Self{
+        Self{time, ..self}
+    }
+
+    fn spline(k0: &Self, k1: &Self, k2: &Self, k3: &Self, t: f32, time: f32) -> Self{
+        let scalar = |f: fn(&Self) -> f32| spline_scalar([f(k0), f(k1), f(k2), f(k3)], t);
+        Self{
+            time,
+            position: catmull_rom(k0.position, k1.position, k2.position, k3.position, t),
+            radius: scalar(|k| k.radius).max(0.0),
+            emission_color: catmull_rom(k0.emission_color, k1.emission_color, k2.emission_color, k3.emission_color, t).max(Vec3::ZERO),
+            emission_strength: scalar(|k| k.emission_strength).max(0.0),
+            //Glass is -1, so only the sign of the neighbours is kept
+            smoothness: if k1.smoothness < 0.0 {k1.smoothness} else {scalar(|k| k.smoothness).clamp(0.0, 1.0)},
+        }
+    }
+}
+
+//Object indices are the same as Scene::raycast, spheres first then meshes
+pub struct ObjectTrack{
+    pub object: usize,
+    pub track: Track<ObjectKeyframe>,
+}
+
+pub fn catmull_rom(p0: Vec3, p1: Vec3, p2: Vec3, p3: Vec3, t: f32) -> Vec3{
+    let t2 = t * t;
+    let t3 = t2 * t;
+    0.5 * (2.0 * p1 + (p2 - p0) * t + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2 + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+}
+
+//Renders every timeline frame to a sample count and writes them out as numbered images
+pub struct Sequence{
+    pub directory: String,
+    pub samples: i32,
+    pub frame: i32,
+    pub active: bool,
+}
+
+impl Default for Sequence{
+    fn default() -> Self{
+        Self{
+            directory: String::from("frames"),
+            samples: 256,
+            frame: 0,
+            active: false,
+        }
+    }
+}
+
+impl Sequence{
+    pub fn path(&self) -> std::path::PathBuf{
+        std::path::Path::new(&self.directory).join(format!("frame_{:04}.png", self.frame))
+    }
+}
@@ -0,0 +1,170 @@
+use super::texture::Texture;
+
+//Same order as the AOV_ constants in ray_tracer.wgsl, bit i of the mask enables AOV i
+pub const AOV_COUNT: usize = 11;
+pub const AOV_NAMES: [&str; AOV_COUNT] = [
+    "Albedo",
+    "Normal",
+    "Depth",
+    "Position",
+    "Object id",
+    "Material id",
+    "Direct diffuse",
+    "Direct specular",
+    "Indirect diffuse",
+    "Indirect specular",
+    "Emission",
+];
+//Direct diffuse to emission, split up along the path by integrators that support it
+pub const AOV_LIGHTING: u32 = 0b11111 << 6;
+//Layer names in exported exr files
+pub const AOV_LAYER_NAMES: [&str; AOV_COUNT] = [
+    "albedo",
+    "normal",
+    "depth",
+    "position",
+    "object_id",
+    "material_id",
+    "direct_diffuse",
+    "direct_specular",
+    "indirect_diffuse",
+    "indirect_specular",
+    "emission",
+];
+
+//Arbitrary output variables for compositing, written by the ray tracer next to the image. Only enabled
+//AOVs get a layer of the array texture, with none enabled it shrinks to a single texel
+pub struct Aovs{
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub mask: u32,
+    //AOV shown instead of the image
+    pub shown: Option<usize>,
+    //The layer being viewed or read back, copied out of the array
+    pub display: Texture,
+    width: u32,
+    height: u32,
+}
+
+impl Aovs{
+    pub fn new(device: &wgpu::Device, width: u32, height: u32) -> Self{
+        let (texture, view) = Self::create_texture(device, width, height, 0);
+        Self{
+            texture,
+            view,
+            mask: 0,
+            shown: None,
+            display: Texture::new(device, width, height, wgpu::TextureFormat::Rgba32Float),
+            width,
+            height,
+        }
+    }
+
+    fn create_texture(device: &wgpu::Device, width: u32, height: u32, mask: u32) -> (wgpu::Texture, wgpu::TextureView){
+        let (width, height) = if mask == 0 {(1, 1)} else {(width, height)};
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("AOV Texture"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: mask.count_ones().max(1),
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        //A single layer would default to a plain 2d view
+        let view = texture.create_view(&wgpu::TextureViewDescriptor{
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        (texture, view)
+    }
+
+    //The ray tracer's bind group has to be rebuilt after this and set_mask
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32){
+        (self.width, self.height) = (width, height);
+        (self.texture, self.view) = Self::create_texture(device, width, height, self.mask);
+        self.display = Texture::new(device, width, height, wgpu::TextureFormat::Rgba32Float);
+    }
+
+    //Returns whether the layers had to be reallocated
+    pub fn set_mask(&mut self, device: &wgpu::Device, mask: u32) -> bool{
+        if mask == self.mask{
+            return false;
+        }
+        self.mask = mask;
+        (self.texture, self.view) = Self::create_texture(device, self.width, self.height, mask);
+        self.shown = self.shown.filter(|aov| self.enabled(*aov));
+        true
+    }
+
+    pub fn enabled(&self, aov: usize) -> bool{
+        self.mask & (1 << aov) != 0
+    }
+
+    fn layer(&self, aov: usize) -> u32{
+        (self.mask & ((1 << aov) - 1)).count_ones()
+    }
+
+    pub fn binding_type(&self) -> wgpu::BindingType{
+        wgpu::BindingType::StorageTexture {
+            access: wgpu::StorageTextureAccess::ReadWrite,
+            format: wgpu::TextureFormat::Rgba32Float,
+            view_dimension: wgpu::TextureViewDimension::D2Array,
+        }
+    }
+
+    pub fn binding_resource(&self) -> wgpu::BindingResource<'_>{
+        wgpu::BindingResource::TextureView(&self.view)
+    }
+
+    fn copy_layer(&self, encoder: &mut wgpu::CommandEncoder, aov: usize){
+        encoder.copy_texture_to_texture(
+            wgpu::ImageCopyTexture{
+                texture: &self.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d{x: 0, y: 0, z: self.layer(aov)},
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyTexture{
+                texture: &self.display.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::Extent3d{
+                width: self.width,
+                height: self.height,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    //What the display pass and exports should read, image unless an AOV is shown
+    pub fn image<'a>(&'a self, image: &'a Texture) -> &'a Texture{
+        if self.shown.is_some() {&self.display} else {image}
+    }
+
+    //Refreshes display with the shown AOV, after the ray tracer has written this frame
+    pub fn dispatch(&self, encoder: &mut wgpu::CommandEncoder){
+        if let Some(aov) = self.shown.filter(|aov| self.enabled(*aov)){
+            self.copy_layer(encoder, aov);
+        }
+    }
+
+    //Every enabled AOV with its exr layer name, rows top to bottom as seen on screen
+    pub fn read(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<(&'static str, Vec<f32>)>{
+        (0..AOV_COUNT).filter(|aov| self.enabled(*aov)).map(|aov|{
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor{
+                label: Some("AOV Readback Encoder")
+            });
+            self.copy_layer(&mut encoder, aov);
+            queue.submit(std::iter::once(encoder.finish()));
+            (AOV_LAYER_NAMES[aov], self.display.read(device, queue))
+        }).collect()
+    }
+}
@@ -0,0 +1,38 @@
+use std::mem;
+
+//Has to match BDPT_VERTICES in ray_tracer.wgsl, camera and light subpaths stop at this many vertices
+//whatever the bounce limit or russian roulette would allow
+pub const BDPT_VERTICES: i32 = 8;
+
+//Light subpaths connected straight to the camera land in other pixels, so they're summed here with
+//atomics in fixed point during the frame and added to the image by a pass after it
+pub struct Bdpt{
+    //Red, green and blue of every pixel
+    pub splat_buffer: wgpu::Buffer,
+}
+
+impl Bdpt{
+    pub fn new(device: &wgpu::Device, width: u32, height: u32) -> Self{
+        Self{
+            splat_buffer: Self::splat_buffer(device, width, height),
+        }
+    }
+
+    fn splat_buffer(device: &wgpu::Device, width: u32, height: u32) -> wgpu::Buffer{
+        device.create_buffer(&wgpu::BufferDescriptor{
+            label: Some("Splat Buffer"),
+            size: (width * height * 3) as u64 * mem::size_of::<u32>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    //The ray tracer's bind group has to be rebuilt after this
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32){
+        self.splat_buffer = Self::splat_buffer(device, width, height);
+    }
+
+    pub fn clear(&self, encoder: &mut wgpu::CommandEncoder){
+        encoder.clear_buffer(&self.splat_buffer, 0, None);
+    }
+}
@@ -1,10 +1,20 @@
 use std::time::Duration;
 
-use glam::{Vec3};
+use glam::{Vec2, Vec3, Quat};
 use imgui_winit_support::winit::{event::{VirtualKeyCode, ElementState, MouseScrollDelta}, dpi::PhysicalPosition};
 use wgpu::util::DeviceExt;
 const SAFE_FRAC_PI_2: f32 = std::f32::consts::FRAC_PI_2 - 0.0001;
-const SAFE_FRAC_PI_2_DEG: f32 = SAFE_FRAC_PI_2 * (180.0/std::f32::consts::PI);
+//Has to match BOKEH_SIZE in ray_tracer.wgsl
+pub const BOKEH_SIZE: u32 = 64;
+
+pub const PROJECTION_PERSPECTIVE: i32 = 0;
+pub const PROJECTION_ORTHOGRAPHIC: i32 = 1;
+pub const PROJECTION_FISHEYE: i32 = 2;
+pub const PROJECTION_EQUIRECTANGULAR: i32 = 3;
+pub const PROJECTION_CUBE_FACE: i32 = 4;
+pub const PROJECTION_NAMES: [&str; 5] = ["Perspective", "Orthographic", "Fisheye", "Equirectangular", "Cube map face"];
+//World axis each cube face looks down
+pub const CUBE_FACE_NAMES: [&str; 6] = ["+X", "-X", "+Y", "-Y", "+Z", "-Z"];
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
@@ -26,6 +36,15 @@ pub struct CameraUniform{
     _padding7: f32,
     pub v: [f32;3],
     pub lens_radius: f32,
+    //0 for a circular aperture
+    pub blades: u32,
+    pub blade_rotation: f32,
+    pub anamorphic: f32,
+    pub bokeh: u32,
+    pub projection: i32,
+    pub ortho_height: f32,
+    pub fisheye_fov: f32,
+    pub cube_face: i32,
 }
 
 #[derive(Debug)]
@@ -39,9 +58,30 @@ pub struct Camera{
     pub aspect: f32,
     pub near: f32,
     pub far: f32,
-    pub aperture: f32,
+    pub projection: i32,
+    //World units covered vertically by the orthographic projection
+    pub ortho_height: f32,
+    //Degrees across the fisheye image circle
+    pub fisheye_fov: f32,
+    pub cube_face: i32,
+    //Thin lens, scene units are metres and the focal length follows from fov and sensor_height
+    pub pinhole: bool,
+    pub f_stop: f32,
+    pub sensor_height: f32,
+    //Exposure settings, shutter in seconds
+    pub iso: f32,
+    pub shutter: f32,
     pub focus_dist: f32,
-    pub lens_radius: f32,
+    //Focus distance being pulled towards and roughly how many seconds it takes, 0 jumps straight there
+    pub focus_target: Option<f32>,
+    pub focus_pull: f32,
+    pub blades: i32,
+    pub blade_rotation: f32,
+    //Horizontal squeeze of the aperture, 1 is spherical
+    pub anamorphic: f32,
+    //Set once a bokeh image has been loaded into bokeh_buffer, which then replaces the blades
+    pub bokeh: bool,
+    pub bokeh_buffer: wgpu::Buffer,
     pub buffer: wgpu::Buffer,
     pub controller: CameraController,
 }
@@ -54,13 +94,20 @@ impl Camera{
             contents: bytemuck::bytes_of(&uniform),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
+        //Cumulative aperture transmittance, sampled by inverting it in the shader
+        let bokeh_buffer = device.create_buffer(&wgpu::BufferDescriptor{
+            label: Some("Bokeh buffer"),
+            size: (BOKEH_SIZE * BOKEH_SIZE) as u64 * std::mem::size_of::<f32>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
         let controller = CameraController::new(3.0,0.35);
 
 
         let pitch = 0.0;
         let yaw = 0.0;
 
-        Camera{
+        let mut camera = Camera{
             origin,
             pitch,yaw,
             look_at,
@@ -69,12 +116,118 @@ impl Camera{
             aspect,
             near,
             far,
-            aperture,
-            lens_radius: aperture /2.0,
+            projection: PROJECTION_PERSPECTIVE,
+            ortho_height: 10.0,
+            fisheye_fov: 180.0,
+            cube_face: 0,
+            pinhole: aperture <= 0.0,
+            f_stop: 0.0,
+            sensor_height: 24.0,
+            iso: 100.0,
+            shutter: 1.0 / 60.0,
             focus_dist,
+            focus_target: None,
+            focus_pull: 0.3,
+            blades: 0,
+            blade_rotation: 0.0,
+            anamorphic: 1.0,
+            bokeh: false,
+            bokeh_buffer,
             buffer,
             controller,
+        };
+        //Scenes give the aperture diameter, keep it by picking the matching f-stop
+        camera.f_stop = if camera.pinhole {8.0} else {camera.focal_length() / 1000.0 / aperture};
+        camera
+    }
+    //In millimetres
+    pub fn focal_length(&self) -> f32{
+        0.5 * self.sensor_height / f32::tan(radians(self.fov) / 2.0)
+    }
+    pub fn set_focal_length(&mut self, focal_length: f32){
+        self.fov = degrees(2.0 * f32::atan(0.5 * self.sensor_height / focal_length));
+    }
+    //Exposure value at ISO 100 for the current settings, the pinhole still exposes at f_stop
+    pub fn ev100(&self) -> f32{
+        f32::log2(self.f_stop * self.f_stop / self.shutter * 100.0 / self.iso)
+    }
+    pub fn lens_radius(&self) -> f32{
+        if self.pinhole{
+            0.0
+        }else{
+            self.focal_length() / 1000.0 / (2.0 * self.f_stop)
+        }
+    }
+    //x and y in 0..1 across the image, the same mapping as camera_ray in the shader.
+    //None outside the fisheye image circle
+    pub fn pixel_ray(&self, x: f32, y: f32) -> Option<(Vec3, Vec3)>{
+        let w = (self.origin - self.look_at).normalize();
+        let u = self.view_up.cross(w).normalize();
+        let v = w.cross(u);
+        let (px, py) = (x * 2.0 - 1.0, y * 2.0 - 1.0);
+        match self.projection{
+            PROJECTION_ORTHOGRAPHIC => {
+                let origin = self.origin + 0.5 * self.ortho_height * (px * self.aspect * u + py * v);
+                Some((origin, -w))
+            }
+            PROJECTION_FISHEYE => {
+                let r = (px * px * self.aspect * self.aspect + py * py).sqrt();
+                if r > 1.0{
+                    return None;
+                }
+                let theta = r * radians(self.fisheye_fov) / 2.0;
+                let phi = py.atan2(px * self.aspect);
+                Some((self.origin, theta.sin() * (phi.cos() * u + phi.sin() * v) - theta.cos() * w))
+            }
+            PROJECTION_EQUIRECTANGULAR => {
+                let phi = px * std::f32::consts::PI;
+                let theta = py * std::f32::consts::FRAC_PI_2;
+                Some((self.origin, theta.cos() * (phi.sin() * u - phi.cos() * w) + theta.sin() * v))
+            }
+            PROJECTION_CUBE_FACE => {
+                let dir = match self.cube_face{
+                    0 => Vec3::new(1.0, py, -px),
+                    1 => Vec3::new(-1.0, py, px),
+                    2 => Vec3::new(px, 1.0, -py),
+                    3 => Vec3::new(px, -1.0, py),
+                    4 => Vec3::new(px, py, 1.0),
+                    _ => Vec3::new(-px, py, -1.0),
+                };
+                Some((self.origin, dir.normalize()))
+            }
+            _ => {
+                let height = 2.0 * f32::tan(radians(self.fov)/2.0);
+                let width = self.aspect * height;
+                let dir = 0.5 * px * width * u + 0.5 * py * height * v - w;
+                Some((self.origin, dir.normalize()))
+            }
+        }
+    }
+    //Focus on a point, measured along the view direction since the focal plane is flat
+    pub fn focus_on(&mut self, point: Vec3){
+        let w = (self.origin - self.look_at).normalize();
+        let dist = (self.origin - point).dot(w).max(self.near);
+        if self.focus_pull > 0.0{
+            self.focus_target = Some(dist);
+        }else{
+            self.focus_dist = dist;
+        }
+    }
+    pub fn load_bokeh(&mut self, queue: &wgpu::Queue, path: &str) -> anyhow::Result<()>{
+        let image = image::open(path)?
+            .resize_exact(BOKEH_SIZE, BOKEH_SIZE, image::imageops::FilterType::Triangle)
+            .to_luma32f();
+        let mut total = 0.0;
+        let cdf: Vec<f32> = image.pixels().map(|p|{
+            total += p.0[0];
+            total
+        }).collect();
+        if total <= 0.0{
+            return Err(anyhow::anyhow!("Bokeh image is black"));
         }
+        queue.write_buffer(&self.bokeh_buffer, 0, bytemuck::cast_slice(&cdf));
+        self.bokeh = true;
+        Ok(())
     }
     pub fn to_uniform(&mut self) -> CameraUniform{
         // let theta = radians(self.fov); 
@@ -97,7 +250,6 @@ impl Camera{
         let horizontal = self.focus_dist * width * u;
         let vertical = self.focus_dist * height * v;
         let lower_left_corner = self.origin - horizontal/2.0 - vertical/2.0 - self.focus_dist * w;
-        //TODO add lens_radius = aperture / 2.0
 
         CameraUniform {
             origin: self.origin.to_array(),
@@ -116,12 +268,35 @@ impl Camera{
             u: u.to_array(),
             _padding7: 0.0,
             v: v.to_array(),
-            lens_radius: self.aperture/2.0,
+            lens_radius: self.lens_radius(),
+            blades: if self.blades >= 3 {self.blades as u32} else {0},
+            blade_rotation: radians(self.blade_rotation),
+            anamorphic: self.anamorphic,
+            bokeh: self.bokeh as u32,
+            projection: self.projection,
+            ortho_height: self.ortho_height,
+            fisheye_fov: radians(self.fisheye_fov),
+            cube_face: self.cube_face,
         }
     }
     pub fn update_camera(&mut self, dt: Duration) {
         let dt = dt.as_secs_f32();
 
+        if let Some(target) = self.focus_target{
+            self.focus_dist += (target - self.focus_dist) * (1.0 - f32::exp(-dt * 3.0 / self.focus_pull.max(1e-3)));
+            if (target - self.focus_dist).abs() < 1e-3{
+                self.focus_dist = target;
+                self.focus_target = None;
+            }
+        }
+
+        if self.controller.mode == CONTROLLER_FLY{
+            self.update_fly(dt);
+        }else{
+            self.update_orbit(dt);
+        }
+    }
+    fn update_fly(&mut self, dt: f32){
         let direction = (self.look_at - self.origin).normalize();
         let mut pitch = direction.y.asin();
         let mut yaw = direction.x.atan2(direction.z);
@@ -132,13 +307,8 @@ impl Camera{
         self.origin += forward * (self.controller.amount_forward - self.controller.amount_backward) * self.controller.speed * dt;
         self.origin += right * (self.controller.amount_right - self.controller.amount_left) * self.controller.speed * dt;
 
-        // Move in/out (aka. "zoom")
-        // Note: this isn't an actual zoom. The camera's position
-        // changes when zooming. I've added this to make it easier
-        // to get closer to an object you want to focus on.
-        let (pitch_sin, pitch_cos) = pitch.sin_cos();
-        let scrollward = Vec3::new(pitch_cos * yaw_cos, pitch_sin, pitch_cos * yaw_sin).normalize();
-        self.origin -= scrollward * self.controller.scroll * self.controller.speed * self.controller.sensitivity * dt;
+        // Move in/out along the view direction, a fixed step per scroll line
+        self.origin += direction * self.controller.scroll * self.controller.speed * SCROLL_STEP;
         self.controller.scroll = 0.0;
 
         // Move up/down. Since we don't use roll, we can just
@@ -156,14 +326,89 @@ impl Camera{
         self.controller.rotate_vertical = 0.0;
 
         // Keep the camera's angle from going too high/low.
-        if pitch < -SAFE_FRAC_PI_2_DEG {
-            pitch = -SAFE_FRAC_PI_2_DEG;
-        } else if pitch > SAFE_FRAC_PI_2_DEG {
-            pitch = SAFE_FRAC_PI_2_DEG;
-        }
+        pitch = pitch.clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2);
         self.look_at = self.origin + Vec3::new(pitch.cos() * yaw.sin(), pitch.sin(), pitch.cos() * yaw.cos());
     }
+    //Every other controller moves around look_at. Pending mouse movement is consumed a
+    //fraction at a time so the motion eases out instead of stopping dead
+    fn update_orbit(&mut self, dt: f32){
+        let controller = &mut self.controller;
+        let k = if controller.damping > 0.0 {1.0 - f32::exp(-dt / controller.damping)} else {1.0};
+        let orbit = controller.orbit * k;
+        let pan = controller.pan * k;
+        let dolly = (controller.dolly - controller.scroll * PIXELS_PER_LINE) * k;
+        controller.orbit -= orbit;
+        controller.pan -= pan;
+        controller.dolly = controller.dolly - controller.scroll * PIXELS_PER_LINE - dolly;
+        controller.scroll = 0.0;
+        if controller.orbit.length() < 0.01 && controller.pan.length() < 0.01 && controller.dolly.abs() < 0.01{
+            controller.orbit = Vec2::ZERO;
+            controller.pan = Vec2::ZERO;
+            controller.dolly = 0.0;
+        }
+        let angle = orbit * controller.sensitivity * 0.01;
+
+        let mut offset = self.origin - self.look_at;
+        let distance = offset.length();
+        let w = offset / distance;
+        let u = self.view_up.cross(w).normalize();
+        let v = w.cross(u);
+        match controller.mode{
+            CONTROLLER_TURNTABLE => {
+                //Yaw around the world up and keep the pitch away from the poles
+                let yaw = w.x.atan2(w.z) - angle.x;
+                let pitch = (w.y.asin() + angle.y).clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2);
+                offset = distance * Vec3::new(pitch.cos() * yaw.sin(), pitch.sin(), pitch.cos() * yaw.cos());
+                self.view_up = Vec3::Y;
+            }
+            CONTROLLER_ARCBALL => {
+                //Free rotation about the target, the up vector turns with it so the camera can go over the top
+                let rotation = Quat::from_axis_angle(v, -angle.x) * Quat::from_axis_angle(u, -angle.y);
+                offset = rotation * offset;
+                self.view_up = (rotation * v).normalize();
+            }
+            _ => {}
+        }
+        //Pan by the same fraction of the view whatever the distance
+        let shift = (-pan.x * u + pan.y * v) * distance * 0.002;
+        self.look_at += shift;
+        offset *= f32::exp(dolly * 0.01);
+        self.origin = self.look_at + offset;
+    }
+    //Moves back along the current view direction until the sphere fits in the view
+    pub fn frame(&mut self, center: Vec3, radius: f32){
+        let direction = (self.look_at - self.origin).normalize();
+        let half_fov = f32::atan(f32::tan(radians(self.fov) / 2.0) * self.aspect.min(1.0));
+        let distance = radius / half_fov.sin();
+        self.look_at = if self.controller.mode == CONTROLLER_FLY {center - direction * (distance - 1.0)} else {center};
+        self.origin = center - direction * distance;
+        self.focus_dist = distance;
+        self.focus_target = None;
+        self.ortho_height = 2.0 * radius;
+    }
+    //Switching from fly to an orbit controller orbits whatever is in focus
+    pub fn set_controller(&mut self, mode: i32){
+        if self.controller.mode == CONTROLLER_FLY && mode != CONTROLLER_FLY{
+            let direction = (self.look_at - self.origin).normalize();
+            self.look_at = self.origin + direction * self.focus_dist.max(self.near);
+        }else if self.controller.mode != CONTROLLER_FLY && mode == CONTROLLER_FLY{
+            self.look_at = self.origin + (self.look_at - self.origin).normalize();
+            self.view_up = Vec3::Y;
+        }
+        self.controller.mode = mode;
+    }
 }
+
+pub const CONTROLLER_FLY: i32 = 0;
+pub const CONTROLLER_TURNTABLE: i32 = 1;
+pub const CONTROLLER_ARCBALL: i32 = 2;
+pub const CONTROLLER_PAN: i32 = 3;
+pub const CONTROLLER_DOLLY: i32 = 4;
+pub const CONTROLLER_NAMES: [&str; 5] = ["Fly", "Turntable", "Arcball", "Pan", "Dolly"];
+//Scroll is counted in lines, trackpads report pixels
+const PIXELS_PER_LINE: f32 = 20.0;
+const SCROLL_STEP: f32 = 0.1;
+
 #[derive(Debug)]
 pub struct CameraController{
     amount_left: f32,
@@ -175,8 +420,15 @@ pub struct CameraController{
     rotate_horizontal: f32,
     rotate_vertical: f32,
     scroll: f32,
+    //Mouse movement still to be applied by the orbit controllers, in pixels
+    orbit: Vec2,
+    pan: Vec2,
+    dolly: f32,
     speed: f32,
     sensitivity: f32,
+    pub mode: i32,
+    //Seconds for the orbit controllers to ease out, 0 stops immediately
+    pub damping: f32,
 }
 
 impl CameraController{
@@ -191,8 +443,13 @@ impl CameraController{
             rotate_horizontal: 0.0,
             rotate_vertical: 0.0,
             scroll: 0.0,
+            orbit: Vec2::ZERO,
+            pan: Vec2::ZERO,
+            dolly: 0.0,
             speed,
             sensitivity,
+            mode: CONTROLLER_FLY,
+            damping: 0.1,
         }
     }
 
@@ -228,27 +485,38 @@ impl CameraController{
         }
     }
     pub fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64){
-        self.rotate_horizontal = mouse_dx as f32 * 3.0;
-        self.rotate_vertical = mouse_dy as f32 * 3.0;
+        let delta = Vec2::new(mouse_dx as f32, mouse_dy as f32);
+        match self.mode{
+            CONTROLLER_FLY => {
+                self.rotate_horizontal += delta.x * 3.0;
+                self.rotate_vertical += delta.y * 3.0;
+            }
+            CONTROLLER_PAN => self.pan += delta,
+            CONTROLLER_DOLLY => self.dolly += delta.y,
+            _ => self.orbit += delta,
+        }
     }
 
     pub fn process_scroll(&mut self, delta: &MouseScrollDelta) -> bool {
-        self.scroll = -match delta{
-            MouseScrollDelta::LineDelta(_, scroll) => scroll * 10000.0,
+        self.scroll += match delta{
+            MouseScrollDelta::LineDelta(_, scroll) => *scroll,
             MouseScrollDelta::PixelDelta(PhysicalPosition{
                 y: scroll,
                 ..
-            }) => *scroll as f32,
+            }) => *scroll as f32 / PIXELS_PER_LINE,
         };
         return true;
     }
 
-     
+    //Still easing out or a key is held, so the image will keep changing
+    pub fn is_moving(&self) -> bool{
+        self.orbit != Vec2::ZERO || self.pan != Vec2::ZERO || self.dolly != 0.0
+            || self.amount_forward != self.amount_backward || self.amount_left != self.amount_right || self.amount_up != self.amount_down
+    }
 }
 pub fn radians(deg: f32)->f32{
     deg * (std::f32::consts::PI / 180.0)
 }
-#[allow(unused)]
 pub fn degrees(rad: f32) -> f32{
     rad * (180.0/std::f32::consts::PI)
 }
\ No newline at end of file
@@ -1,25 +1,92 @@
 use std::time::{Duration, Instant};
 use bytemuck::{Pod, Zeroable};
-use imgui_winit_support::winit::{self, event::{WindowEvent, KeyboardInput, ElementState, MouseButton }};
+use imgui_winit_support::winit::{self, event::{WindowEvent, KeyboardInput, ElementState, MouseButton, VirtualKeyCode }};
 use wgpu::{util::DeviceExt};
+use glam::Vec3;
 
-use crate::core::{renderer::Renderer, ray_tracer::RayTracer};
-use super::{window::Window, texture::Texture, scene::Scene};
-
-const WORKGROUP_SIZE: (u32, u32) = (8, 8);
+use crate::core::{renderer::Renderer, ray_tracer::{RayTracer, Resources}, sampler::{self, SAMPLER_SOBOL, SAMPLER_NAMES}, light::{LIGHT_SAMPLING_BVH, LIGHT_SAMPLING_NAMES}, photon_map::{PhotonMap, MAX_PHOTONS}, restir::Restir, denoiser::{Denoiser, MAX_ITERATIONS}, aov::{Aovs, AOV_NAMES, AOV_LIGHTING}, adaptive::Adaptive, bdpt::{Bdpt, BDPT_VERTICES}, exposure::{Exposure, EXPOSURE_NAMES, EXPOSURE_PHYSICAL, EXPOSURE_AUTO}, tonemap::{TONEMAP_NAMES, TONEMAP_NONE, TONEMAP_AGX}, animation::{Timeline, Sequence, CameraKeyframe}, export::{save_png, save, save_exr_layers, render_path, EXPORT_FORMAT_NAMES, EXPORT_PNG, EXPORT_EXR}, integrator::{Integrator, DEBUG_MODE_NAMES}};
+use super::{window::Window, texture::Texture, scene::{Scene, SCENE_NAMES}, camera::{PROJECTION_NAMES, PROJECTION_PERSPECTIVE, PROJECTION_ORTHOGRAPHIC, PROJECTION_FISHEYE, PROJECTION_CUBE_FACE, CUBE_FACE_NAMES, CONTROLLER_NAMES, CONTROLLER_FLY}};
 
 #[repr(C)]
 #[derive(Clone, Copy, Pod, Zeroable,Debug)]
 pub struct Params {
-    width : u32,
-    height : u32,
-    number_of_bounces: i32,
-    rays_per_pixel: i32,
-    skybox: i32,
-    frames: i32,
-    accumulate: i32,
+    pub width : u32,
+    pub height : u32,
+    pub number_of_bounces: i32,
+    pub rays_per_pixel: i32,
+    pub skybox: i32,
+    pub frames: i32,
+    pub accumulate: i32,
+    pub termination: i32,
+    pub min_bounces: i32,
+    pub max_bounces: i32,
+    pub sampler: i32,
+    pub light_sampling: i32,
+    pub spectral: i32,
+    pub photon_count: i32,
+    pub photon_radius: f32,
+    pub restir_candidates: i32,
+    pub restir_temporal: i32,
+    pub restir_spatial_samples: i32,
+    pub restir_spatial_radius: i32,
+    pub restir_max_history: i32,
+    pub ao_distance: f32,
+    pub debug_mode: i32,
+    //Distance or count that maps to the top of the debug colour ramps
+    pub debug_scale: f32,
+    pub motion_blur: i32,
+    //Part of each object's start to end motion the shutter is open for
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+    //Writes primary hit albedo, normal and depth for the denoiser
+    pub features: i32,
+    pub aovs: u32,
+    pub adaptive: i32,
+    pub adaptive_threshold: f32,
+    pub adaptive_min_frames: i32,
+}
+
+impl Params{
+    pub fn new(width: u32, height: u32, photon_map: &PhotonMap, restir: &Restir) -> Self{
+        Self {
+            width,
+            height,
+            number_of_bounces: 3,
+            rays_per_pixel: 1,
+            skybox: 0,
+            frames: 0,
+            accumulate: 1,
+            termination: TERMINATION_RUSSIAN_ROULETTE,
+            min_bounces: 3,
+            max_bounces: 64,
+            sampler: SAMPLER_SOBOL,
+            light_sampling: LIGHT_SAMPLING_BVH,
+            spectral: 0,
+            photon_count: photon_map.photons_per_frame,
+            photon_radius: photon_map.radius,
+            restir_candidates: restir.candidates,
+            restir_temporal: restir.temporal as i32,
+            restir_spatial_samples: restir.spatial_samples,
+            restir_spatial_radius: restir.spatial_radius,
+            restir_max_history: restir.max_history,
+            ao_distance: 1.0,
+            debug_mode: 0,
+            debug_scale: 10.0,
+            motion_blur: 0,
+            shutter_open: 0.0,
+            shutter_close: 1.0,
+            features: 0,
+            aovs: 0,
+            adaptive: 0,
+            adaptive_threshold: 0.01,
+            adaptive_min_frames: 16,
+        }
+    }
 }
 
+pub const TERMINATION_FIXED: i32 = 0;
+pub const TERMINATION_RUSSIAN_ROULETTE: i32 = 1;
+
 pub struct Context{
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
@@ -28,12 +95,43 @@ pub struct Context{
     pub texture: Texture,
     pub params_buffer: wgpu::Buffer,
     pub params: Params,
+    pub blue_noise_buffer: wgpu::Buffer,
+    pub photon_map: PhotonMap,
+    pub exposure: Exposure,
+    pub timeline: Timeline,
+    //Set when the cpu side of the scene was edited and has to be uploaded
+    pub scene_dirty: bool,
+    //Set when something the ray tracer binds was replaced, it's rebound before the next frame
+    pub bindings_dirty: bool,
+    pub sequence: Sequence,
+    pub restir: Restir,
+    pub denoiser: Denoiser,
+    pub aovs: Aovs,
+    pub adaptive: Adaptive,
+    pub bdpt: Bdpt,
+    pub integrator: Integrator,
     pub renderer: Renderer,
     pub ray_tracer: RayTracer,
     pub scene: Scene,
     pub mouse_pressed: bool,
+    pub cursor: winit::dpi::PhysicalPosition<f64>,
+    //Object index from Scene::raycast, picked with right click
+    pub selected_object: Option<usize>,
     pub selected_scene: i32,
+    pub bokeh_path: String,
+    //Why the last bokeh image failed to load, shown under its button
+    pub bokeh_error: Option<String>,
+    //glTF or obj file in the assets folder to add to the scene
+    pub model_path: String,
+    //Why the last model failed to load, shown under its button
+    pub model_error: Option<String>,
     pub prev_scene: i32,
+    pub export_format: i32,
+    pub export_directory: String,
+    //Saved after the next frame is presented, so the readback has everything accumulated so far
+    pub save_requested: bool,
+    //Limit used while "Bounce limit" is on, kept while it's off so turning it back on restores it
+    pub max_bounces: i32,
 }
 
 impl Context{
@@ -66,6 +164,8 @@ impl Context{
         let (device, queue) = adapter
             .request_device(&wgpu::DeviceDescriptor{
                 features: wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES,
+                //The default of 8 storage buffers per stage is too few for the scene, light and photon buffers
+                limits: adapter.limits(),
                 ..Default::default()
             }, None)
             .await
@@ -83,28 +183,39 @@ impl Context{
         surface.configure(&device, &config);
 
         println!("{} {}", config.width, config.height);
-        let params = Params {
-            width: config.width,
-            height: config.height,
-            number_of_bounces: 3,
-            rays_per_pixel: 1,
-            skybox: 0,
-            frames: 0,
-            accumulate: 1,
-        };
+        let photon_map = PhotonMap::new(&device);
+        let restir = Restir::new(&device, config.width, config.height);
+        let params = Params::new(config.width, config.height, &photon_map, &restir);
         let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("parameters buffer"),
             contents: bytemuck::bytes_of(&params),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
+        let blue_noise_buffer = sampler::blue_noise_buffer(&device);
+
         let texture = Texture::new(&device,config.width,config.height,wgpu::TextureFormat::Rgba32Float);
 
-        let renderer = Renderer::new(&device,&queue,&texture,&config,&params_buffer,window.as_ref()).await;
+        let exposure = Exposure::new(&device, &texture);
+        let denoiser = Denoiser::new(&device, &texture, config.width, config.height);
+        let aovs = Aovs::new(&device, config.width, config.height);
+        let adaptive = Adaptive::new(&device, config.width, config.height);
+        let bdpt = Bdpt::new(&device, config.width, config.height);
+        let renderer = Renderer::new(&device,&queue,&texture,&config,&params_buffer,&exposure,window.as_ref()).await;
 
         let scene = Scene::balls(&device, &config);
 
-        let ray_tracer = RayTracer::new(&device,&texture, &params_buffer, &scene);
+        let ray_tracer = RayTracer::new(&device, &Resources{
+            texture: &texture,
+            params_buffer: &params_buffer,
+            blue_noise_buffer: &blue_noise_buffer,
+            photon_map: &photon_map,
+            restir: &restir,
+            denoiser: &denoiser,
+            aovs: &aovs,
+            adaptive: &adaptive,
+            bdpt: &bdpt,
+        }, &scene);
 
         Self{
             device,
@@ -114,12 +225,35 @@ impl Context{
             texture,
             params_buffer,
             params,
+            blue_noise_buffer,
+            photon_map,
+            exposure,
+            timeline: Timeline::default(),
+            scene_dirty: false,
+            bindings_dirty: false,
+            sequence: Sequence::default(),
+            restir,
+            denoiser,
+            aovs,
+            adaptive,
+            bdpt,
+            integrator: Integrator::PathTracer,
             renderer,
             ray_tracer,
             scene,
             mouse_pressed: false,
+            cursor: Default::default(),
+            selected_object: None,
             selected_scene: 0,
+            bokeh_path: String::new(),
+            bokeh_error: None,
+            model_path: String::new(),
+            model_error: None,
             prev_scene: 0,
+            export_format: EXPORT_PNG,
+            export_directory: String::from("renders"),
+            save_requested: false,
+            max_bounces: params.max_bounces,
         }
     }
 
@@ -130,28 +264,81 @@ impl Context{
             self.scene.camera.aspect = size.width as f32/ size.height as f32;
             self.surface.configure(&self.device, &self.config);
             self.texture = Texture::new(&self.device,size.width,size.height,wgpu::TextureFormat::Rgba32Float);
+            self.restir.resize(&self.device, size.width, size.height);
+            self.denoiser.resize(&self.device, &self.texture, size.width, size.height);
+            self.aovs.resize(&self.device, size.width, size.height);
+            self.adaptive.resize(&self.device, size.width, size.height);
+            self.bdpt.resize(&self.device, size.width, size.height);
 
             self.params.width = size.width;
             self.params.height = size.height;
             self.params.frames = -1;
 
             self.queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[self.params]));
-            self.ray_tracer.update_bind_group(&self.device,&self.params_buffer, &self.texture,&self.scene);
-            self.renderer.update_bind_group(&self.device, &self.params_buffer, &self.texture);
+            self.update_ray_tracer_bind_group();
+            self.exposure.update_bind_group(&self.device, &self.texture);
+            self.renderer.update_bind_group(&self.device, &self.params_buffer, &self.exposure, self.aovs.image(self.denoiser.image(&self.texture)));
         }
     }
+    pub fn update_ray_tracer_bind_group(&mut self){
+        self.ray_tracer.update_bind_group(&self.device, &Resources{
+            texture: &self.texture,
+            params_buffer: &self.params_buffer,
+            blue_noise_buffer: &self.blue_noise_buffer,
+            photon_map: &self.photon_map,
+            restir: &self.restir,
+            denoiser: &self.denoiser,
+            aovs: &self.aovs,
+            adaptive: &self.adaptive,
+            bdpt: &self.bdpt,
+        }, &self.scene);
+    }
     pub fn clear_accululation(&mut self){
         self.params.frames = -1;
         self.queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[self.params]));
     }
 
     pub fn input(&mut self, event: &WindowEvent) -> bool {
-        self.clear_accululation();
+        //Leave the frames of a sequence alone while they render
+        if self.sequence.active{
+            return false;
+        }
+        if let WindowEvent::KeyboardInput{input: KeyboardInput{virtual_keycode: Some(VirtualKeyCode::F12), state: ElementState::Pressed, ..}, ..} = event{
+            self.save_requested = true;
+            return false;
+        }
+        //Events imgui takes are left to render, which only resets accumulation for widgets being edited,
+        //so clicking Save or pressing F12 saves what has accumulated
         let io = self.renderer.imgui_layer.context.io();
         if io.want_capture_mouse || io.want_capture_keyboard {
             return false;
         } 
+        self.clear_accululation();
         match event{
+            WindowEvent::KeyboardInput { 
+                input: 
+                    KeyboardInput{
+                        virtual_keycode: Some(VirtualKeyCode::F),
+                        state: ElementState::Pressed,
+                        ..
+                    }, 
+                ..
+            } => {
+                self.scene.frame(self.selected_object);
+                true
+            }
+            WindowEvent::KeyboardInput { 
+                input: 
+                    KeyboardInput{
+                        virtual_keycode: Some(VirtualKeyCode::Home),
+                        state: ElementState::Pressed,
+                        ..
+                    }, 
+                ..
+            } => {
+                self.scene.frame(None);
+                true
+            }
             WindowEvent::KeyboardInput { 
                 input: 
                     KeyboardInput{
@@ -170,20 +357,93 @@ impl Context{
                 self.mouse_pressed = *state == ElementState::Pressed;
                 true
             }
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor = *position;
+                false
+            }
+            WindowEvent::MouseInput { 
+                button: MouseButton::Right, 
+                state: ElementState::Pressed,
+                ..
+            } => {
+                self.focus_at_cursor();
+                true
+            }
             _ => false,
         }
     }
+    //The display quad flips the texture on both axes, see Renderer::create_vertices
+    pub fn focus_at_cursor(&mut self){
+        let x = 1.0 - self.cursor.x as f32 / self.config.width as f32;
+        let y = 1.0 - self.cursor.y as f32 / self.config.height as f32;
+        if let Some((origin, dir)) = self.scene.camera.pixel_ray(x, y){
+            //What's under the cursor at shutter open, where the image starts blurring from
+            let time = if self.params.motion_blur != 0 {self.params.shutter_open} else {0.0};
+            if let Some((t, object)) = self.scene.raycast(origin, dir, time){
+                self.scene.camera.focus_on(origin + dir * t);
+                self.selected_object = Some(object);
+            }
+        }
+    }
     pub fn update(&mut self, dt: Duration){
         self.renderer.dt = dt;
+        //Accumulation is only valid once the camera and focus have settled
+        if self.scene.camera.focus_target.is_some() || self.scene.camera.controller.is_moving(){
+            self.params.frames = -1;
+        }
         self.scene.camera.update_camera(self.renderer.dt);
+        if self.sequence.active{
+            self.timeline.time = self.timeline.frame_time(self.sequence.frame);
+            self.params.accumulate = 1;
+        }else{
+            self.timeline.advance(dt.as_secs_f32());
+        }
+        if self.timeline.evaluated != Some(self.timeline.time){
+            self.timeline.evaluated = Some(self.timeline.time);
+            if let Some(keyframe) = self.scene.camera_path.evaluate(self.timeline.time){
+                keyframe.apply(&mut self.scene.camera);
+            }
+            if self.scene.animate(self.timeline.time, 1.0 / self.timeline.fps){
+                self.scene_dirty = true;
+            }
+            if !self.sequence.active{
+                self.params.frames = -1;
+            }
+        }
+        if self.scene_dirty{
+            self.scene_dirty = false;
+            if !self.ray_tracer.scene_buffers.write(&self.queue, &self.scene){
+                self.bindings_dirty = true;
+            }
+        }
+        if self.bindings_dirty{
+            self.bindings_dirty = false;
+            self.update_ray_tracer_bind_group();
+        }
         let uniform = self.scene.camera.to_uniform();
         if self.params.accumulate != 0{
             self.params.frames +=1;
         }else{
             self.params.frames = -1;
         }
+        self.params.photon_count = self.photon_map.photons_per_frame;
+        self.params.photon_radius = self.photon_map.update_radius(self.params.frames);
+        self.params.restir_candidates = self.restir.candidates;
+        self.params.restir_temporal = self.restir.temporal as i32;
+        self.params.restir_spatial_samples = self.restir.spatial_samples;
+        self.params.restir_spatial_radius = self.restir.spatial_radius;
+        self.params.restir_max_history = self.restir.max_history;
+        self.restir.update_camera(&self.queue, uniform);
+        self.params.features = self.denoiser.enabled as i32;
+        self.params.aovs = self.aovs.mask;
+        self.params.adaptive = self.adaptive.enabled as i32;
+        self.params.adaptive_threshold = self.adaptive.threshold;
+        self.params.adaptive_min_frames = self.adaptive.min_frames;
+        self.adaptive.poll(&self.device);
+        self.denoiser.update(&self.queue, uniform, self.config.width, self.config.height, self.params.frames);
         self.queue.write_buffer(&self.scene.camera.buffer, 0, bytemuck::cast_slice(&[uniform]));
         self.queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[self.params]));
+        self.exposure.update(&self.queue, &self.scene.camera, dt.as_secs_f32());
     }
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError>{
         let output = self.surface.get_current_texture()?;
@@ -193,19 +453,12 @@ impl Context{
             label: Some("Command Encoder")
         });
 
-        {
-            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor{
-                label: Some("Compute Pass"),
-            });
-            let xdim = self.config.width + WORKGROUP_SIZE.0 - 1;
-            let xgroups = xdim / WORKGROUP_SIZE.0;
-            let ydim = self.config.height + WORKGROUP_SIZE.1 - 1;
-            let ygroups = ydim / WORKGROUP_SIZE.1;
-
-            compute_pass.set_pipeline(&self.ray_tracer.pipeline);
-            compute_pass.set_bind_group(0, &self.ray_tracer.bind_group, &[]);
-            compute_pass.dispatch_workgroups(xgroups,ygroups,1);
-        }
+        self.ray_tracer.dispatch(&mut encoder, self.integrator, &self.photon_map, &mut self.restir, &self.adaptive, &self.bdpt);
+        self.adaptive.readback(&mut encoder);
+        self.denoiser.dispatch(&mut encoder, self.config.width, self.config.height);
+        self.aovs.dispatch(&mut encoder);
+        self.exposure.dispatch(&mut encoder, self.config.width, self.config.height);
+        let display = (self.denoiser.enabled, self.aovs.shown);
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor{
                 label: Some("Render Pass"),
@@ -227,6 +480,42 @@ impl Context{
             render_pass.draw_indexed(0..6, 0, 0..1);
             let mut skybox = self.params.skybox != 0;
             let mut accumulate = self.params.accumulate != 0;
+            let mut termination = self.params.termination as usize;
+            let mut bounce_limit = self.params.max_bounces > 0;
+            let mut sampler = self.params.sampler as usize;
+            let mut light_sampling = self.params.light_sampling as usize;
+            let mut spectral = self.params.spectral != 0;
+            let mut motion_blur = self.params.motion_blur != 0;
+            let mut integrator = self.integrator as usize;
+            let mut debug_mode = self.params.debug_mode as usize;
+            let mut focal_length = self.scene.camera.focal_length();
+            let mut projection = self.scene.camera.projection as usize;
+            let mut controller = self.scene.camera.controller.mode as usize;
+            let mut frame_selection = false;
+            let mut exposure_mode = self.exposure.mode as usize;
+            let mut tone_map = self.exposure.tone_map as usize;
+            let mut add_keyframe = false;
+            let mut go_to_keyframe = None;
+            let mut remove_keyframe = None;
+            let mut start_sequence = false;
+            let mut object_state = self.selected_object.and_then(|object| self.scene.object_state(object, self.timeline.time));
+            let mut object_edited = false;
+            let mut spin = self.selected_object.and_then(|object| self.scene.object_spin(object));
+            let mut spin_edited = false;
+            let mut add_object_keyframe = false;
+            let mut remove_track = None;
+            let mut load_model = false;
+            let mut rig_edited = false;
+            let mut frame_all = false;
+            let mut cube_face = self.scene.camera.cube_face as usize;
+            let mut load_bokeh = false;
+            let mut export_format = self.export_format as usize;
+            //Widgets that only change what is shown, editing them keeps the accumulation
+            let mut display_active = false;
+            let mut denoise = self.denoiser.enabled;
+            let mut adaptive = self.adaptive.enabled;
+            let mut aov_mask = self.aovs.mask;
+            let mut shown_aov = self.aovs.shown.map_or(0, |aov| aov + 1);
             let ui = self.renderer.imgui_layer.context.frame();
             {
                 ui.window("Camera Info")
@@ -247,49 +536,481 @@ impl Context{
                             "Look At: ({})",
                             self.scene.camera.look_at
                         ));
-                        ui.input_int("Bounces", &mut self.params.number_of_bounces).build();
+                        ui.combo_simple_string("Integrator", &mut integrator, &Integrator::ALL.map(|i| i.name()));
+                        if Integrator::ALL[integrator] == Integrator::PhotonMapping{
+                            ui.input_int("Photons per frame", &mut self.photon_map.photons_per_frame).build();
+                            ui.slider("Photon radius", 0.001, 0.5, &mut self.photon_map.initial_radius);
+                            ui.slider("Radius alpha", 0.1, 1.0, &mut self.photon_map.alpha);
+                            ui.text(format!("Current radius: {:.4}", self.photon_map.radius));
+                        }
+                        if Integrator::ALL[integrator] == Integrator::Bidirectional{
+                            ui.text(format!("Subpaths stop at {} vertices", BDPT_VERTICES));
+                            let light_tracing = self.scene.camera.projection == PROJECTION_PERSPECTIVE && self.scene.camera.lens_radius() <= 0.0 && !self.adaptive.enabled;
+                            if !light_tracing{
+                                ui.text("Light tracing needs a pinhole perspective camera and no adaptive sampling");
+                            }
+                        }
+                        if Integrator::ALL[integrator] == Integrator::Restir{
+                            ui.input_int("Candidates", &mut self.restir.candidates).build();
+                            ui.checkbox("Temporal reuse", &mut self.restir.temporal);
+                            ui.input_int("Max history", &mut self.restir.max_history).build();
+                            ui.input_int("Spatial samples", &mut self.restir.spatial_samples).build();
+                            ui.input_int("Spatial radius", &mut self.restir.spatial_radius).build();
+                        }
+                        if Integrator::ALL[integrator] == Integrator::AmbientOcclusion{
+                            ui.slider("AO distance", 0.01, 10.0, &mut self.params.ao_distance);
+                        }
+                        if Integrator::ALL[integrator] == Integrator::Debug{
+                            ui.combo_simple_string("Debug view", &mut debug_mode, &DEBUG_MODE_NAMES);
+                            ui.slider_config("Debug scale", 1.0, 1000.0)
+                                .flags(imgui::SliderFlags::LOGARITHMIC)
+                                .build(&mut self.params.debug_scale);
+                        }
+                        ui.combo_simple_string("Termination", &mut termination, &["Fixed depth", "Russian roulette"]);
+                        if termination as i32 == TERMINATION_FIXED{
+                            ui.input_int("Bounces", &mut self.params.number_of_bounces).build();
+                        }else{
+                            ui.input_int("Min bounces", &mut self.params.min_bounces).build();
+                            ui.checkbox("Bounce limit", &mut bounce_limit);
+                            if bounce_limit{
+                                ui.input_int("Max bounces", &mut self.max_bounces).build();
+                            }
+                        }
                         ui.input_int("Rays per pixel", &mut self.params.rays_per_pixel).build();
+                        ui.combo_simple_string("Sampler", &mut sampler, &SAMPLER_NAMES);
+                        ui.combo_simple_string("Light sampling", &mut light_sampling, &LIGHT_SAMPLING_NAMES);
+                        ui.checkbox("Spectral", &mut spectral);
+                        ui.checkbox("Motion blur", &mut motion_blur);
+                        if motion_blur{
+                            ui.slider("Shutter open", 0.0, 1.0, &mut self.params.shutter_open);
+                            ui.slider("Shutter close", 0.0, 1.0, &mut self.params.shutter_close);
+                        }
                         ui.checkbox("Skybox", &mut skybox);
                         ui.checkbox("Accumulate", &mut accumulate);
-                        ui.slider("Focus distance", 0.0, 10.0, &mut self.scene.camera.focus_dist);
-                        ui.slider("Aperture", -2.0, 2.0, &mut self.scene.camera.aperture);
+                        ui.checkbox("Adaptive sampling", &mut adaptive);
+                        if adaptive{
+                            //Lowering the threshold picks the converged pixels back up, no need to start over
+                            ui.slider_config("Noise threshold", 0.0001, 1.0)
+                                .flags(imgui::SliderFlags::LOGARITHMIC)
+                                .build(&mut self.adaptive.threshold);
+                            display_active |= ui.is_item_active();
+                            ui.input_int("Min frames", &mut self.adaptive.min_frames).build();
+                            display_active |= ui.is_item_active();
+                            ui.text(format!(
+                                "Converged: {:.1}% ({}/{})",
+                                self.adaptive.progress() * 100.0,
+                                self.adaptive.converged,
+                                self.config.width * self.config.height
+                            ));
+                        }
+                        ui.combo_simple_string("Controller", &mut controller, &CONTROLLER_NAMES);
+                        if controller as i32 != CONTROLLER_FLY{
+                            ui.slider("Damping (s)", 0.0, 1.0, &mut self.scene.camera.controller.damping);
+                        }
+                        frame_selection = ui.button("Frame selection (F)");
+                        ui.same_line();
+                        frame_all = ui.button("Frame all (Home)");
+                        ui.combo_simple_string("Projection", &mut projection, &PROJECTION_NAMES);
+                        match projection as i32{
+                            PROJECTION_ORTHOGRAPHIC => {
+                                ui.slider("Ortho height", 0.1, 100.0, &mut self.scene.camera.ortho_height);
+                            }
+                            PROJECTION_FISHEYE => {
+                                ui.slider("Fisheye fov", 10.0, 360.0, &mut self.scene.camera.fisheye_fov);
+                            }
+                            PROJECTION_CUBE_FACE => {
+                                ui.combo_simple_string("Cube face", &mut cube_face, &CUBE_FACE_NAMES);
+                            }
+                            _ => {}
+                        }
+                        if projection as i32 == PROJECTION_PERSPECTIVE{
+                            ui.slider("Focal length (mm)", 10.0, 300.0, &mut focal_length);
+                            ui.checkbox("Pinhole", &mut self.scene.camera.pinhole);
+                        }
+                        //Depth of field only applies to the perspective projection
+                        if projection as i32 == PROJECTION_PERSPECTIVE && !self.scene.camera.pinhole{
+                            ui.slider_config("F-stop", 0.1, 32.0)
+                                .flags(imgui::SliderFlags::LOGARITHMIC)
+                                .build(&mut self.scene.camera.f_stop);
+                            ui.slider("Focus distance", 0.0, 10.0, &mut self.scene.camera.focus_dist);
+                            ui.slider("Focus pull (s)", 0.0, 2.0, &mut self.scene.camera.focus_pull);
+                            ui.text("Right click to focus");
+                            ui.input_int("Blades", &mut self.scene.camera.blades).build();
+                            ui.slider("Blade rotation", 0.0, 360.0, &mut self.scene.camera.blade_rotation);
+                            ui.slider("Anamorphic", 1.0, 2.0, &mut self.scene.camera.anamorphic);
+                            ui.input_text("Bokeh image", &mut self.bokeh_path).build();
+                            load_bokeh = ui.button("Load bokeh");
+                            if self.scene.camera.bokeh{
+                                ui.same_line();
+                                if ui.button("Clear bokeh"){
+                                    self.scene.camera.bokeh = false;
+                                }
+                            }
+                            if let Some(error) = &self.bokeh_error{
+                                ui.text_colored([1.0, 0.4, 0.4, 1.0], error);
+                            }
+                        }
+                        ui.combo_simple_string("Exposure", &mut exposure_mode, &EXPOSURE_NAMES);
+                        if exposure_mode as i32 == EXPOSURE_PHYSICAL{
+                            ui.slider_config("ISO", 50.0, 12800.0)
+                                .flags(imgui::SliderFlags::LOGARITHMIC)
+                                .build(&mut self.scene.camera.iso);
+                            ui.slider_config("Shutter (s)", 1.0 / 8000.0, 30.0)
+                                .flags(imgui::SliderFlags::LOGARITHMIC)
+                                .display_format("%.5f")
+                                .build(&mut self.scene.camera.shutter);
+                            //Otherwise it is with the lens settings
+                            if self.scene.camera.pinhole || self.scene.camera.projection != PROJECTION_PERSPECTIVE{
+                                ui.slider_config("F-stop", 0.1, 32.0)
+                                    .flags(imgui::SliderFlags::LOGARITHMIC)
+                                    .build(&mut self.scene.camera.f_stop);
+                            }
+                            ui.text(format!("EV100: {:.2}", self.scene.camera.ev100()));
+                        }
+                        if exposure_mode as i32 == EXPOSURE_AUTO{
+                            ui.slider("Adaptation speed", 0.1, 10.0, &mut self.exposure.speed);
+                            ui.slider("Low percentile", 0.0, 1.0, &mut self.exposure.low_percentile);
+                            ui.slider("High percentile", 0.0, 1.0, &mut self.exposure.high_percentile);
+                        }
+                        ui.slider("Compensation (EV)", -10.0, 10.0, &mut self.exposure.compensation);
+                        ui.combo_simple_string("Tone mapping", &mut tone_map, &TONEMAP_NAMES);
+                        if tone_map as i32 != TONEMAP_AGX && tone_map as i32 != TONEMAP_NONE{
+                            ui.slider_config("White point", 0.1, 100.0)
+                                .flags(imgui::SliderFlags::LOGARITHMIC)
+                                .build(&mut self.exposure.white_point);
+                        }
+                        ui.checkbox("Denoise", &mut denoise);
+                        if denoise{
+                            ui.slider_config("Strength", 0.1, 100.0)
+                                .flags(imgui::SliderFlags::LOGARITHMIC)
+                                .build(&mut self.denoiser.strength);
+                            display_active |= ui.is_item_active();
+                            ui.slider("Iterations", 1, MAX_ITERATIONS, &mut self.denoiser.iterations);
+                            display_active |= ui.is_item_active();
+                            ui.checkbox("Temporal accumulation", &mut self.denoiser.temporal);
+                            ui.input_int("Denoise history", &mut self.denoiser.max_history).build();
+                            display_active |= ui.is_item_active();
+                        }
                         ui.input_int("Scene ID", &mut self.selected_scene).build();
+                        ui.separator();
+                        ui.combo_simple_string("Export format", &mut export_format, &EXPORT_FORMAT_NAMES);
+                        ui.input_text("Export directory", &mut self.export_directory).build();
+                        if ui.button("Save render (F12)"){
+                            self.save_requested = true;
+                        }
+                        display_active |= ui.is_item_active();
+                    });
+                ui.window("AOVs")
+                    .size([200.0, 300.0], imgui::Condition::FirstUseEver)
+                    .build(|| {
+                        let views: Vec<&str> = std::iter::once("Image").chain(AOV_NAMES).collect();
+                        ui.combo_simple_string("View", &mut shown_aov, &views);
+                        display_active |= ui.is_item_active();
+                        let supported = Integrator::ALL[integrator].aov_mask();
+                        for (aov, name) in AOV_NAMES.iter().enumerate(){
+                            let mut enabled = aov_mask & (1 << aov) != 0;
+                            let _disabled = ui.begin_disabled(supported & (1 << aov) == 0);
+                            if ui.checkbox(name, &mut enabled){
+                                aov_mask ^= 1 << aov;
+                            }
+                        }
+                        if supported & AOV_LIGHTING == 0{
+                            ui.text("Lighting AOVs need the path tracer or photon mapping");
+                        }
+                        ui.text("EXR exports include every enabled AOV");
+                    });
+                ui.window("Timeline")
+                    .size([300.0, 250.0], imgui::Condition::FirstUseEver)
+                    .build(|| {
+                        ui.slider("Time", self.timeline.start, self.timeline.end, &mut self.timeline.time);
+                        if ui.button(if self.timeline.playing {"Pause"} else {"Play"}){
+                            self.timeline.playing = !self.timeline.playing;
+                        }
+                        ui.input_float("Start", &mut self.timeline.start).build();
+                        ui.input_float("End", &mut self.timeline.end).build();
+                        ui.input_float("FPS", &mut self.timeline.fps).build();
+                        ui.separator();
+                        add_keyframe = ui.button("Add camera keyframe");
+                        for (i, keyframe) in self.scene.camera_path.keyframes.iter().enumerate(){
+                            ui.text(format!("{:.2}s", keyframe.time));
+                            ui.same_line();
+                            if ui.button(format!("Go to##{}", i)){
+                                go_to_keyframe = Some(i);
+                            }
+                            ui.same_line();
+                            if ui.button(format!("Delete##{}", i)){
+                                remove_keyframe = Some(i);
+                            }
+                        }
+                        ui.separator();
+                        match (self.selected_object, object_state.as_mut()){
+                            (Some(object), Some(state)) => {
+                                ui.text(format!("Object {}", object));
+                                let mut position = state.position.to_array();
+                                let mut emission_color = state.emission_color.to_array();
+                                object_edited |= ui.input_float3("Position", &mut position).build();
+                                if object < self.scene.spheres.len(){
+                                    object_edited |= ui.input_float("Radius", &mut state.radius).build();
+                                }
+                                object_edited |= ui.color_edit3("Emission colour", &mut emission_color);
+                                object_edited |= ui.input_float("Emission strength", &mut state.emission_strength).build();
+                                object_edited |= ui.slider("Smoothness", -1.0, 1.0, &mut state.smoothness);
+                                if let Some(spin) = spin.as_mut(){
+                                    let mut degrees = (*spin * 180.0 / std::f32::consts::PI).to_array();
+                                    spin_edited = ui.input_float3("Spin over shutter (deg)", &mut degrees).build();
+                                    *spin = Vec3::from(degrees) * std::f32::consts::PI / 180.0;
+                                }
+                                state.position = position.into();
+                                state.emission_color = emission_color.into();
+                                add_object_keyframe = ui.button("Add object keyframe");
+                            }
+                            _ => ui.text("Right click an object to animate it"),
+                        }
+                        for (i, track) in self.scene.object_tracks.iter().enumerate(){
+                            ui.text(format!("Object {}: {} keyframes", track.object, track.track.keyframes.len()));
+                            ui.same_line();
+                            if ui.button(format!("Delete track##{}", i)){
+                                remove_track = Some(i);
+                            }
+                        }
+                        ui.separator();
+                        ui.input_text("Model", &mut self.model_path).build();
+                        ui.same_line();
+                        load_model = ui.button("Load");
+                        if let Some(error) = &self.model_error{
+                            ui.text_colored([1.0, 0.4, 0.4, 1.0], error);
+                        }
+                        for (i, rig) in self.scene.rigs.iter_mut().enumerate(){
+                            let clips = rig.clips.iter().map(|c| c.name.as_str()).collect::<Vec<_>>();
+                            rig_edited |= ui.combo_simple_string(format!("Animation##{}", i), &mut rig.clip, &clips);
+                            rig_edited |= ui.input_float(format!("Speed##{}", i), &mut rig.speed).build();
+                        }
+                        ui.separator();
+                        ui.input_text("Directory", &mut self.sequence.directory).build();
+                        ui.input_int("Samples per frame", &mut self.sequence.samples).build();
+                        if self.sequence.active{
+                            ui.text(format!(
+                                "Frame {}/{}, {} samples",
+                                self.sequence.frame + 1,
+                                self.timeline.frame_count(),
+                                (self.params.frames + 1) * self.params.rays_per_pixel
+                            ));
+                            if ui.button("Stop"){
+                                self.sequence.active = false;
+                            }
+                        }else{
+                            start_sequence = ui.button("Render sequence");
+                        }
                     });
             }
+            //Anything being edited changes the image, except pressing the save button or tuning the denoiser.
+            //Hovering or clicking the windows keeps what has accumulated, see input
+            if ui.is_any_item_active() && !display_active{
+                self.params.frames = -1;
+            }
+            self.export_format = export_format as i32;
             if !(self.selected_scene==self.prev_scene){
                 println!("changeing {}",self.selected_scene);
-                match self.selected_scene{
-                    0 => {
-                        self.scene = Scene::balls(&self.device, &self.config);
-                        self.ray_tracer = RayTracer::new(&self.device,&self.texture, &self.params_buffer, &self.scene);
-                    }
-                    1 => {
-                        self.scene = Scene::random_balls(&self.device, &self.config);
-                        self.ray_tracer = RayTracer::new(&self.device,&self.texture, &self.params_buffer, &self.scene);
-                    }
-                    2 => {
-                        self.scene = Scene::room(&self.device, &self.config);
-                        self.ray_tracer = RayTracer::new(&self.device,&self.texture, &self.params_buffer, &self.scene);
-                    }
-                    3 => {
-                        self.scene = Scene::metal(&self.device, &self.config);
-                        self.ray_tracer = RayTracer::new(&self.device,&self.texture, &self.params_buffer, &self.scene);
-                    }
-                    _ => ()
+                if let Some(scene) = Scene::from_id(self.selected_scene, &self.device, &self.config){
+                    self.scene = scene;
+                    //Only the scene's buffers change, the pipelines are kept
+                    self.bindings_dirty = true;
                 }
+                self.restir.reset = true;
+                self.denoiser.reset = true;
+                self.selected_object = None;
+                self.timeline.evaluated = None;
                 self.params.frames = -1;
                 self.queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[self.params]));
             }
             self.prev_scene = self.selected_scene;
             self.params.skybox = skybox as i32;
             self.params.accumulate = accumulate as i32;
+            self.params.termination = termination as i32;
+            self.params.sampler = sampler as i32;
+            self.params.light_sampling = light_sampling as i32;
+            self.params.spectral = spectral as i32;
+            self.params.motion_blur = motion_blur as i32;
+            self.params.shutter_close = self.params.shutter_close.max(self.params.shutter_open);
+            self.integrator = Integrator::ALL[integrator];
+            self.params.debug_mode = debug_mode as i32;
+            self.scene.camera.projection = projection as i32;
+            self.exposure.mode = exposure_mode as i32;
+            self.exposure.tone_map = tone_map as i32;
+            //The features are only written while denoising, so they have to accumulate from scratch
+            if denoise != self.denoiser.enabled{
+                self.denoiser.enabled = denoise;
+                self.denoiser.reset = true;
+                self.params.frames = -1;
+            }
+            //Viewing an AOV turns it on, lighting AOVs the integrator would leave black are turned off
+            let supported = self.integrator.aov_mask();
+            if shown_aov > 0 && supported & (1 << (shown_aov - 1)) == 0{
+                shown_aov = 0;
+            }
+            if shown_aov > 0{
+                aov_mask |= 1 << (shown_aov - 1);
+            }
+            if self.aovs.set_mask(&self.device, aov_mask & supported){
+                self.bindings_dirty = true;
+                self.params.frames = -1;
+            }
+            self.aovs.shown = shown_aov.checked_sub(1);
+            //Pixels only have moments from frames rendered with adaptive sampling on
+            if adaptive != self.adaptive.enabled{
+                self.adaptive.enabled = adaptive;
+                self.adaptive.converged = 0;
+                self.params.frames = -1;
+            }
+            if add_keyframe{
+                self.scene.camera_path.insert(CameraKeyframe::from_camera(&self.scene.camera, self.timeline.time));
+            }
+            if let Some(i) = go_to_keyframe{
+                self.timeline.time = self.scene.camera_path.keyframes[i].time;
+                self.scene.camera_path.keyframes[i].apply(&mut self.scene.camera);
+            }
+            if let Some(i) = remove_keyframe{
+                self.scene.camera_path.keyframes.remove(i);
+            }
+            if let (Some(object), Some(state), true) = (self.selected_object, object_state, object_edited){
+                self.scene.set_object_state(object, &state);
+                self.scene_dirty = true;
+                self.params.frames = -1;
+            }
+            if let (Some(object), Some(spin), true) = (self.selected_object, spin, spin_edited){
+                self.scene.set_object_spin(object, spin);
+                self.scene_dirty = true;
+                self.params.frames = -1;
+            }
+            if let Some(object) = self.selected_object.filter(|_| add_object_keyframe){
+                self.scene.add_object_keyframe(object, self.timeline.time);
+            }
+            if let Some(i) = remove_track{
+                self.scene.object_tracks.remove(i);
+            }
+            if load_model{
+                match self.scene.load_model(&self.model_path){
+                    Ok(()) => {
+                        self.model_error = None;
+                        if let Some(duration) = self.scene.rigs.last().map(|rig| rig.duration()).filter(|d| *d > 0.0){
+                            self.timeline.end = self.timeline.start + duration;
+                        }
+                        self.bindings_dirty = true;
+                        self.timeline.evaluated = None;
+                        self.params.frames = -1;
+                    }
+                    Err(e) => {
+                        log::error!("Failed to load model {}: {}", self.model_path, e);
+                        self.model_error = Some(format!("Failed to load model: {}", e));
+                    }
+                }
+            }
+            if rig_edited{
+                self.timeline.evaluated = None;
+            }
+            if start_sequence{
+                self.sequence.frame = 0;
+                self.sequence.active = true;
+                self.timeline.playing = false;
+                self.params.frames = -1;
+            }
+            self.timeline.fps = self.timeline.fps.max(1.0);
+            self.timeline.end = self.timeline.end.max(self.timeline.start);
+            self.sequence.samples = self.sequence.samples.max(1);
+            self.scene.camera.iso = self.scene.camera.iso.max(1.0);
+            self.scene.camera.shutter = self.scene.camera.shutter.max(1e-5);
+            self.scene.camera.f_stop = self.scene.camera.f_stop.max(0.1);
+            self.scene.camera.set_controller(controller as i32);
+            if frame_selection{
+                self.scene.frame(self.selected_object);
+            }
+            if frame_all{
+                self.scene.frame(None);
+            }
+            self.scene.camera.cube_face = cube_face as i32;
+            if focal_length != self.scene.camera.focal_length(){
+                self.scene.camera.set_focal_length(focal_length);
+            }
+            if load_bokeh{
+                self.bokeh_error = self.scene.camera.load_bokeh(&self.queue, &self.bokeh_path).err().map(|e|{
+                    log::error!("Failed to load bokeh {}: {}", self.bokeh_path, e);
+                    format!("Failed to load bokeh: {}", e)
+                });
+            }
+            self.scene.camera.blades = self.scene.camera.blades.max(0);
+            self.scene.camera.anamorphic = self.scene.camera.anamorphic.max(0.1);
+            self.photon_map.photons_per_frame = self.photon_map.photons_per_frame.clamp(0, MAX_PHOTONS as i32);
+            self.restir.candidates = self.restir.candidates.max(1);
+            self.restir.max_history = self.restir.max_history.max(1);
+            self.restir.spatial_samples = self.restir.spatial_samples.max(0);
+            self.restir.spatial_radius = self.restir.spatial_radius.max(1);
+            self.denoiser.iterations = self.denoiser.iterations.clamp(1, MAX_ITERATIONS);
+            self.denoiser.max_history = self.denoiser.max_history.max(1);
+            self.adaptive.threshold = self.adaptive.threshold.max(1e-6);
+            self.adaptive.min_frames = self.adaptive.min_frames.max(1);
+            self.params.min_bounces = self.params.min_bounces.max(0);
+            self.max_bounces = self.max_bounces.max(1);
+            self.params.max_bounces = if bounce_limit {self.max_bounces} else {0};
 
             self.renderer.imgui_layer
             .render(&self.device, &self.queue, &mut render_pass)
             .expect("Failed to render imgui layer");
         }
+        if (self.denoiser.enabled, self.aovs.shown) != display{
+            self.renderer.update_bind_group(&self.device, &self.params_buffer, &self.exposure, self.aovs.image(self.denoiser.image(&self.texture)));
+        }
         self.queue.submit(std::iter::once(encoder.finish()));
+        self.adaptive.map();
         output.present();
+        if self.sequence.active && (self.params.frames + 1) * self.params.rays_per_pixel >= self.sequence.samples{
+            self.save_sequence_frame();
+        }
+        if self.save_requested{
+            self.save_requested = false;
+            self.save_render();
+        }
         Ok(())
     }
+
+    fn save_render(&self){
+        let scene = SCENE_NAMES.get(self.selected_scene as usize).copied().unwrap_or("scene");
+        let samples = (self.params.frames + 1) * self.params.rays_per_pixel;
+        let path = render_path(&self.export_directory, scene, samples, self.export_format);
+        let image = self.denoiser.image(&self.texture);
+        let result = if self.export_format == EXPORT_EXR && self.aovs.mask != 0{
+            let pixels = image.read(&self.device, &self.queue);
+            save_exr_layers(&path, image.width, image.height, &pixels, &self.aovs.read(&self.device, &self.queue))
+        }else{
+            //Whatever is on screen, which may be an AOV
+            let image = self.aovs.image(image);
+            let mut pixels = image.read(&self.device, &self.queue);
+            //Hdr formats stay linear and unexposed
+            if self.export_format == EXPORT_PNG{
+                self.exposure.apply(&mut pixels, self.exposure.scale(&self.device, &self.queue, &self.scene.camera));
+            }
+            save(&path, image.width, image.height, &pixels)
+        };
+        match result{
+            Ok(()) => log::info!("Saved {}", path.display()),
+            Err(e) => log::error!("Failed to save {}: {}", path.display(), e),
+        }
+    }
+
+    fn save_sequence_frame(&mut self){
+        let mut pixels = self.aovs.image(self.denoiser.image(&self.texture)).read(&self.device, &self.queue);
+        self.exposure.apply(&mut pixels, self.exposure.scale(&self.device, &self.queue, &self.scene.camera));
+        let path = self.sequence.path();
+        match save_png(&path, self.texture.width, self.texture.height, &pixels){
+            Ok(()) => log::info!("Saved {}", path.display()),
+            Err(e) => {
+                log::error!("Failed to save {}: {}", path.display(), e);
+                self.sequence.active = false;
+            }
+        }
+        self.sequence.frame += 1;
+        if self.sequence.frame >= self.timeline.frame_count(){
+            self.sequence.active = false;
+        }
+        self.clear_accululation();
+    }
 }
\ No newline at end of file
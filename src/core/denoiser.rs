@@ -0,0 +1,368 @@
+use std::mem;
+
+use wgpu::util::DeviceExt;
+
+use super::{texture::Texture, camera::CameraUniform};
+
+pub const MAX_ITERATIONS: i32 = 5;
+//Has to match denoise.wgsl
+const WORKGROUP_SIZE: (u32, u32) = (8, 8);
+
+//Primary hit of a pixel, written by the ray tracer
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct Feature{
+    pub albedo: [f32;3],
+    pub depth: f32,
+    pub normal: [f32;3],
+    pub _padding: f32,
+    pub position: [f32;3],
+    pub _padding2: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct DenoiseUniform{
+    pub width: u32,
+    pub height: u32,
+    pub frames: i32,
+    pub temporal: i32,
+    pub strength: f32,
+    pub max_history: f32,
+    pub _padding: [f32;2],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+struct Iteration{
+    step: i32,
+    source: i32,
+    last: i32,
+    _padding: i32,
+}
+
+//Edge-avoiding a-trous filter over the accumulated image, guided by the albedo, normal and depth
+//of the primary hits. Lighting is demodulated by the albedo, reprojected into a history after the
+//camera moves and then filtered with a kernel that doubles its spacing every iteration
+pub struct Denoiser{
+    pub features_buffer: wgpu::Buffer,
+    pub prev_features_buffer: wgpu::Buffer,
+    pub history_buffer: wgpu::Buffer,
+    pub ping_buffer: wgpu::Buffer,
+    pub pong_buffer: wgpu::Buffer,
+    pub uniform_buffer: wgpu::Buffer,
+    pub prev_camera_buffer: wgpu::Buffer,
+    pub prev_camera: CameraUniform,
+    iteration_buffer: wgpu::Buffer,
+    iteration_stride: u64,
+    //Denoised image, shown and saved instead of the accumulation when enabled
+    pub output: Texture,
+    pub temporal_pipeline: wgpu::ComputePipeline,
+    pub atrous_pipeline: wgpu::ComputePipeline,
+    pub bind_group: wgpu::BindGroup,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    iteration_bind_group: wgpu::BindGroup,
+    pub enabled: bool,
+    pub iterations: i32,
+    //Scales how different two luminances can be and still get blended
+    pub strength: f32,
+    pub temporal: bool,
+    pub max_history: i32,
+    //Set when the history no longer describes the scene
+    pub reset: bool,
+}
+
+impl Denoiser{
+    pub fn new(device: &wgpu::Device, texture: &Texture, width: u32, height: u32) -> Self{
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor{
+            label: Some("Denoise Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("../../shaders/denoise.wgsl").into())
+        });
+        let (features_buffer, prev_features_buffer, history_buffer, ping_buffer, pong_buffer) = Self::pixel_buffers(device, width, height);
+        let output = Texture::new(device, width, height, wgpu::TextureFormat::Rgba32Float);
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor{
+            label: Some("Denoise Buffer"),
+            contents: bytemuck::bytes_of(&DenoiseUniform::default()),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let prev_camera = CameraUniform::default();
+        let prev_camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor{
+            label: Some("Denoise Previous Camera Buffer"),
+            contents: bytemuck::bytes_of(&prev_camera),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        //Every iteration has its own step, so they sit at aligned offsets of one buffer
+        let alignment = device.limits().min_uniform_buffer_offset_alignment as u64;
+        let iteration_stride = (mem::size_of::<Iteration>() as u64).next_multiple_of(alignment);
+        let iteration_buffer = device.create_buffer(&wgpu::BufferDescriptor{
+            label: Some("Denoise Iteration Buffer"),
+            size: iteration_stride * MAX_ITERATIONS as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let uniform = |binding|{
+            wgpu::BindGroupLayoutEntry {
+                binding,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer{
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }
+        };
+        let storage = |binding, read_only|{
+            wgpu::BindGroupLayoutEntry {
+                binding,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer{
+                    ty: wgpu::BufferBindingType::Storage { read_only },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor{
+            label: Some("Denoise Bind Group Layout"),
+            entries: &[
+                uniform(0),
+                uniform(1),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: texture.binding_type(wgpu::StorageTextureAccess::ReadWrite),
+                    count: None,
+                },
+                storage(3, true),
+                storage(4, true),
+                storage(5, true),
+                storage(6, false),
+                storage(7, false),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 8,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: output.binding_type(wgpu::StorageTextureAccess::WriteOnly),
+                    count: None,
+                },
+            ],
+        });
+        let iteration_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor{
+            label: Some("Denoise Iteration Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer{
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: wgpu::BufferSize::new(mem::size_of::<Iteration>() as _),
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let iteration_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor{
+            label: Some("Denoise Iteration Bind Group"),
+            layout: &iteration_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding{
+                        buffer: &iteration_buffer,
+                        offset: 0,
+                        size: wgpu::BufferSize::new(mem::size_of::<Iteration>() as _),
+                    }),
+                },
+            ],
+        });
+        let bind_group = Self::create_bind_group(device, &bind_group_layout, [&uniform_buffer, &prev_camera_buffer, &features_buffer, &prev_features_buffer, &history_buffer, &ping_buffer, &pong_buffer], texture, &output);
+        Self{
+            bind_group,
+            temporal_pipeline: Self::pipeline(device, &shader, &bind_group_layout, &iteration_bind_group_layout, "temporal"),
+            atrous_pipeline: Self::pipeline(device, &shader, &bind_group_layout, &iteration_bind_group_layout, "atrous"),
+            features_buffer,
+            prev_features_buffer,
+            history_buffer,
+            ping_buffer,
+            pong_buffer,
+            uniform_buffer,
+            prev_camera_buffer,
+            prev_camera,
+            iteration_buffer,
+            iteration_stride,
+            output,
+            bind_group_layout,
+            iteration_bind_group,
+            enabled: false,
+            iterations: 4,
+            strength: 4.0,
+            temporal: true,
+            max_history: 16,
+            reset: true,
+        }
+    }
+
+    fn pipeline(device: &wgpu::Device, shader: &wgpu::ShaderModule, bind_group_layout: &wgpu::BindGroupLayout, iteration_bind_group_layout: &wgpu::BindGroupLayout, entry_point: &str) -> wgpu::ComputePipeline{
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor{
+            label: Some("Denoise Pipeline Layout"),
+            bind_group_layouts: &[bind_group_layout, iteration_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some(entry_point),
+            layout: Some(&layout),
+            module: shader,
+            entry_point,
+        })
+    }
+
+    fn pixel_buffers(device: &wgpu::Device, width: u32, height: u32) -> (wgpu::Buffer, wgpu::Buffer, wgpu::Buffer, wgpu::Buffer, wgpu::Buffer){
+        let pixels = (width * height) as u64;
+        let buffer = |label, size|{
+            device.create_buffer(&wgpu::BufferDescriptor{
+                label: Some(label),
+                size,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            })
+        };
+        let feature_size = pixels * mem::size_of::<Feature>() as u64;
+        let color_size = pixels * mem::size_of::<[f32;4]>() as u64;
+        (
+            buffer("Feature Buffer", feature_size),
+            buffer("Previous Feature Buffer", feature_size),
+            buffer("Denoise History Buffer", color_size),
+            buffer("Denoise Ping Buffer", color_size),
+            buffer("Denoise Pong Buffer", color_size),
+        )
+    }
+
+    //The ray tracer's bind group has to be rebuilt afterwards, it writes into the new feature buffer
+    pub fn resize(&mut self, device: &wgpu::Device, texture: &Texture, width: u32, height: u32){
+        (self.features_buffer, self.prev_features_buffer, self.history_buffer, self.ping_buffer, self.pong_buffer) = Self::pixel_buffers(device, width, height);
+        self.output = Texture::new(device, width, height, wgpu::TextureFormat::Rgba32Float);
+        self.update_bind_group(device, texture);
+        self.reset = true;
+    }
+
+    //Buffers in binding order, skipping the textures
+    fn create_bind_group(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, buffers: [&wgpu::Buffer; 7], texture: &Texture, output: &Texture) -> wgpu::BindGroup{
+        let [uniform_buffer, prev_camera_buffer, features_buffer, prev_features_buffer, history_buffer, ping_buffer, pong_buffer] = buffers;
+        device.create_bind_group(&wgpu::BindGroupDescriptor{
+            label: Some("Denoise Bind Group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: prev_camera_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: texture.binding_resource(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: features_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: prev_features_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: history_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: ping_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: pong_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: output.binding_resource(),
+                },
+            ],
+        })
+    }
+
+    pub fn update_bind_group(&mut self, device: &wgpu::Device, texture: &Texture){
+        self.bind_group = Self::create_bind_group(device, &self.bind_group_layout, [&self.uniform_buffer, &self.prev_camera_buffer, &self.features_buffer, &self.prev_features_buffer, &self.history_buffer, &self.ping_buffer, &self.pong_buffer], texture, &self.output);
+    }
+
+    //Like Restir::update_camera, the camera the history was rendered with is written before storing this one
+    pub fn update(&mut self, queue: &wgpu::Queue, camera: CameraUniform, width: u32, height: u32, frames: i32){
+        queue.write_buffer(&self.prev_camera_buffer, 0, bytemuck::bytes_of(&self.prev_camera));
+        self.prev_camera = camera;
+        let uniform = DenoiseUniform{
+            width,
+            height,
+            frames,
+            temporal: self.temporal as i32,
+            strength: self.strength,
+            max_history: self.max_history.max(1) as f32,
+            _padding: [0.0;2],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniform));
+        let iterations = self.iterations.clamp(1, MAX_ITERATIONS);
+        for i in 0..iterations{
+            let iteration = Iteration{
+                step: 1 << i,
+                source: i % 2,
+                last: (i == iterations - 1) as i32,
+                _padding: 0,
+            };
+            queue.write_buffer(&self.iteration_buffer, i as u64 * self.iteration_stride, bytemuck::bytes_of(&iteration));
+        }
+    }
+
+    pub fn dispatch(&mut self, encoder: &mut wgpu::CommandEncoder, width: u32, height: u32){
+        if !self.enabled{
+            return;
+        }
+        if self.reset{
+            encoder.clear_buffer(&self.history_buffer, 0, None);
+            encoder.clear_buffer(&self.prev_features_buffer, 0, None);
+            self.reset = false;
+        }
+        let xgroups = width.div_ceil(WORKGROUP_SIZE.0);
+        let ygroups = height.div_ceil(WORKGROUP_SIZE.1);
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor{
+                label: Some("Denoise Temporal Pass"),
+            });
+            compute_pass.set_bind_group(0, &self.bind_group, &[]);
+            compute_pass.set_bind_group(1, &self.iteration_bind_group, &[0]);
+            compute_pass.set_pipeline(&self.temporal_pipeline);
+            compute_pass.dispatch_workgroups(xgroups, ygroups, 1);
+        }
+        //The unfiltered lighting is what gets reprojected next frame
+        encoder.copy_buffer_to_buffer(&self.ping_buffer, 0, &self.history_buffer, 0, self.history_buffer.size());
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor{
+                label: Some("Denoise A-Trous Pass"),
+            });
+            compute_pass.set_bind_group(0, &self.bind_group, &[]);
+            compute_pass.set_pipeline(&self.atrous_pipeline);
+            for i in 0..self.iterations.clamp(1, MAX_ITERATIONS){
+                compute_pass.set_bind_group(1, &self.iteration_bind_group, &[(i as u64 * self.iteration_stride) as u32]);
+                compute_pass.dispatch_workgroups(xgroups, ygroups, 1);
+            }
+        }
+        encoder.copy_buffer_to_buffer(&self.features_buffer, 0, &self.prev_features_buffer, 0, self.features_buffer.size());
+    }
+
+    //What the display pass and exports should read
+    pub fn image<'a>(&'a self, texture: &'a Texture) -> &'a Texture{
+        if self.enabled {&self.output} else {texture}
+    }
+}
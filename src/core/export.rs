@@ -0,0 +1,100 @@
+use std::{io::Write, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+pub const EXPORT_PNG: i32 = 0;
+pub const EXPORT_EXR: i32 = 1;
+pub const EXPORT_PFM: i32 = 2;
+pub const EXPORT_FORMAT_NAMES: [&str; 3] = ["PNG (8 bit)", "EXR (linear)", "PFM (linear)"];
+
+//Unused name in directory like room_1024spp_1700000000.exr
+pub fn render_path(directory: &str, scene: &str, samples: i32, format: i32) -> PathBuf{
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let extension = match format{
+        EXPORT_EXR => "exr",
+        EXPORT_PFM => "pfm",
+        _ => "png",
+    };
+    let name = format!("{}_{}spp_{}", scene, samples, time);
+    let mut path = Path::new(directory).join(format!("{}.{}", name, extension));
+    let mut copy = 1;
+    while path.exists(){
+        path = Path::new(directory).join(format!("{}_{}.{}", name, copy, extension));
+        copy += 1;
+    }
+    path
+}
+
+//Picks the format from the extension
+pub fn save(path: &Path, width: u32, height: u32, pixels: &[f32]) -> anyhow::Result<()>{
+    match path.extension().and_then(|e| e.to_str()){
+        Some("exr") => save_exr(path, width, height, pixels),
+        Some("pfm") => save_pfm(path, width, height, pixels),
+        _ => save_png(path, width, height, pixels),
+    }
+}
+
+//Linear rgba floats, as read back from the accumulation texture, to an 8 bit srgb png
+pub fn save_png(path: &Path, width: u32, height: u32, pixels: &[f32]) -> anyhow::Result<()>{
+    create_parent(path)?;
+    let bytes: Vec<u8> = pixels.chunks(4).flat_map(|p|{
+        [to_srgb(p[0]), to_srgb(p[1]), to_srgb(p[2]), 255]
+    }).collect();
+    image::save_buffer(path, &bytes, width, height, image::ColorType::Rgba8)?;
+    Ok(())
+}
+
+//Unclamped linear radiance, alpha is forced to 1
+pub fn save_exr(path: &Path, width: u32, height: u32, pixels: &[f32]) -> anyhow::Result<()>{
+    create_parent(path)?;
+    let rgba: Vec<f32> = pixels.chunks(4).flat_map(|p| [p[0], p[1], p[2], 1.0]).collect();
+    image::save_buffer_with_format(path, bytemuck::cast_slice(&rgba), width, height, image::ColorType::Rgba32F, image::ImageFormat::OpenExr)?;
+    Ok(())
+}
+
+//Multi-layer exr for compositing, the image is the unnamed rgba layer and every AOV gets a layer of
+//its own with name.R, name.G, name.B and name.A channels
+pub fn save_exr_layers(path: &Path, width: u32, height: u32, pixels: &[f32], aovs: &[(&str, Vec<f32>)]) -> anyhow::Result<()>{
+    use exr::prelude::*;
+    create_parent(path)?;
+    let layer = |attributes: LayerAttributes, pixels: &[f32]|{
+        let channels = ["R", "G", "B", "A"].iter().enumerate().map(|(c, name)|{
+            AnyChannel::new(*name, FlatSamples::F32(pixels.iter().skip(c).step_by(4).copied().collect()))
+        }).collect();
+        Layer::new((width as usize, height as usize), attributes, Encoding::SMALL_LOSSLESS, AnyChannels::sort(SmallVec::from_vec(channels)))
+    };
+    let rgba: Vec<f32> = pixels.chunks(4).flat_map(|p| [p[0], p[1], p[2], 1.0]).collect();
+    let mut layers = vec![layer(LayerAttributes::default(), &rgba)];
+    layers.extend(aovs.iter().map(|(name, pixels)| layer(LayerAttributes::named(*name), pixels)));
+    Image::from_layers(ImageAttributes::new(IntegerBounds::from_dimensions((width as usize, height as usize))), layers)
+        .write()
+        .to_file(path)?;
+    Ok(())
+}
+
+//Portable float map, little endian rgb with the bottom row first
+pub fn save_pfm(path: &Path, width: u32, height: u32, pixels: &[f32]) -> anyhow::Result<()>{
+    create_parent(path)?;
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    write!(file, "PF\n{} {}\n-1.0\n", width, height)?;
+    for row in pixels.chunks(width as usize * 4).rev(){
+        for p in row.chunks(4){
+            for c in &p[..3]{
+                file.write_all(&c.to_le_bytes())?;
+            }
+        }
+    }
+    file.flush()?;
+    Ok(())
+}
+
+fn create_parent(path: &Path) -> anyhow::Result<()>{
+    if let Some(parent) = path.parent(){
+        std::fs::create_dir_all(parent)?;
+    }
+    Ok(())
+}
+
+fn to_srgb(linear: f32) -> u8{
+    let c = linear.clamp(0.0, 1.0);
+    let srgb = if c <= 0.0031308 {12.92 * c} else {1.055 * c.powf(1.0 / 2.4) - 0.055};
+    (srgb * 255.0).round() as u8
+}
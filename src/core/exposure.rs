@@ -0,0 +1,246 @@
+use wgpu::util::DeviceExt;
+
+use glam::Vec3;
+
+use super::{texture::Texture, camera::Camera, tonemap::{tone_map, TONEMAP_NONE}};
+
+pub const EXPOSURE_NONE: i32 = 0;
+pub const EXPOSURE_PHYSICAL: i32 = 1;
+pub const EXPOSURE_AUTO: i32 = 2;
+pub const EXPOSURE_NAMES: [&str; 3] = ["None", "Physical camera", "Auto"];
+//Has to match auto_exposure.wgsl
+const HISTOGRAM_BINS: u64 = 64;
+const WORKGROUP_SIZE: (u32, u32) = (16, 16);
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct ExposureUniform{
+    pub mode: i32,
+    //Scale applied to the image when not in auto mode
+    pub exposure: f32,
+    pub compensation: f32,
+    //How far the adapted luminance moves towards the measured one this frame
+    pub adaptation: f32,
+    pub min_log_luminance: f32,
+    pub log_luminance_range: f32,
+    pub low_percentile: f32,
+    pub high_percentile: f32,
+    pub tone_map: i32,
+    pub white_point: f32,
+    pub _padding: [f32;2],
+}
+
+//Scales the accumulated image before display, either from the camera's ISO, shutter and f-stop
+//or from a log luminance histogram of the image itself, then tone maps it
+pub struct Exposure{
+    pub uniform_buffer: wgpu::Buffer,
+    pub histogram_buffer: wgpu::Buffer,
+    //Adapted average luminance, written by the auto exposure pass and read by the display pass
+    pub luminance_buffer: wgpu::Buffer,
+    pub histogram_pipeline: wgpu::ComputePipeline,
+    pub average_pipeline: wgpu::ComputePipeline,
+    pub bind_group: wgpu::BindGroup,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub mode: i32,
+    //In stops
+    pub compensation: f32,
+    pub speed: f32,
+    pub low_percentile: f32,
+    pub high_percentile: f32,
+    pub tone_map: i32,
+    pub white_point: f32,
+}
+
+impl Exposure{
+    pub fn new(device: &wgpu::Device, texture: &Texture) -> Self{
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor{
+            label: Some("Auto Exposure Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("../../shaders/auto_exposure.wgsl").into())
+        });
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor{
+            label: Some("Exposure Buffer"),
+            contents: bytemuck::bytes_of(&ExposureUniform::default()),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let histogram_buffer = device.create_buffer(&wgpu::BufferDescriptor{
+            label: Some("Luminance Histogram Buffer"),
+            size: HISTOGRAM_BINS * std::mem::size_of::<u32>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let luminance_buffer = device.create_buffer(&wgpu::BufferDescriptor{
+            label: Some("Average Luminance Buffer"),
+            size: std::mem::size_of::<f32>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let storage = |binding, read_only|{
+            wgpu::BindGroupLayoutEntry {
+                binding,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer{
+                    ty: wgpu::BufferBindingType::Storage { read_only },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor{
+            label: Some("Auto Exposure Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry{
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: texture.binding_type(wgpu::StorageTextureAccess::ReadWrite),
+                    count: None,
+                },
+                storage(2, false),
+                storage(3, false),
+            ],
+        });
+        let bind_group = Self::create_bind_group(device, &bind_group_layout, &uniform_buffer, &histogram_buffer, &luminance_buffer, texture);
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor{
+            label: Some("Auto Exposure Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = |entry_point|{
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&layout),
+                module: &shader,
+                entry_point,
+            })
+        };
+        Self{
+            histogram_pipeline: pipeline("build_histogram"),
+            average_pipeline: pipeline("average_luminance"),
+            uniform_buffer,
+            histogram_buffer,
+            luminance_buffer,
+            bind_group,
+            bind_group_layout,
+            mode: EXPOSURE_NONE,
+            compensation: 0.0,
+            speed: 2.0,
+            low_percentile: 0.5,
+            high_percentile: 0.95,
+            tone_map: TONEMAP_NONE,
+            white_point: 4.0,
+        }
+    }
+
+    fn create_bind_group(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, uniform_buffer: &wgpu::Buffer, histogram_buffer: &wgpu::Buffer, luminance_buffer: &wgpu::Buffer, texture: &Texture) -> wgpu::BindGroup{
+        device.create_bind_group(&wgpu::BindGroupDescriptor{
+            label: Some("Auto Exposure Bind Group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: texture.binding_resource(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: histogram_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: luminance_buffer.as_entire_binding(),
+                },
+            ],
+        })
+    }
+
+    pub fn update_bind_group(&mut self, device: &wgpu::Device, texture: &Texture){
+        self.bind_group = Self::create_bind_group(device, &self.bind_group_layout, &self.uniform_buffer, &self.histogram_buffer, &self.luminance_buffer, texture);
+    }
+
+    //Scale outside of auto mode
+    fn fixed_scale(&self, camera: &Camera) -> f32{
+        let exposure = match self.mode{
+            //Lagarde & de Rousiers 2014, luminance that saturates the sensor is 1.2 * 2^EV100
+            EXPOSURE_PHYSICAL => 1.0 / (1.2 * f32::exp2(camera.ev100())),
+            _ => 1.0,
+        };
+        exposure * f32::exp2(self.compensation)
+    }
+
+    pub fn update(&self, queue: &wgpu::Queue, camera: &Camera, dt: f32){
+        let uniform = ExposureUniform{
+            mode: self.mode,
+            exposure: self.fixed_scale(camera),
+            compensation: self.compensation,
+            adaptation: 1.0 - f32::exp(-dt * self.speed),
+            min_log_luminance: -10.0,
+            log_luminance_range: 20.0,
+            low_percentile: self.low_percentile,
+            high_percentile: self.high_percentile.max(self.low_percentile),
+            tone_map: self.tone_map,
+            white_point: self.white_point,
+            _padding: [0.0;2],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&uniform));
+    }
+
+    //The scale the display pass is currently using, auto mode reads the adapted luminance back
+    pub fn scale(&self, device: &wgpu::Device, queue: &wgpu::Queue, camera: &Camera) -> f32{
+        if self.mode != EXPOSURE_AUTO{
+            return self.fixed_scale(camera);
+        }
+        let size = std::mem::size_of::<f32>() as u64;
+        let staging = device.create_buffer(&wgpu::BufferDescriptor{
+            label: Some("Luminance Readback Buffer"),
+            size,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor{
+            label: Some("Luminance Readback Encoder")
+        });
+        encoder.copy_buffer_to_buffer(&self.luminance_buffer, 0, &staging, 0, size);
+        queue.submit(std::iter::once(encoder.finish()));
+        let slice = staging.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |_| ());
+        device.poll(wgpu::Maintain::Wait);
+        let luminance = bytemuck::cast_slice::<u8, f32>(&slice.get_mapped_range())[0];
+        if luminance > 0.0 {0.18 / luminance * f32::exp2(self.compensation)} else {1.0}
+    }
+
+    //Cpu copy of the display pass for exports, rgba in place
+    pub fn apply(&self, pixels: &mut [f32], scale: f32){
+        for p in pixels.chunks_mut(4){
+            let color = tone_map(self.tone_map, Vec3::new(p[0], p[1], p[2]) * scale, self.white_point);
+            p[..3].copy_from_slice(&color.to_array());
+        }
+    }
+
+    pub fn dispatch(&self, encoder: &mut wgpu::CommandEncoder, width: u32, height: u32){
+        if self.mode != EXPOSURE_AUTO{
+            return;
+        }
+        encoder.clear_buffer(&self.histogram_buffer, 0, None);
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor{
+            label: Some("Auto Exposure Pass"),
+        });
+        compute_pass.set_bind_group(0, &self.bind_group, &[]);
+        compute_pass.set_pipeline(&self.histogram_pipeline);
+        compute_pass.dispatch_workgroups(width.div_ceil(WORKGROUP_SIZE.0), height.div_ceil(WORKGROUP_SIZE.1), 1);
+        compute_pass.set_pipeline(&self.average_pipeline);
+        compute_pass.dispatch_workgroups(1, 1, 1);
+    }
+}
@@ -0,0 +1,239 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context as _};
+use wgpu::util::DeviceExt;
+
+use super::{context::Params, texture::Texture, scene::{Scene, SCENE_NAMES}, ray_tracer::{RayTracer, Resources}, photon_map::PhotonMap, restir::Restir, denoiser::Denoiser, aov::Aovs, adaptive::Adaptive, bdpt::Bdpt, scene_file::SceneFile, exposure::Exposure, tonemap::{TONEMAP_NONE, TONEMAP_REINHARD, TONEMAP_ACES, TONEMAP_AGX, TONEMAP_UNCHARTED2}, integrator::Integrator, sampler, export::{save, save_exr_layers}};
+
+//Submissions queued before waiting on the gpu, keeps drivers from timing out on long renders
+const FRAMES_IN_FLIGHT: i32 = 16;
+
+//ray_tracer render <scene.ron|name|model> [--spp N] [--size WxH] [--denoise] [--aovs] [--threshold X] [--ev X] [--tonemap NAME] [-o PATH],
+//the extension of PATH picks png, exr or pfm. --aovs adds every AOV as a layer of the exr. --threshold turns on adaptive sampling,
+//--spp is then the most a pixel gets and the render stops early once every pixel is under the threshold. Pngs are exposed by
+//--ev stops and tone mapped with none, reinhard, aces, agx or uncharted2 like the display pass, exr and pfm stay linear
+pub struct RenderArgs{
+    //A .ron scene file (see SceneFile), a name from SCENE_NAMES, or a model in the assets folder rendered in an empty scene
+    pub scene: String,
+    pub spp: i32,
+    pub width: u32,
+    pub height: u32,
+    pub output: PathBuf,
+    pub denoise: bool,
+    pub aovs: bool,
+    //Noise threshold for adaptive sampling
+    pub threshold: Option<f32>,
+    //Exposure compensation and tone mapping operator for pngs
+    pub ev: f32,
+    pub tone_map: i32,
+}
+
+impl RenderArgs{
+    pub fn parse(args: &[String]) -> anyhow::Result<Self>{
+        let mut render = Self{
+            scene: String::new(),
+            spp: 256,
+            width: 800,
+            height: 800,
+            output: PathBuf::from("render.png"),
+            denoise: false,
+            aovs: false,
+            threshold: None,
+            ev: 0.0,
+            tone_map: TONEMAP_NONE,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next(){
+            let mut value = || args.next().ok_or_else(|| anyhow!("Missing value for {}", arg));
+            match arg.as_str(){
+                "--spp" => render.spp = value()?.parse().context("Invalid --spp")?,
+                "--size" => {
+                    let size = value()?;
+                    let (width, height) = size.split_once('x').ok_or_else(|| anyhow!("Size should look like 1920x1080, got {}", size))?;
+                    render.width = width.parse().context("Invalid width")?;
+                    render.height = height.parse().context("Invalid height")?;
+                }
+                "-o" | "--output" => render.output = PathBuf::from(value()?),
+                "--denoise" => render.denoise = true,
+                "--aovs" => render.aovs = true,
+                "--threshold" => render.threshold = Some(value()?.parse().context("Invalid --threshold")?),
+                "--ev" => render.ev = value()?.parse().context("Invalid --ev")?,
+                "--tonemap" => render.tone_map = match value()?.to_lowercase().as_str(){
+                    "none" => TONEMAP_NONE,
+                    "reinhard" => TONEMAP_REINHARD,
+                    "aces" => TONEMAP_ACES,
+                    "agx" => TONEMAP_AGX,
+                    "uncharted2" => TONEMAP_UNCHARTED2,
+                    operator => return Err(anyhow!("Unknown tone mapping operator {}, use none, reinhard, aces, agx or uncharted2", operator)),
+                },
+                _ if arg.starts_with('-') => return Err(anyhow!("Unknown option {}", arg)),
+                _ => render.scene = arg.clone(),
+            }
+        }
+        if render.scene.is_empty(){
+            return Err(anyhow!("Usage: ray_tracer render <scene.ron|{}|model> [--spp N] [--size WxH] [--denoise] [--aovs] [--threshold X] [--ev X] [--tonemap NAME] [-o out.png|exr|pfm]", SCENE_NAMES.join("|")));
+        }
+        if render.spp < 1 || render.width == 0 || render.height == 0{
+            return Err(anyhow!("Samples and size have to be positive"));
+        }
+        if render.threshold.is_some_and(|threshold| threshold <= 0.0){
+            return Err(anyhow!("The noise threshold has to be positive"));
+        }
+        if render.aovs && render.output.extension().and_then(|e| e.to_str()) != Some("exr"){
+            return Err(anyhow!("AOVs can only be saved to an exr"));
+        }
+        Ok(render)
+    }
+}
+
+//Path traces without a window or surface. Any Vulkan, Metal or DX12 adapter works, software Vulkan included,
+//GL doesn't, see below
+pub async fn render(args: RenderArgs) -> anyhow::Result<()>{
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor{
+        backends: wgpu::util::backend_bits_from_env().unwrap_or(wgpu::Backends::PRIMARY),
+        dx12_shader_compiler: Default::default(),
+    });
+    //The accumulation texture is read and written in place, which GL can't do for Rgba32Float, so GL
+    //adapters are filtered out and WGPU_BACKEND only narrows down the rest. Software Vulkan (lavapipe,
+    //SwiftShader) works. Real gpus are preferred over software ones
+    let adapter = instance.enumerate_adapters(wgpu::Backends::all())
+        .filter(|adapter| adapter.get_texture_format_features(wgpu::TextureFormat::Rgba32Float).flags
+            .contains(wgpu::TextureFormatFeatureFlags::STORAGE_READ_WRITE))
+        .min_by_key(|adapter| match adapter.get_info().device_type{
+            wgpu::DeviceType::DiscreteGpu => 0,
+            wgpu::DeviceType::IntegratedGpu => 1,
+            wgpu::DeviceType::VirtualGpu => 2,
+            wgpu::DeviceType::Cpu => 3,
+            wgpu::DeviceType::Other => 4,
+        })
+        .ok_or_else(|| anyhow!("No adapter supports read-write Rgba32Float storage textures, GL adapters never do"))?;
+    log::info!("Rendering on {:?}", adapter.get_info());
+
+    let (device, queue) = adapter
+        .request_device(&wgpu::DeviceDescriptor{
+            features: adapter.features() & wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES,
+            limits: adapter.limits(),
+            ..Default::default()
+        }, None)
+        .await?;
+
+    //Scenes only use the configuration for their aspect ratio
+    let config = wgpu::SurfaceConfiguration{
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        format: wgpu::TextureFormat::Rgba8UnormSrgb,
+        width: args.width,
+        height: args.height,
+        present_mode: wgpu::PresentMode::Fifo,
+        alpha_mode: wgpu::CompositeAlphaMode::Auto,
+        view_formats: vec![],
+    };
+    let mut scene = match SCENE_NAMES.iter().position(|name| *name == args.scene){
+        Some(id) => Scene::from_id(id as i32, &device, &config).unwrap(),
+        None if Path::new(&args.scene).extension() == Some("ron".as_ref()) => {
+            SceneFile::load(Path::new(&args.scene))?.build(&device, &config)?
+        }
+        None => {
+            let mut scene = Scene::new(&device, &config);
+            scene.load_model(&args.scene)?;
+            scene.frame(None);
+            scene
+        }
+    };
+    scene.animate(0.0, 0.0);
+
+    let photon_map = PhotonMap::new(&device);
+    let mut restir = Restir::new(&device, args.width, args.height);
+    let mut params = Params::new(args.width, args.height, &photon_map, &restir);
+    params.features = args.denoise as i32;
+    let mut aovs = Aovs::new(&device, args.width, args.height);
+    //Headless renders always path trace, which fills every AOV
+    let integrator = Integrator::PathTracer;
+    if args.aovs{
+        aovs.set_mask(&device, integrator.aov_mask());
+    }
+    params.aovs = aovs.mask;
+    let mut adaptive = Adaptive::new(&device, args.width, args.height);
+    if let Some(threshold) = args.threshold{
+        adaptive.enabled = true;
+        adaptive.threshold = threshold;
+        adaptive.min_frames = adaptive.min_frames.min(args.spp);
+    }
+    params.adaptive = adaptive.enabled as i32;
+    params.adaptive_threshold = adaptive.threshold;
+    params.adaptive_min_frames = adaptive.min_frames;
+    let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("parameters buffer"),
+        contents: bytemuck::bytes_of(&params),
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+    });
+    let blue_noise_buffer = sampler::blue_noise_buffer(&device);
+    let bdpt = Bdpt::new(&device, args.width, args.height);
+    let texture = Texture::new(&device, args.width, args.height, wgpu::TextureFormat::Rgba32Float);
+    let mut denoiser = Denoiser::new(&device, &texture, args.width, args.height);
+    denoiser.enabled = args.denoise;
+    let ray_tracer = RayTracer::new(&device, &Resources{
+        texture: &texture,
+        params_buffer: &params_buffer,
+        blue_noise_buffer: &blue_noise_buffer,
+        photon_map: &photon_map,
+        restir: &restir,
+        denoiser: &denoiser,
+        aovs: &aovs,
+        adaptive: &adaptive,
+        bdpt: &bdpt,
+    }, &scene);
+    let uniform = scene.camera.to_uniform();
+    queue.write_buffer(&scene.camera.buffer, 0, bytemuck::cast_slice(&[uniform]));
+
+    log::info!("Rendering {} at {}x{} with {} samples", args.scene, args.width, args.height, args.spp);
+    let mut frames = args.spp;
+    for frame in 0..args.spp{
+        params.frames = frame;
+        queue.write_buffer(&params_buffer, 0, bytemuck::cast_slice(&[params]));
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor{
+            label: Some("Command Encoder")
+        });
+        ray_tracer.dispatch(&mut encoder, integrator, &photon_map, &mut restir, &adaptive, &bdpt);
+        let wait = (frame + 1) % FRAMES_IN_FLIGHT == 0;
+        if wait{
+            adaptive.readback(&mut encoder);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+        adaptive.map();
+        if wait{
+            device.poll(wgpu::Maintain::Wait);
+            if adaptive.poll(&device){
+                log::info!("{}/{} samples, {:.1}% converged", frame + 1, args.spp, adaptive.progress() * 100.0);
+            }else{
+                log::info!("{}/{} samples", frame + 1, args.spp);
+            }
+            if adaptive.done(){
+                frames = frame + 1;
+                break;
+            }
+        }
+    }
+
+    //Only the finished image needs denoising
+    denoiser.update(&queue, uniform, args.width, args.height, frames - 1);
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor{
+        label: Some("Denoise Encoder")
+    });
+    denoiser.dispatch(&mut encoder, args.width, args.height);
+    queue.submit(std::iter::once(encoder.finish()));
+    let mut pixels = denoiser.image(&texture).read(&device, &queue);
+    //Same as saving from the window, hdr formats stay linear and unexposed
+    if !matches!(args.output.extension().and_then(|e| e.to_str()), Some("exr" | "pfm")){
+        let mut exposure = Exposure::new(&device, &texture);
+        exposure.compensation = args.ev;
+        exposure.tone_map = args.tone_map;
+        exposure.apply(&mut pixels, exposure.scale(&device, &queue, &scene.camera));
+    }
+    if args.aovs{
+        save_exr_layers(&args.output, args.width, args.height, &pixels, &aovs.read(&device, &queue))?;
+    }else{
+        save(&args.output, args.width, args.height, &pixels)?;
+    }
+    log::info!("Saved {}", args.output.display());
+    Ok(())
+}
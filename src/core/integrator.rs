@@ -0,0 +1,63 @@
+use super::aov::{AOV_COUNT, AOV_LIGHTING};
+
+//Views of the Debug integrator, same order as the DEBUG_* constants in ray_tracer.wgsl
+pub const DEBUG_MODE_NAMES: [&str; 8] = [
+    "Shading normals",
+    "Geometric normals",
+    "Depth",
+    "Barycentrics",
+    "Object id",
+    "Material id",
+    "Path length",
+    "Intersection tests",
+];
+
+//Each integrator gets its own build of ray_tracer.wgsl and its own pipeline, its discriminant is
+//what INTEGRATOR is set to
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Integrator{
+    PathTracer,
+    Bidirectional,
+    PhotonMapping,
+    Restir,
+    DirectLighting,
+    AmbientOcclusion,
+    Whitted,
+    Debug,
+}
+
+impl Integrator{
+    pub const ALL: [Integrator; 8] = [
+        Integrator::PathTracer,
+        Integrator::Bidirectional,
+        Integrator::PhotonMapping,
+        Integrator::Restir,
+        Integrator::DirectLighting,
+        Integrator::AmbientOcclusion,
+        Integrator::Whitted,
+        Integrator::Debug,
+    ];
+
+    pub fn name(&self) -> &'static str{
+        match self{
+            Integrator::PathTracer => "Path tracer",
+            Integrator::Bidirectional => "Bidirectional",
+            Integrator::PhotonMapping => "Photon mapping",
+            Integrator::Restir => "ReSTIR",
+            Integrator::DirectLighting => "Direct lighting",
+            Integrator::AmbientOcclusion => "Ambient occlusion",
+            Integrator::Whitted => "Whitted",
+            Integrator::Debug => "Debug",
+        }
+    }
+
+    //AOVs this integrator fills, geometry comes from the primary hit so every integrator has it. ReSTIR's
+    //bounces go through trace_path but its direct light doesn't, so it leaves the lighting AOVs out too
+    pub fn aov_mask(&self) -> u32{
+        let all = (1 << AOV_COUNT) - 1;
+        match self{
+            Integrator::PathTracer | Integrator::PhotonMapping => all,
+            _ => all & !AOV_LIGHTING,
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::f32::consts::PI;
+
+use glam::{Quat, Vec3};
+use wgpu::util::DeviceExt;
+
+use super::scene::{Mesh, Sphere, Vertex};
+
+pub const LIGHT_SPHERE: u32 = 0;
+pub const LIGHT_TRIANGLE: u32 = 1;
+pub const NO_LIGHT: u32 = u32::MAX;
+
+//Indexed by the LIGHT_SAMPLING_* ids in ray_tracer.wgsl
+pub const LIGHT_SAMPLING_BVH: i32 = 2;
+pub const LIGHT_SAMPLING_NAMES: [&str; 3] = ["None (BSDF only)", "Uniform", "Light BVH"];
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct Light{
+    pub kind: u32,
+    //Sphere index, or mesh index for triangles
+    pub index: u32,
+    pub triangle: u32,
+    //Branches taken from the root to reach this light's leaf, bit n is level n, 1 = second child
+    pub bit_trail: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct LightNode{
+    pub bounds_min: [f32;3],
+    pub power: f32,
+    pub bounds_max: [f32;3],
+    pub cos_theta_o: f32,
+    pub axis: [f32;3],
+    pub cos_theta_e: f32,
+    //The first child always directly follows its parent
+    pub second_child: u32,
+    pub light: u32,
+    pub leaf: u32,
+    pub _padding: u32,
+}
+
+//Bounds, power and orientation cone (Conty & Kulla 2018) of a single emitter or a cluster of them
+#[derive(Debug, Copy, Clone)]
+struct LightBounds{
+    min: Vec3,
+    max: Vec3,
+    power: f32,
+    axis: Vec3,
+    theta_o: f32,
+    theta_e: f32,
+}
+
+impl LightBounds{
+    fn centroid(&self) -> Vec3{
+        (self.min + self.max) * 0.5
+    }
+
+    fn union(&self, other: &LightBounds) -> LightBounds{
+        if self.power <= 0.0{
+            return *other;
+        }
+        if other.power <= 0.0{
+            return *self;
+        }
+        let (axis, theta_o) = union_cone((self.axis, self.theta_o), (other.axis, other.theta_o));
+        LightBounds{
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+            power: self.power + other.power,
+            axis,
+            theta_o,
+            theta_e: self.theta_e.max(other.theta_e),
+        }
+    }
+
+    fn to_node(self) -> LightNode{
+        LightNode{
+            bounds_min: self.min.to_array(),
+            power: self.power,
+            bounds_max: self.max.to_array(),
+            cos_theta_o: self.theta_o.cos(),
+            axis: self.axis.to_array(),
+            cos_theta_e: self.theta_e.cos(),
+            ..Default::default()
+        }
+    }
+}
+
+fn union_cone(a: (Vec3, f32), b: (Vec3, f32)) -> (Vec3, f32){
+    let (a, b) = if b.1 > a.1 {(b, a)} else {(a, b)};
+    let theta_d = a.0.dot(b.0).clamp(-1.0, 1.0).acos();
+    if (theta_d + b.1).min(PI) <= a.1{
+        return a;
+    }
+    let theta_o = (a.1 + theta_d + b.1) * 0.5;
+    if theta_o >= PI{
+        return (a.0, PI);
+    }
+    let rotation_axis = a.0.cross(b.0);
+    if rotation_axis.length_squared() < 1e-8{
+        return (a.0, PI);
+    }
+    let axis = Quat::from_axis_angle(rotation_axis.normalize(), theta_o - a.1) * a.0;
+    (axis.normalize(), theta_o)
+}
+
+fn luminance(color: [f32;4]) -> f32{
+    0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2]
+}
+
+pub struct LightBvh{
+    pub lights: Vec<Light>,
+    pub nodes: Vec<LightNode>,
+}
+
+impl LightBvh{
+    //Collects every emissive sphere and triangle and links them back to their light index
+    pub fn build(spheres: &mut [Sphere], meshes: &mut [Mesh], vertices: &[Vertex], indices: &[u32]) -> Self{
+        let mut lights = vec![];
+        let mut bounds = vec![];
+
+        for (i, sphere) in spheres.iter_mut().enumerate(){
+            sphere.light = NO_LIGHT;
+            let radiance = luminance(sphere.emission_color) * sphere.emission_strength;
+            if radiance <= 0.0{
+                continue;
+            }
+            sphere.light = lights.len() as u32;
+            //Moving lights are bounded over the whole shutter interval
+            let start = Vec3::from(sphere.position);
+            let end = start + Vec3::from(sphere.motion);
+            let area = 4.0 * PI * sphere.radius * sphere.radius;
+            lights.push(Light{kind: LIGHT_SPHERE, index: i as u32, triangle: 0, bit_trail: 0});
+            bounds.push(LightBounds{
+                min: start.min(end) - Vec3::splat(sphere.radius),
+                max: start.max(end) + Vec3::splat(sphere.radius),
+                power: radiance * area * PI,
+                axis: Vec3::Y,
+                theta_o: PI,
+                theta_e: PI / 2.0,
+            });
+        }
+
+        for (i, mesh) in meshes.iter_mut().enumerate(){
+            mesh.light = NO_LIGHT;
+            let radiance = luminance(mesh.emission_color) * mesh.emission_strength;
+            if radiance <= 0.0 || mesh.triangles == 0{
+                continue;
+            }
+            mesh.light = lights.len() as u32;
+            for t in 0..mesh.triangles{
+                let corner = |k: u32|{
+                    let index = indices[(mesh.first + t * 3 + k) as usize];
+                    Vec3::from(vertices[(mesh.offset + index) as usize].pos) + Vec3::from(mesh.pos)
+                };
+                let (a, b, c) = (corner(0), corner(1), corner(2));
+                let normal = (b - a).cross(c - a);
+                let area = normal.length() * 0.5;
+                lights.push(Light{kind: LIGHT_TRIANGLE, index: i as u32, triangle: t, bit_trail: 0});
+                let motion = Vec3::from(mesh.motion);
+                let (mut min, mut max) = (a.min(b).min(c), a.max(b).max(c));
+                //A spinning triangle stays within the sphere around pos its corners sweep, and its normal
+                //within the angle it turns by
+                let spin = Vec3::from(mesh.spin).length().min(PI);
+                if spin > 0.0{
+                    let pos = Vec3::from(mesh.pos);
+                    let radius = (a - pos).length().max((b - pos).length()).max((c - pos).length());
+                    (min, max) = (pos - Vec3::splat(radius), pos + Vec3::splat(radius));
+                }
+                bounds.push(LightBounds{
+                    min: min.min(min + motion),
+                    max: max.max(max + motion),
+                    power: radiance * area * PI,
+                    axis: normal.normalize_or_zero(),
+                    theta_o: spin,
+                    theta_e: PI / 2.0,
+                });
+            }
+        }
+
+        if lights.is_empty(){
+            //Buffers can't be empty, the shader skips light sampling when the root has no power
+            return Self{
+                lights: vec![Light::default()],
+                nodes: vec![LightNode{leaf: 1, ..Default::default()}],
+            };
+        }
+
+        let mut order: Vec<usize> = (0..lights.len()).collect();
+        let mut nodes = vec![];
+        build_recursive(&bounds, &mut order, &mut lights, &mut nodes, 0, 0);
+        Self{
+            lights,
+            nodes,
+        }
+    }
+
+    pub fn light_buffer(&self, device: &wgpu::Device) -> wgpu::Buffer{
+        device.create_buffer_init(&wgpu::util::BufferInitDescriptor{
+            label: Some("Light Buffer"),
+            contents: bytemuck::cast_slice(&self.lights),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+        })
+    }
+
+    pub fn node_buffer(&self, device: &wgpu::Device) -> wgpu::Buffer{
+        device.create_buffer_init(&wgpu::util::BufferInitDescriptor{
+            label: Some("Light Node Buffer"),
+            contents: bytemuck::cast_slice(&self.nodes),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::STORAGE,
+        })
+    }
+}
+
+//Splits at the centroid median of the widest axis, nodes are stored depth first
+fn build_recursive(bounds: &[LightBounds], order: &mut [usize], lights: &mut [Light], nodes: &mut Vec<LightNode>, depth: u32, bit_trail: u32) -> LightBounds{
+    let node_index = nodes.len();
+    nodes.push(LightNode::default());
+
+    if order.len() == 1{
+        let light = order[0];
+        lights[light].bit_trail = bit_trail;
+        nodes[node_index] = LightNode{
+            light: light as u32,
+            leaf: 1,
+            ..bounds[light].to_node()
+        };
+        return bounds[light];
+    }
+
+    let (min, max) = order.iter().fold((Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)), |(min, max), i|{
+        let centroid = bounds[*i].centroid();
+        (min.min(centroid), max.max(centroid))
+    });
+    let extent = max - min;
+    let axis = if extent.x > extent.y && extent.x > extent.z {0} else if extent.y > extent.z {1} else {2};
+    order.sort_by(|a, b| bounds[*a].centroid()[axis].total_cmp(&bounds[*b].centroid()[axis]));
+
+    //The bit trail is 32 bits deep, past that keep splitting but stop recording branches
+    let (left_trail, right_trail) = if depth < 32 {(bit_trail, bit_trail | (1 << depth))} else {(bit_trail, bit_trail)};
+    let (left, right) = order.split_at_mut(order.len() / 2);
+    let left_bounds = build_recursive(bounds, left, lights, nodes, depth + 1, left_trail);
+    let second_child = nodes.len() as u32;
+    let right_bounds = build_recursive(bounds, right, lights, nodes, depth + 1, right_trail);
+
+    let combined = left_bounds.union(&right_bounds);
+    nodes[node_index] = LightNode{
+        second_child,
+        ..combined.to_node()
+    };
+    combined
+}
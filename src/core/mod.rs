@@ -6,4 +6,21 @@ pub mod camera;
 pub mod resource;
 pub mod renderer;
 pub mod ray_tracer;
-pub mod scene;
\ No newline at end of file
+pub mod scene;
+pub mod sampler;
+pub mod light;
+pub mod spectrum;
+pub mod photon_map;
+pub mod restir;
+pub mod integrator;
+pub mod exposure;
+pub mod animation;
+pub mod export;
+pub mod skinning;
+pub mod headless;
+pub mod tonemap;
+pub mod denoiser;
+pub mod aov;
+pub mod adaptive;
+pub mod bdpt;
+pub mod scene_file;
@@ -0,0 +1,70 @@
+//Has to match the constants in ray_tracer.wgsl
+pub const MAX_PHOTONS: u64 = 1 << 19;
+pub const HASH_CELLS: u64 = 1 << 18;
+pub const PHOTON_WORKGROUP_SIZE: u32 = 64;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct Photon{
+    pub position: [f32;3],
+    //1 + index of the next photon in the same cell, 0 ends the list
+    pub next: u32,
+    pub dir: [f32;3],
+    pub _padding: f32,
+    pub power: [f32;4],
+}
+
+//Caustic photons are re-emitted every frame into a hash grid of linked lists
+pub struct PhotonMap{
+    pub photon_buffer: wgpu::Buffer,
+    //Element 0 counts the stored photons, the rest are the heads of each cell's list
+    pub grid_buffer: wgpu::Buffer,
+    pub photons_per_frame: i32,
+    pub initial_radius: f32,
+    pub alpha: f32,
+    pub radius: f32,
+}
+
+impl PhotonMap{
+    pub fn new(device: &wgpu::Device) -> Self{
+        let photon_buffer = device.create_buffer(&wgpu::BufferDescriptor{
+            label: Some("Photon Buffer"),
+            size: MAX_PHOTONS * std::mem::size_of::<Photon>() as u64,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let grid_buffer = device.create_buffer(&wgpu::BufferDescriptor{
+            label: Some("Photon Grid Buffer"),
+            size: (HASH_CELLS + 1) * std::mem::size_of::<u32>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Self{
+            photon_buffer,
+            grid_buffer,
+            photons_per_frame: 1 << 16,
+            initial_radius: 0.05,
+            alpha: 2.0 / 3.0,
+            radius: 0.05,
+        }
+    }
+
+    //Progressive photon mapping radius reduction (Knaus & Zwicker 2011)
+    pub fn update_radius(&mut self, frame: i32) -> f32{
+        if frame <= 0{
+            self.radius = self.initial_radius;
+        }else{
+            let i = frame as f32;
+            self.radius *= ((i + self.alpha) / (i + 1.0)).sqrt();
+        }
+        self.radius
+    }
+
+    pub fn workgroups(&self) -> u32{
+        (self.photons_per_frame.max(0) as u32).div_ceil(PHOTON_WORKGROUP_SIZE)
+    }
+
+    pub fn clear(&self, encoder: &mut wgpu::CommandEncoder){
+        encoder.clear_buffer(&self.grid_buffer, 0, None);
+    }
+}
@@ -1,20 +1,53 @@
 use std::mem;
 
-use super::{context::Params, texture::Texture, scene::{Scene, Mesh, Vertex, Sphere}};
+use super::{context::Params, texture::Texture, scene::{Scene, SceneBuffers}, photon_map::PhotonMap, restir::Restir, denoiser::Denoiser, aov::Aovs, adaptive::Adaptive, bdpt::Bdpt, integrator::Integrator};
+
+const WORKGROUP_SIZE: (u32, u32) = (8, 8);
+//Has to match the line declaring INTEGRATOR in ray_tracer.wgsl
+const INTEGRATOR_DECLARATION: &str = "const INTEGRATOR: i32 = INTEGRATOR_PATH;";
 
 pub struct RayTracer{
-    pub pipeline: wgpu::ComputePipeline,
+    //Indexed by Integrator
+    pub pipelines: Vec<wgpu::ComputePipeline>,
+    pub photon_pipeline: wgpu::ComputePipeline,
+    pub restir_initial_pipeline: wgpu::ComputePipeline,
+    pub restir_spatial_pipeline: wgpu::ComputePipeline,
+    pub bdpt_splat_pipeline: wgpu::ComputePipeline,
     pub bind_group: wgpu::BindGroup,
     pub bind_group_layout: wgpu::BindGroupLayout,
+    pub scene_buffers: SceneBuffers,
+    //Size of the texture bound, which the dispatch covers
+    pub width: u32,
+    pub height: u32,
+}
+
+//Everything the compute bind group points at apart from the scene
+pub struct Resources<'a>{
+    pub texture: &'a Texture,
+    pub params_buffer: &'a wgpu::Buffer,
+    pub blue_noise_buffer: &'a wgpu::Buffer,
+    pub photon_map: &'a PhotonMap,
+    pub restir: &'a Restir,
+    pub denoiser: &'a Denoiser,
+    pub aovs: &'a Aovs,
+    pub adaptive: &'a Adaptive,
+    pub bdpt: &'a Bdpt,
 }
 
 impl RayTracer{
-    pub fn new(device: &wgpu::Device, texture: &Texture,params_buffer: &wgpu::Buffer,scene: &Scene)->Self{
+    //The pipelines don't depend on the scene, switching scenes only needs update_bind_group
+    pub fn new(device: &wgpu::Device, resources: &Resources, scene: &Scene)->Self{
 
-        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor{
-            label: Some("Compute Shader"),
-            source: wgpu::ShaderSource::Wgsl(include_str!("../../shaders/ray_tracer.wgsl").into())
-        });
+        //A module per integrator with INTEGRATOR set, so each one only compiles its own code
+        let source = include_str!("../../shaders/ray_tracer.wgsl");
+        //Without it every module would silently build the path tracer
+        assert!(source.contains(INTEGRATOR_DECLARATION), "ray_tracer.wgsl no longer declares INTEGRATOR as {}", INTEGRATOR_DECLARATION);
+        let shaders: Vec<wgpu::ShaderModule> = Integrator::ALL.iter().map(|integrator|{
+            device.create_shader_module(wgpu::ShaderModuleDescriptor{
+                label: Some(integrator.name()),
+                source: wgpu::ShaderSource::Wgsl(source.replace(INTEGRATOR_DECLARATION, &format!("const INTEGRATOR: i32 = {};", *integrator as i32)).into())
+            })
+        }).collect();
 
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor{
             label: Some("Compute Bind Group Layout"),
@@ -45,7 +78,7 @@ impl RayTracer{
                 wgpu::BindGroupLayoutEntry {
                     binding: 2,
                     visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: texture.binding_type(wgpu::StorageTextureAccess::ReadWrite),
+                    ty: resources.texture.binding_type(wgpu::StorageTextureAccess::ReadWrite),
                     count: None,
                 },
                 //Spheres
@@ -55,7 +88,7 @@ impl RayTracer{
                     ty: wgpu::BindingType::Buffer{
                         ty: wgpu::BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
-                        min_binding_size: wgpu::BufferSize::new((mem::size_of::<Sphere>() * scene.spheres.len()) as _),
+                        min_binding_size: None,
                     },
                     count: None,
                 },
@@ -66,7 +99,7 @@ impl RayTracer{
                     ty: wgpu::BindingType::Buffer{
                         ty: wgpu::BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
-                        min_binding_size: wgpu::BufferSize::new((mem::size_of::<Vertex>() * scene.vertices.len()) as _),
+                        min_binding_size: None,
                     },
                     count: None,
                 },
@@ -77,7 +110,7 @@ impl RayTracer{
                     ty: wgpu::BindingType::Buffer{
                         ty: wgpu::BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
-                        min_binding_size: wgpu::BufferSize::new((mem::size_of::<u32>() * scene.indices.len()) as _),
+                        min_binding_size: None,
                     },
                     count: None,
                 },
@@ -88,72 +121,269 @@ impl RayTracer{
                     ty: wgpu::BindingType::Buffer{
                         ty: wgpu::BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
-                        min_binding_size: wgpu::BufferSize::new((mem::size_of::<Mesh>() * scene.meshes.len()) as _),
+                        min_binding_size: None,
                     },
                     count: None,
                 },
-            ],
-        });
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor{
-            label: Some("Compute Bind Group"),
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: params_buffer.as_entire_binding(),
+                //Blue noise
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer{
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
                 },
-                wgpu::BindGroupEntry{
-                    binding: 1,
-                    resource: scene.camera.buffer.as_entire_binding(),
+                //Lights
+                wgpu::BindGroupLayoutEntry {
+                    binding: 8,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer{
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
                 },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: texture.binding_resource(),
+                //Light BVH
+                wgpu::BindGroupLayoutEntry {
+                    binding: 9,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer{
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
                 },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: scene.sphere_buffer(device).as_entire_binding(),
+                //Photons
+                wgpu::BindGroupLayoutEntry {
+                    binding: 10,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer{
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
                 },
-                wgpu::BindGroupEntry {
-                    binding: 4,
-                    resource: scene.vertex_buffer(device).as_entire_binding(),
+                //Photon grid
+                wgpu::BindGroupLayoutEntry {
+                    binding: 11,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer{
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
                 },
-                wgpu::BindGroupEntry {
-                    binding: 5,
-                    resource: scene.index_buffer(device).as_entire_binding(),
+                //Reservoirs
+                wgpu::BindGroupLayoutEntry {
+                    binding: 12,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer{
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
                 },
-                wgpu::BindGroupEntry {
-                    binding: 6,
-                    resource: scene.mesh_buffer(device).as_entire_binding(),
+                //Reservoir history
+                wgpu::BindGroupLayoutEntry {
+                    binding: 13,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer{
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                //Previous camera
+                wgpu::BindGroupLayoutEntry {
+                    binding: 14,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer{
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                //Bokeh
+                wgpu::BindGroupLayoutEntry {
+                    binding: 15,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer{
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                //Denoiser features
+                wgpu::BindGroupLayoutEntry {
+                    binding: 16,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer{
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                //AOVs
+                wgpu::BindGroupLayoutEntry {
+                    binding: 17,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: resources.aovs.binding_type(),
+                    count: None,
+                },
+                //Adaptive sampling moments
+                wgpu::BindGroupLayoutEntry {
+                    binding: 18,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer{
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                //Converged pixels
+                wgpu::BindGroupLayoutEntry {
+                    binding: 19,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer{
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                //Light tracing splats
+                wgpu::BindGroupLayoutEntry {
+                    binding: 20,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer{
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
                 },
             ],
         });
+        let scene_buffers = scene.buffers(device);
+        let bind_group = Self::create_bind_group(device, &bind_group_layout, resources, scene, &scene_buffers);
 
         let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor{
             label: Some("Compute Pipeline layout"),
             bind_group_layouts: &[&bind_group_layout],
             push_constant_ranges: &[],
         });
-        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-            label: Some("Compute Pipeline"),
+        let pipelines = Integrator::ALL.iter().map(|integrator|{
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(integrator.name()),
+                layout: Some(&layout),
+                module: &shaders[*integrator as usize],
+                entry_point: "render",
+            })
+        }).collect();
+        //The passes an integrator depends on come from that integrator's module
+        let photon_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Photon Pipeline"),
             layout: Some(&layout),
-            module: &shader,
-            entry_point: "main",
+            module: &shaders[Integrator::PhotonMapping as usize],
+            entry_point: "emit_photons",
+        });
+        let restir_initial_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("ReSTIR Initial Pipeline"),
+            layout: Some(&layout),
+            module: &shaders[Integrator::Restir as usize],
+            entry_point: "restir_initial",
+        });
+        let restir_spatial_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("ReSTIR Spatial Pipeline"),
+            layout: Some(&layout),
+            module: &shaders[Integrator::Restir as usize],
+            entry_point: "restir_spatial",
+        });
+        let bdpt_splat_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("BDPT Splat Pipeline"),
+            layout: Some(&layout),
+            module: &shaders[Integrator::Bidirectional as usize],
+            entry_point: "bdpt_splat",
         });
         Self { 
-            pipeline, 
+            pipelines, 
+            photon_pipeline,
+            restir_initial_pipeline,
+            restir_spatial_pipeline,
+            bdpt_splat_pipeline,
             bind_group,
             bind_group_layout,
+            scene_buffers,
+            width: resources.texture.width,
+            height: resources.texture.height,
+        }
+    }
+    pub fn pipeline(&self, integrator: Integrator) -> &wgpu::ComputePipeline{
+        &self.pipelines[integrator as usize]
+    }
+    //One frame of the integrator, including the photon and reservoir passes it depends on
+    pub fn dispatch(&self, encoder: &mut wgpu::CommandEncoder, integrator: Integrator, photon_map: &PhotonMap, restir: &mut Restir, adaptive: &Adaptive, bdpt: &Bdpt){
+        if integrator == Integrator::PhotonMapping{
+            photon_map.clear(encoder);
+        }
+        if integrator == Integrator::Restir{
+            restir.clear(encoder);
+        }
+        if integrator == Integrator::Bidirectional{
+            bdpt.clear(encoder);
+        }
+        adaptive.clear(encoder);
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor{
+            label: Some("Compute Pass"),
+        });
+        let xgroups = self.width.div_ceil(WORKGROUP_SIZE.0);
+        let ygroups = self.height.div_ceil(WORKGROUP_SIZE.1);
+
+        compute_pass.set_bind_group(0, &self.bind_group, &[]);
+        if integrator == Integrator::PhotonMapping{
+            compute_pass.set_pipeline(&self.photon_pipeline);
+            compute_pass.dispatch_workgroups(photon_map.workgroups(),1,1);
         }
+        if integrator == Integrator::Restir{
+            compute_pass.set_pipeline(&self.restir_initial_pipeline);
+            compute_pass.dispatch_workgroups(xgroups,ygroups,1);
+            compute_pass.set_pipeline(&self.restir_spatial_pipeline);
+            compute_pass.dispatch_workgroups(xgroups,ygroups,1);
+        }
+        compute_pass.set_pipeline(self.pipeline(integrator));
+        compute_pass.dispatch_workgroups(xgroups,ygroups,1);
+        if integrator == Integrator::Bidirectional{
+            compute_pass.set_pipeline(&self.bdpt_splat_pipeline);
+            compute_pass.dispatch_workgroups(xgroups,ygroups,1);
+        }
+    }
+    pub fn update_bind_group(&mut self, device: &wgpu::Device, resources: &Resources, scene: &Scene){
+        self.scene_buffers = scene.buffers(device);
+        self.bind_group = Self::create_bind_group(device, &self.bind_group_layout, resources, scene, &self.scene_buffers);
+        (self.width, self.height) = (resources.texture.width, resources.texture.height);
     }
-    pub fn update_bind_group(&mut self, device: &wgpu::Device, params_buffer: &wgpu::Buffer, texture: &Texture,scene: &Scene){
-        self.bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor{
+
+    fn create_bind_group(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, resources: &Resources, scene: &Scene, scene_buffers: &SceneBuffers) -> wgpu::BindGroup{
+        device.create_bind_group(&wgpu::BindGroupDescriptor{
             label: Some("Compute Bind Group"),
-            layout: &self.bind_group_layout,
+            layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: params_buffer.as_entire_binding(),
+                    resource: resources.params_buffer.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
@@ -161,25 +391,81 @@ impl RayTracer{
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
-                    resource: texture.binding_resource(),
+                    resource: resources.texture.binding_resource(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 3,
-                    resource: scene.sphere_buffer(&device).as_entire_binding(),
+                    resource: scene_buffers.spheres.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 4,
-                    resource: scene.vertex_buffer(&device).as_entire_binding(),
+                    resource: scene_buffers.vertices.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 5,
-                    resource: scene.index_buffer(&device).as_entire_binding(),
+                    resource: scene_buffers.indices.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 6,
-                    resource: scene.mesh_buffer(&device).as_entire_binding(),
+                    resource: scene_buffers.meshes.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: resources.blue_noise_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: scene_buffers.lights.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: scene_buffers.light_nodes.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: resources.photon_map.photon_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 11,
+                    resource: resources.photon_map.grid_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 12,
+                    resource: resources.restir.reservoir_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 13,
+                    resource: resources.restir.history_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 14,
+                    resource: resources.restir.prev_camera_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 15,
+                    resource: scene.camera.bokeh_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 16,
+                    resource: resources.denoiser.features_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 17,
+                    resource: resources.aovs.binding_resource(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 18,
+                    resource: resources.adaptive.moments_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 19,
+                    resource: resources.adaptive.counter_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 20,
+                    resource: resources.bdpt.splat_buffer.as_entire_binding(),
                 },
             ],
-        });
+        })
     }
 }
\ No newline at end of file
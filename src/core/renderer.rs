@@ -4,7 +4,7 @@ use bytemuck::{Pod, Zeroable};
 use imgui_winit_support::winit;
 use wgpu::{SurfaceConfiguration, util::DeviceExt};
 
-use super::{imgui::ImguiLayer, texture::Texture, context::Params};
+use super::{imgui::ImguiLayer, texture::Texture, context::Params, exposure::Exposure};
 
 #[repr(C)]
 #[derive(Clone, Copy, Pod, Zeroable)]
@@ -45,7 +45,7 @@ impl Renderer{
         (vertex_data.to_vec(), index_data.to_vec())
     }
     //maybe change texture to texture_binding resource
-    pub async fn new(device: &wgpu::Device,queue: &wgpu::Queue,texture: &Texture, config: &SurfaceConfiguration, params_buffer: &wgpu::Buffer, window_ref: &winit::window::Window) -> Self{
+    pub async fn new(device: &wgpu::Device,queue: &wgpu::Queue,texture: &Texture, config: &SurfaceConfiguration, params_buffer: &wgpu::Buffer, exposure: &Exposure, window_ref: &winit::window::Window) -> Self{
         
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: Some("Bind Group Layout"),
@@ -66,6 +66,26 @@ impl Renderer{
                     ty: texture.binding_type(wgpu::StorageTextureAccess::ReadWrite),
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry{
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry{
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
         });
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
@@ -79,6 +99,14 @@ impl Renderer{
                     binding: 1,
                     resource: texture.binding_resource(),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: exposure.uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: exposure.luminance_buffer.as_entire_binding(),
+                },
             ],
             label: Some("Bind Group"),
         });
@@ -166,7 +194,7 @@ impl Renderer{
         }
     }
 
-    pub fn update_bind_group(&mut self, device: &wgpu::Device, params_buffer: &wgpu::Buffer, texture: &Texture){
+    pub fn update_bind_group(&mut self, device: &wgpu::Device, params_buffer: &wgpu::Buffer, exposure: &Exposure, texture: &Texture){
         self.bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: &self.bind_group_layout,
             entries: &[
@@ -178,6 +206,14 @@ impl Renderer{
                     binding: 1,
                     resource: texture.binding_resource(),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: exposure.uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: exposure.luminance_buffer.as_entire_binding(),
+                },
             ],
             label: Some("Bind Group"),
         });
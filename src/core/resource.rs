@@ -1,6 +1,9 @@
 use std::{io::{BufReader, Cursor}, path::Path};
 
-use super::scene::{Vertex, Mesh};
+use glam::{Mat4, Quat, Vec3};
+use gltf::animation::util::ReadOutputs;
+
+use super::{scene::{Vertex, Mesh}, light::NO_LIGHT, skinning::{Rig, Node, Transform, Skin, Deformed, Channel, Clip, Property}};
 
 const FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"));
 
@@ -28,17 +31,16 @@ pub async fn load_model(
     path: &Path,
     vertices: &mut Vec<Vertex>,
     indices: &mut Vec<u32>,
-    meshes: &mut Vec<Mesh>
+    meshes: &mut Vec<Mesh>,
+    rigs: &mut Vec<Rig>,
 ) -> anyhow::Result<()>{
     let path = std::path::Path::new(FILE).join("assets").join(path);
 
     log::info!("Loading model: {}", path.display());
     if path.extension() == Some("obj".as_ref()) {
         load_model_obj(&path, vertices, indices, meshes).await
-    } else if path.extension() == Some("gltf".as_ref()) {
-        load_model_gltf(&path, vertices, indices, meshes).await
-    } else if path.extension() == Some("glb".as_ref()) {
-        load_model_glb(&path, vertices, indices, meshes).await
+    } else if path.extension() == Some("gltf".as_ref()) || path.extension() == Some("glb".as_ref()) {
+        load_model_gltf(&path, vertices, indices, meshes, rigs).await
     } else {
         Err(anyhow::anyhow!("Unsupported model format"))
     }
@@ -76,12 +78,12 @@ pub async fn load_model_obj(
             offset: vertices.len() as u32,
             _padding2: 0.0,
             pos: [meshes.len() as f32 * 3.0, 0.0,0.0],
-            _padding: 0.0,
+            light: NO_LIGHT,
             color: [0.2,0.2,1.0,1.0],
             emission_color: [0.0;4],
             emission_strength: 0.0,
             specular: 0.5,
-            _padding3: [0.0;2],
+            ..Default::default()
         });
         vertices.append(&mut (0..m.mesh.positions.len() / 3)
             .map(|i| {
@@ -106,96 +108,14 @@ pub async fn load_model_obj(
     Ok(())
 }
 
+//Text and binary glTF, external buffers are read next to the file. Skinned, morphed or animated files
+//also add a Rig that poses their meshes
 pub async fn load_model_gltf(
     path: &Path,
     vertices: &mut Vec<Vertex>,
     indices: &mut Vec<u32>,
-    meshes: &mut Vec<Mesh>
-) -> anyhow::Result<()> {
-    let gltf_text = load_string(path).await?;
-    let gltf_cursor = Cursor::new(gltf_text);
-    let gltf_reader = BufReader::new(gltf_cursor);
-    let gltf = gltf::Gltf::from_reader(gltf_reader)?;
-
-    // Load buffers
-    let mut buffer_data = Vec::new();
-    for buffer in gltf.buffers() {
-        let bin = match buffer.source() {
-            gltf::buffer::Source::Bin => {
-                Vec::new()
-            }
-            gltf::buffer::Source::Uri(uri) => {
-                let uri = path.with_file_name(uri);
-                load_binary(&uri).await?
-            }
-        };
-
-        buffer_data.push(bin);
-    }
-
-    log::debug!("Initizalized buffers");
-
-    for scene in gltf.scenes() {
-        for node in scene.nodes() {
-            log::info!("Node {} {}", node.index(), node.name().unwrap_or("Unnamed"));
-
-            let mesh = node.mesh().expect("Failed to get mesh");
-            let primitives = mesh.primitives();
-            primitives.for_each(|primitive| {
-                let reader = primitive.reader(|buffer| Some(&buffer_data[buffer.index()]));
-
-                log::info!("[START] Reading positions, normals");
-                let (positions, normals) = (
-                    reader.read_positions().unwrap(),
-                    reader.read_normals().unwrap(),
-                );
-                log::info!("[END  ] Reading positions, normals");
-
-                log::info!("[START] Reading indices");
-                let i = reader.read_indices().map(|indices| indices.into_u32());
-                let mut new_indices = match i{
-                    Some(indices) => indices.collect::<Vec<_>>(),
-                    None => (0..positions.len() as u32).collect(),
-                };
-                log::info!("[END  ] Reading indices");
-
-
-                meshes.push(Mesh{
-                    triangles: new_indices.len() as u32 / 3,
-                    first: indices.len() as u32,
-                    offset: vertices.len() as u32,
-                    _padding2: 0.0,
-                    pos: [meshes.len() as f32 * 3.0, 0.0,0.0],
-                    _padding: 0.0,
-                    color: [0.2,0.2,1.0,1.0],
-                    emission_color: [0.0;4],
-                    emission_strength: 0.0,
-                    specular: 0.5,
-                    _padding3: [0.0;2],
-                });
-
-                vertices.append(&mut positions
-                    .zip(normals)
-                    .map(|(pos, normal)| Vertex {
-                        pos: [pos[0]* meshes.len() as f32,pos[1]* meshes.len() as f32,pos[2]* meshes.len() as f32],
-                        _padding1: 0.0,
-                        normal,
-                        _padding2: 0.0,
-                    })
-                    .collect::<Vec<Vertex>>());
-                indices.append(&mut new_indices);
-            });
-        }
-    }
-
-    Ok(())
-}
-
-pub async fn load_model_glb(
-    path: &Path,
-    vertices: &mut Vec<Vertex>,
-    indices: &mut Vec<u32>,
-    meshes: &mut Vec<Mesh>
+    meshes: &mut Vec<Mesh>,
+    rigs: &mut Vec<Rig>,
 ) -> anyhow::Result<()> {
     let gltf_text = load_binary(path).await?;
     let gltf_cursor = Cursor::new(gltf_text);
@@ -223,21 +143,23 @@ pub async fn load_model_glb(
         buffer_data.push(bin);
     }
 
+    //Scene meshes each glTF mesh was loaded into, one per primitive
+    let mut mesh_map = vec![];
     for mesh in gltf.meshes() {
+        let mut primitive_meshes = vec![];
         log::info!(
             r#"Mesh#{} "{}""#,
             mesh.index(),
             mesh.name().unwrap_or("Unnamed")
         );
 
-        let primitives = mesh.primitives();
-        primitives.for_each(|primitive| {
+        for primitive in mesh.primitives() {
             let reader = primitive.reader(|buffer| Some(&buffer_data[buffer.index()]));
 
             log::info!("[START] Reading positions, normals");
             let (positions, normals) = (
-                reader.read_positions().unwrap(),
-                reader.read_normals().unwrap(),
+                reader.read_positions().ok_or_else(|| anyhow::anyhow!("Primitive {} of mesh {} has no positions", primitive.index(), mesh.index()))?,
+                reader.read_normals().ok_or_else(|| anyhow::anyhow!("Primitive {} of mesh {} has no normals", primitive.index(), mesh.index()))?,
             );
             log::info!("[END  ] Reading positions, normals");
 
@@ -249,18 +171,19 @@ pub async fn load_model_glb(
             };
             log::info!("[END  ] Reading indices");
 
+            primitive_meshes.push(meshes.len());
             meshes.push(Mesh{
                 triangles: new_indices.len() as u32 / 3,
                 first: indices.len() as u32,
                 offset: vertices.len() as u32,
                 _padding2: 0.0,
                 pos: [meshes.len() as f32 * 3.0, 0.0,0.0],
-                _padding: 0.0,
+                light: NO_LIGHT,
                 color: [0.2,0.2,1.0,1.0],
                 emission_color: [0.0;4],
                 emission_strength: 0.0,
                 specular: 0.5,
-                _padding3: [0.0;2],
+                ..Default::default()
             });
 
             vertices.append(&mut positions
@@ -273,7 +196,133 @@ pub async fn load_model_glb(
                 })
                 .collect::<Vec<Vertex>>());
             indices.append(&mut new_indices);
-        });
+        }
+        mesh_map.push(primitive_meshes);
+    }
+    if let Some(rig) = load_rig(&gltf, &buffer_data, &mesh_map, vertices, meshes){
+        log::info!("Loaded {} animations and {} skins", rig.clips.len(), rig.skins.len());
+        rigs.push(rig);
     }
     Ok(())
+}
+
+//Node hierarchy, skins, morph targets and animations. None when nothing in the file moves
+fn load_rig(
+    gltf: &gltf::Gltf,
+    buffer_data: &[Vec<u8>],
+    mesh_map: &[Vec<usize>],
+    vertices: &[Vertex],
+    meshes: &mut [Mesh],
+) -> Option<Rig> {
+    if gltf.animations().len() == 0 && gltf.skins().len() == 0 {
+        return None;
+    }
+
+    let mut nodes = gltf.nodes().map(|node| {
+        let (translation, rotation, scale) = node.transform().decomposed();
+        Node {
+            parent: None,
+            rest: Transform {
+                translation: Vec3::from(translation),
+                rotation: Quat::from_array(rotation),
+                scale: Vec3::from(scale),
+            },
+            morph_weights: node.weights()
+                .or_else(|| node.mesh().and_then(|mesh| mesh.weights()))
+                .map(|weights| weights.to_vec())
+                .unwrap_or_default(),
+        }
+    }).collect::<Vec<_>>();
+    for node in gltf.nodes() {
+        for child in node.children() {
+            nodes[child.index()].parent = Some(node.index());
+        }
+    }
+
+    let skins = gltf.skins().map(|skin| {
+        let reader = skin.reader(|buffer| Some(&buffer_data[buffer.index()]));
+        let joints = skin.joints().map(|joint| joint.index()).collect::<Vec<_>>();
+        let inverse_bind = match reader.read_inverse_bind_matrices() {
+            Some(matrices) => matrices.map(|m| Mat4::from_cols_array_2d(&m)).collect(),
+            None => vec![Mat4::IDENTITY; joints.len()],
+        };
+        Skin { joints, inverse_bind }
+    }).collect::<Vec<_>>();
+
+    let mut primitives: Vec<Deformed> = vec![];
+    for node in gltf.nodes() {
+        if let Some(mesh) = node.mesh() {
+            let scene_meshes = &mesh_map[mesh.index()];
+            //Vertices are only loaded once, a mesh used by several nodes follows the first
+            if primitives.iter().any(|p| scene_meshes.contains(&p.mesh)) {
+                continue;
+            }
+            for (primitive, &scene_mesh) in mesh.primitives().zip(scene_meshes) {
+                let reader = primitive.reader(|buffer| Some(&buffer_data[buffer.index()]));
+                let count = primitive.get(&gltf::Semantic::Positions).map_or(0, |a| a.count());
+                let offset = meshes[scene_mesh].offset as usize;
+                let joints = reader.read_joints(0)
+                    .map(|joints| joints.into_u16().collect::<Vec<_>>())
+                    .unwrap_or_default();
+                let weights = reader.read_weights(0)
+                    .map(|weights| weights.into_f32().collect::<Vec<_>>())
+                    .unwrap_or_default();
+                let skin = node.skin()
+                    .map(|skin| skin.index())
+                    .filter(|_| joints.len() == count && weights.len() == count);
+                let targets = reader.read_morph_targets().map(|(positions, normals, _)| {
+                    let positions = positions.map_or(vec![Vec3::ZERO; count], |p| p.map(Vec3::from).collect());
+                    let normals = normals.map_or(vec![Vec3::ZERO; count], |n| n.map(Vec3::from).collect());
+                    positions.into_iter().zip(normals).map(|(p, n)| [p, n]).collect()
+                }).collect();
+                //Posed vertices are already where the node puts them, the offset the loader
+                //spreads primitives out by would move them a second time
+                meshes[scene_mesh].pos = [0.0; 3];
+                primitives.push(Deformed {
+                    mesh: scene_mesh,
+                    node: node.index(),
+                    skin,
+                    rest: vertices[offset..offset + count].to_vec(),
+                    joints,
+                    weights,
+                    targets,
+                });
+            }
+        }
+    }
+
+    let clips = gltf.animations().map(|animation| {
+        let channels = animation.channels().filter_map(|channel| {
+            let reader = channel.reader(|buffer| Some(&buffer_data[buffer.index()]));
+            let times = reader.read_inputs()?.collect::<Vec<_>>();
+            let (property, values) = match reader.read_outputs()? {
+                ReadOutputs::Translations(t) => (Property::Translation, t.flatten().collect()),
+                ReadOutputs::Rotations(r) => (Property::Rotation, r.into_f32().flatten().collect()),
+                ReadOutputs::Scales(s) => (Property::Scale, s.flatten().collect()),
+                ReadOutputs::MorphTargetWeights(w) => (Property::MorphWeights, w.into_f32().collect()),
+            };
+            Some(Channel {
+                node: channel.target().node().index(),
+                property,
+                interpolation: channel.sampler().interpolation(),
+                times,
+                values,
+            })
+        }).collect::<Vec<_>>();
+        let duration = channels.iter().filter_map(|c| c.times.last()).fold(0.0f32, |a, b| a.max(*b));
+        Clip {
+            name: animation.name().unwrap_or("Unnamed").to_string(),
+            channels,
+            duration,
+        }
+    }).collect();
+
+    Some(Rig {
+        nodes,
+        skins,
+        primitives,
+        clips,
+        clip: 0,
+        speed: 1.0,
+    })
 }
\ No newline at end of file
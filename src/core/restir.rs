@@ -0,0 +1,93 @@
+use std::mem;
+
+use wgpu::util::DeviceExt;
+
+use super::camera::CameraUniform;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct Reservoir{
+    pub light_point: [f32;3],
+    pub light: u32,
+    //Surface the reservoir was built for, used to reject mismatched neighbours and history
+    pub point: [f32;3],
+    pub w_sum: f32,
+    pub normal: [f32;3],
+    pub m: f32,
+    pub weight: f32,
+    pub target_pdf: f32,
+    pub _padding: [f32;2],
+}
+
+//Per pixel reservoirs for resampled direct lighting (Bitterli et al. 2020). The initial pass writes
+//reservoir_buffer, the spatial pass writes history_buffer which is shaded and reused next frame. Indirect
+//light is path traced from the first bounce on top of it
+pub struct Restir{
+    pub reservoir_buffer: wgpu::Buffer,
+    pub history_buffer: wgpu::Buffer,
+    pub prev_camera_buffer: wgpu::Buffer,
+    pub prev_camera: CameraUniform,
+    pub candidates: i32,
+    pub temporal: bool,
+    pub spatial_samples: i32,
+    pub spatial_radius: i32,
+    pub max_history: i32,
+    //Set when the reservoirs no longer describe the scene, e.g. after switching scenes
+    pub reset: bool,
+}
+
+impl Restir{
+    pub fn new(device: &wgpu::Device, width: u32, height: u32) -> Self{
+        let (reservoir_buffer, history_buffer) = Self::reservoir_buffers(device, width, height);
+        let prev_camera = CameraUniform::default();
+        let prev_camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor{
+            label: Some("Previous Camera Buffer"),
+            contents: bytemuck::bytes_of(&prev_camera),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        Self{
+            reservoir_buffer,
+            history_buffer,
+            prev_camera_buffer,
+            prev_camera,
+            candidates: 8,
+            temporal: true,
+            spatial_samples: 4,
+            spatial_radius: 30,
+            max_history: 20,
+            reset: true,
+        }
+    }
+
+    fn reservoir_buffers(device: &wgpu::Device, width: u32, height: u32) -> (wgpu::Buffer, wgpu::Buffer){
+        let size = (width * height) as u64 * mem::size_of::<Reservoir>() as u64;
+        let buffer = |label|{
+            device.create_buffer(&wgpu::BufferDescriptor{
+                label: Some(label),
+                size,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+        (buffer("Reservoir Buffer"), buffer("Reservoir History Buffer"))
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32){
+        (self.reservoir_buffer, self.history_buffer) = Self::reservoir_buffers(device, width, height);
+        self.reset = true;
+    }
+
+    //The camera the history was rendered with, has to be written before the current camera changes
+    pub fn update_camera(&mut self, queue: &wgpu::Queue, camera: CameraUniform){
+        queue.write_buffer(&self.prev_camera_buffer, 0, bytemuck::bytes_of(&self.prev_camera));
+        self.prev_camera = camera;
+    }
+
+    pub fn clear(&mut self, encoder: &mut wgpu::CommandEncoder){
+        if self.reset{
+            encoder.clear_buffer(&self.reservoir_buffer, 0, None);
+            encoder.clear_buffer(&self.history_buffer, 0, None);
+            self.reset = false;
+        }
+    }
+}
@@ -0,0 +1,16 @@
+use wgpu::util::DeviceExt;
+
+//Indexed by the SAMPLER_* ids in ray_tracer.wgsl
+pub const SAMPLER_SOBOL: i32 = 2;
+pub const SAMPLER_NAMES: [&str; 4] = ["Independent", "Stratified", "Sobol (Owen scrambled)", "Blue noise"];
+
+//64x64 tileable blue noise ranks in [0,1), generated by build.rs
+const BLUE_NOISE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/blue_noise.bin"));
+
+pub fn blue_noise_buffer(device: &wgpu::Device) -> wgpu::Buffer{
+    device.create_buffer_init(&wgpu::util::BufferInitDescriptor{
+        label: Some("Blue Noise Buffer"),
+        contents: BLUE_NOISE,
+        usage: wgpu::BufferUsages::STORAGE,
+    })
+}
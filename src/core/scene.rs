@@ -1,23 +1,33 @@
 use std::{path::Path};
 
-use glam::{Vec3, Vec4};
+use glam::{Quat, Vec3, Vec4};
 use rand::Rng;
 use wgpu::util::DeviceExt;
 
 use crate::core::resource::load_model;
 
-use super::camera::Camera;
+use super::{camera::Camera, light::{LightBvh, NO_LIGHT}, spectrum::Ior, animation::{CameraPath, ObjectTrack, ObjectKeyframe}, skinning::Rig};
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable, Default)]
 pub struct Sphere{
-    position: [f32;3], 
-    radius: f32,
-    color: [f32;4],
-    emission_color: [f32;4],
-    emission_strength: f32,
-    smoothness: f32,
-    _padding: [f32;2],
+    pub position: [f32;3], 
+    pub radius: f32,
+    pub color: [f32;4],
+    pub emission_color: [f32;4],
+    pub emission_strength: f32,
+    pub smoothness: f32,
+    pub dispersion: u32,
+    pub ior: f32,
+    pub ior_b: [f32;3],
+    pub _padding: f32,
+    pub ior_c: [f32;3],
+    pub _padding2: f32,
+    pub light: u32,
+    pub _padding3: [u32;3],
+    //Where the sphere moves to by the end of the shutter interval, for motion blur
+    pub motion: [f32;3],
+    pub _padding4: f32,
 }
 
 #[repr(C)]
@@ -26,14 +36,30 @@ pub struct Mesh{
     pub first: u32,
     pub triangles: u32,
     pub offset: u32,
-    pub _padding: f32,
+    //Light index of the first triangle, the rest follow in order
+    pub light: u32,
     pub pos: [f32;3],
     pub _padding2: f32,
     pub color: [f32;4],
     pub emission_color: [f32;4],
     pub emission_strength: f32,
     pub specular: f32,
-    pub _padding3: [f32;2],
+    pub dispersion: u32,
+    pub ior: f32,
+    pub ior_b: [f32;3],
+    pub _padding3: f32,
+    pub ior_c: [f32;3],
+    pub _padding4: f32,
+    pub motion: [f32;3],
+    pub _padding5: f32,
+    //Bounds of the triangles relative to pos, see refit_meshes
+    pub bounds_min: [f32;3],
+    pub _padding6: f32,
+    pub bounds_max: [f32;3],
+    pub _padding7: f32,
+    //Rotation about pos reached by the end of the shutter interval, axis times angle in radians
+    pub spin: [f32;3],
+    pub _padding8: f32,
 }
 
 #[repr(C)]
@@ -52,8 +78,28 @@ impl Sphere{
             color: color.to_array(),
             emission_color: emission_color.to_array(),
             emission_strength,
-            _padding: [0.0;2],
             smoothness: if specular < 1.0 {specular} else{1.0},
+            light: NO_LIGHT,
+            ..Default::default()
+        }.with_ior(Ior::GLASS)
+    }
+
+    //Only used by glass (smoothness -1)
+    pub fn with_ior(self, ior: Ior) -> Self{
+        let (dispersion, ior, ior_b, ior_c) = ior.coefficients();
+        Self{
+            dispersion,
+            ior,
+            ior_b,
+            ior_c,
+            ..self
+        }
+    }
+
+    pub fn with_motion(self, motion: Vec3) -> Self{
+        Self{
+            motion: motion.to_array(),
+            ..self
         }
     }
 }
@@ -66,14 +112,30 @@ impl Mesh{
             offset,
             _padding2: 0.0,
             pos: pos.to_array(),
-            _padding: 0.0,
+            light: NO_LIGHT,
             color: color.to_array(),
             emission_color: emission_color.to_array(),
             emission_strength,
             specular: if specular < 1.0 {specular} else{1.0},
-            _padding3: [0.0;2],
+            ..Default::default()
+        }.with_ior(Ior::GLASS)
+    }
+
+    pub fn with_ior(self, ior: Ior) -> Self{
+        let (dispersion, ior, ior_b, ior_c) = ior.coefficients();
+        Self{
+            dispersion,
+            ior,
+            ior_b,
+            ior_c,
+            ..self
         }
     }
+
+    //Where a vertex is at time along the motion, 0 at the start and 1 at the end like ray_time in the shader
+    pub fn transform(&self, point: Vec3, time: f32) -> Vec3{
+        Quat::from_scaled_axis(Vec3::from(self.spin) * time) * point + Vec3::from(self.pos) + Vec3::from(self.motion) * time
+    }
 }
 
 #[repr(C)]
@@ -91,15 +153,247 @@ impl Vertex{
     }
 }
 
+//Built in scenes by id, in the order of Scene::from_id
+pub const SCENE_NAMES: [&str; 5] = ["balls", "random_balls", "room", "metal", "random_lights"];
+
 pub struct Scene{
     pub camera: Camera,
     pub spheres: Vec<Sphere>,
     pub vertices: Vec<Vertex>,
     pub indices: Vec<u32>, 
     pub meshes: Vec<Mesh>,
+    pub light_bvh: LightBvh,
+    pub camera_path: CameraPath,
+    pub object_tracks: Vec<ObjectTrack>,
+    //Skinned and morphed glTF models, posed by animate
+    pub rigs: Vec<Rig>,
+}
+
+//Bounds of every mesh's triangles, the shader skips meshes whose box a ray misses.
+//Has to be called again whenever vertices move
+pub fn refit_meshes(meshes: &mut [Mesh], vertices: &[Vertex], indices: &[u32]){
+    for mesh in meshes{
+        let (min, max) = (0..mesh.triangles * 3)
+            .map(|i| Vec3::from(vertices[(mesh.offset + indices[(mesh.first + i) as usize]) as usize].pos))
+            .fold((Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)), |(min, max), p| (min.min(p), max.max(p)));
+        mesh.bounds_min = min.to_array();
+        mesh.bounds_max = max.to_array();
+    }
 }
 
 impl Scene{
+    //Distance along dir to the closest surface and its object index, spheres first then meshes
+    //like the object ids in the shader. For picking on the cpu, with everything where it is at time
+    pub fn raycast(&self, origin: Vec3, dir: Vec3, time: f32) -> Option<(f32, usize)>{
+        let mut closest: Option<(f32, usize)> = None;
+        let mut hit = |t: f32, object: usize|{
+            if t > 0.0 && closest.is_none_or(|(c, _)| t < c){
+                closest = Some((t, object));
+            }
+        };
+        for (i, sphere) in self.spheres.iter().enumerate(){
+            let oc = origin - Vec3::from(sphere.position) - Vec3::from(sphere.motion) * time;
+            let a = dir.dot(dir);
+            let b = 2.0 * oc.dot(dir);
+            let c = oc.dot(oc) - sphere.radius * sphere.radius;
+            let discriminant = b * b - 4.0 * a * c;
+            if discriminant >= 0.0{
+                let t = (-b - discriminant.sqrt()) / (2.0 * a);
+                hit(if t > 0.0 {t} else {(-b + discriminant.sqrt()) / (2.0 * a)}, i);
+            }
+        }
+        for (i, mesh) in self.meshes.iter().enumerate(){
+            for [a, b, c] in self.mesh_triangles(mesh, time){
+                //Moller-Trumbore, both sides
+                let edge_ab = b - a;
+                let edge_ac = c - a;
+                let p = dir.cross(edge_ac);
+                let determinant = edge_ab.dot(p);
+                if determinant.abs() < 1e-8{
+                    continue;
+                }
+                let ao = origin - a;
+                let u = ao.dot(p) / determinant;
+                let q = ao.cross(edge_ab);
+                let v = dir.dot(q) / determinant;
+                if u >= 0.0 && v >= 0.0 && u + v <= 1.0{
+                    hit(edge_ac.dot(q) / determinant, self.spheres.len() + i);
+                }
+            }
+        }
+        closest
+    }
+
+    fn mesh_triangles<'a>(&'a self, mesh: &'a Mesh, time: f32) -> impl Iterator<Item = [Vec3; 3]> + 'a{
+        (0..mesh.triangles).map(move |triangle|{
+            [0, 1, 2].map(|i|{
+                let index = self.indices[(mesh.first + triangle * 3 + i) as usize];
+                mesh.transform(Vec3::from(self.vertices[(mesh.offset + index) as usize].pos), time)
+            })
+        })
+    }
+
+    //Bounding sphere of one object, indexed like raycast
+    pub fn object_bounds(&self, object: usize) -> Option<(Vec3, f32)>{
+        if let Some(sphere) = self.spheres.get(object){
+            return Some((Vec3::from(sphere.position), sphere.radius));
+        }
+        let mesh = self.meshes.get(object - self.spheres.len())?;
+        let (min, max) = self.mesh_triangles(mesh, 0.0).flatten()
+            .fold((Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)), |(min, max), p| (min.min(p), max.max(p)));
+        if min.x > max.x{
+            return None;
+        }
+        Some(((min + max) / 2.0, (max - min).length() / 2.0))
+    }
+
+    //Current state of an object, indexed like raycast
+    pub fn object_state(&self, object: usize, time: f32) -> Option<ObjectKeyframe>{
+        if let Some(sphere) = self.spheres.get(object){
+            return Some(ObjectKeyframe{
+                time,
+                position: Vec3::from(sphere.position),
+                radius: sphere.radius,
+                emission_color: Vec4::from(sphere.emission_color).truncate(),
+                emission_strength: sphere.emission_strength,
+                smoothness: sphere.smoothness,
+            });
+        }
+        let mesh = self.meshes.get(object - self.spheres.len())?;
+        Some(ObjectKeyframe{
+            time,
+            position: Vec3::from(mesh.pos),
+            radius: 0.0,
+            emission_color: Vec4::from(mesh.emission_color).truncate(),
+            emission_strength: mesh.emission_strength,
+            smoothness: mesh.specular,
+        })
+    }
+
+    pub fn set_object_state(&mut self, object: usize, state: &ObjectKeyframe){
+        let emission_color = state.emission_color.extend(1.0).to_array();
+        if let Some(sphere) = self.spheres.get_mut(object){
+            sphere.position = state.position.to_array();
+            sphere.radius = state.radius;
+            sphere.emission_color = emission_color;
+            sphere.emission_strength = state.emission_strength;
+            sphere.smoothness = state.smoothness;
+        }else if let Some(mesh) = self.meshes.get_mut(object - self.spheres.len()){
+            mesh.pos = state.position.to_array();
+            mesh.emission_color = emission_color;
+            mesh.emission_strength = state.emission_strength;
+            mesh.specular = state.smoothness;
+        }
+    }
+
+    pub fn set_object_motion(&mut self, object: usize, motion: Vec3){
+        if let Some(sphere) = self.spheres.get_mut(object){
+            sphere.motion = motion.to_array();
+        }else if let Some(mesh) = self.meshes.get_mut(object - self.spheres.len()){
+            mesh.motion = motion.to_array();
+        }
+    }
+
+    //Only meshes spin, a sphere looks the same at every angle
+    pub fn object_spin(&self, object: usize) -> Option<Vec3>{
+        let mesh = self.meshes.get(object.checked_sub(self.spheres.len())?)?;
+        Some(Vec3::from(mesh.spin))
+    }
+
+    pub fn set_object_spin(&mut self, object: usize, spin: Vec3){
+        if let Some(mesh) = object.checked_sub(self.spheres.len()).and_then(|i| self.meshes.get_mut(i)){
+            mesh.spin = spin.to_array();
+            //A spinning light is bounded by everywhere it turns through
+            self.light_bvh = LightBvh::build(&mut self.spheres, &mut self.meshes, &self.vertices, &self.indices);
+        }
+    }
+
+    pub fn add_object_keyframe(&mut self, object: usize, time: f32){
+        if let Some(state) = self.object_state(object, time){
+            match self.object_tracks.iter_mut().find(|t| t.object == object){
+                Some(track) => track.track.insert(state),
+                None => {
+                    let mut track = ObjectTrack{object, track: Default::default()};
+                    track.track.insert(state);
+                    self.object_tracks.push(track);
+                }
+            }
+        }
+    }
+
+    //Poses every animated object and rig, motion blur covers the next frame_duration seconds.
+    //Returns false when nothing is animated
+    pub fn animate(&mut self, time: f32, frame_duration: f32) -> bool{
+        if self.object_tracks.is_empty() && self.rigs.is_empty(){
+            return false;
+        }
+        for i in 0..self.object_tracks.len(){
+            let object = self.object_tracks[i].object;
+            let track = &self.object_tracks[i].track;
+            if let (Some(state), Some(next)) = (track.evaluate(time), track.evaluate(time + frame_duration)){
+                self.set_object_state(object, &state);
+                self.set_object_motion(object, next.position - state.position);
+            }
+        }
+        for rig in &self.rigs{
+            rig.pose(time, &mut self.vertices, &self.meshes);
+        }
+        if !self.rigs.is_empty(){
+            refit_meshes(&mut self.meshes, &self.vertices, &self.indices);
+        }
+        //Emission and positions of lights may have changed
+        self.light_bvh = LightBvh::build(&mut self.spheres, &mut self.meshes, &self.vertices, &self.indices);
+        true
+    }
+
+    //Appends a model from the assets folder, skinned or animated glTF files are posed by animate
+    pub fn load_model(&mut self, path: &str) -> anyhow::Result<()>{
+        tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(
+            load_model(Path::new(path), &mut self.vertices, &mut self.indices, &mut self.meshes, &mut self.rigs)
+        ))?;
+        refit_meshes(&mut self.meshes, &self.vertices, &self.indices);
+        self.light_bvh = LightBvh::build(&mut self.spheres, &mut self.meshes, &self.vertices, &self.indices);
+        Ok(())
+    }
+
+    //Frames one object, or everything if there is none
+    pub fn frame(&mut self, object: Option<usize>){
+        if let Some((center, radius)) = object.and_then(|object| self.object_bounds(object)).or_else(|| self.bounds()){
+            self.camera.frame(center, radius);
+        }
+    }
+
+    //Bounding sphere of the whole scene. The skybox sized ground spheres some scenes
+    //use would swamp everything else, so spheres larger than half the far plane are left out
+    pub fn bounds(&self) -> Option<(Vec3, f32)>{
+        let mut min = Vec3::splat(f32::MAX);
+        let mut max = Vec3::splat(f32::MIN);
+        for i in 0..self.spheres.len() + self.meshes.len(){
+            if let Some((center, radius)) = self.object_bounds(i){
+                if radius > self.camera.far / 2.0{
+                    continue;
+                }
+                min = min.min(center - radius);
+                max = max.max(center + radius);
+            }
+        }
+        if min.x > max.x{
+            return None;
+        }
+        Some(((min + max) / 2.0, (max - min).length() / 2.0))
+    }
+
+    pub fn from_id(id: i32, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> Option<Self>{
+        match id{
+            0 => Some(Scene::balls(device, config)),
+            1 => Some(Scene::random_balls(device, config)),
+            2 => Some(Scene::room(device, config)),
+            3 => Some(Scene::metal(device, config)),
+            4 => Some(Scene::random_lights(device, config)),
+            _ => None,
+        }
+    }
+
     pub fn new(device: &wgpu::Device,config: &wgpu::SurfaceConfiguration)->Self{
         let camera = Camera::new(&device,
             Vec3::new(-2.764473, 5.8210998, 3.839141),
@@ -115,6 +409,10 @@ impl Scene{
             vertices: vec![],
             indices: vec![],
             meshes: vec![],
+            light_bvh: LightBvh::build(&mut [], &mut [], &[], &[]),
+            camera_path: CameraPath::default(),
+            object_tracks: vec![],
+            rigs: vec![],
         }
     }
 
@@ -143,9 +441,11 @@ impl Scene{
                 if (center - Vec3::new(4.0,0.2,0.0)).length() > 0.9{
                     if mat < 0.8{
                         let albedo = Vec4::new(rng.gen::<f32>(),rng.gen::<f32>(),rng.gen::<f32>(),1.0);
+                        //Bouncing, only visible with motion blur on
+                        let motion = Vec3::new(0.0,rng.gen_range(0.0..0.5),0.0);
                         spheres.push(Sphere::new(
                             center,0.2,albedo,Vec4::ZERO,0.0,0.0
-                        ));
+                        ).with_motion(motion));
                     }else if mat < 0.95{
                         let albedo = Vec4::new(rng.gen_range(0.5..1.0),rng.gen_range(0.5..1.0),rng.gen_range(0.5..1.0),1.0);
                         let fuzz = rng.gen_range(0.0..0.5);
@@ -155,6 +455,83 @@ impl Scene{
                     }else{
                         spheres.push(Sphere::new(
                             center,0.2,Vec4::ONE,Vec4::ZERO,0.0,-1.0,
+                        ).with_ior(Ior::WATER));
+                    }
+                }
+            }
+        }
+
+        spheres.push(Sphere::new(
+            Vec3::new(0.0,1.0,0.0),1.0,Vec4::ONE,Vec4::ZERO,0.0,-1.0
+        ).with_ior(Ior::BK7));
+
+        spheres.push(Sphere::new(
+            Vec3::new(-4.0,1.0,0.0),1.0,Vec4::new(0.4,0.2,0.1,1.0),Vec4::ZERO,0.0,0.0
+        ));
+
+        spheres.push(Sphere::new(
+            Vec3::new(4.0,1.0,0.0),1.0,Vec4::new(0.7,0.6,0.5,1.0),Vec4::ZERO,0.0,0.9
+        ));
+
+        let vertices = vec![
+            Vertex::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(2.0,-3.0,-1.0)),
+        ];
+        let indices = vec![
+            1u32,
+        ];
+        let mut meshes = vec![
+            Mesh::new(
+                Vec3::new(0.0,0.0,0.0),
+                0, 0, 0,
+                Vec4::new(0.0,0.6,0.0,1.0),
+                Vec4::new(1.0,1.0,1.0,1.0), 0.0, 0.5,
+            ),
+        ];
+        refit_meshes(&mut meshes, &vertices, &indices);
+        let light_bvh = LightBvh::build(&mut spheres, &mut meshes, &vertices, &indices);
+        Self{
+            camera,
+            spheres,
+            vertices,
+            indices,
+            meshes,
+            light_bvh,
+            camera_path: CameraPath::default(),
+            object_tracks: vec![],
+            rigs: vec![],
+        }
+    }
+    //random_balls at night, lit only by hundreds of small emissive spheres
+    pub fn random_lights(device: &wgpu::Device,config: &wgpu::SurfaceConfiguration)->Self{
+        let camera = Camera::new(device,
+            Vec3::new(10.5,2.0,3.0),
+            Vec3::new(0.0,0.0,0.0),
+            Vec3::new(0.0,1.0,0.0),45.0,
+            config.width as f32/config.height as f32,0.1,100.0,
+            0.0,
+            10.0,
+        );
+        let mut spheres: Vec<Sphere> = vec![
+            Sphere::new(
+                Vec3::new(0.0,-1000.0,0.0),1000.0,Vec4::new(0.5,0.5,0.5,1.0),Vec4::ZERO,0.0,0.0
+            )
+        ];
+
+        let mut rng = rand::thread_rng();
+        for a in -11..11{
+            for b in -11..11{
+                let mat = rng.gen::<f32>();
+                let center = Vec3::new(a as f32 + 0.9 * rng.gen::<f32>(),0.2,b as f32 + 0.9 * rng.gen::<f32>());
+                if (center - Vec3::new(4.0,0.2,0.0)).length() > 0.9{
+                    let albedo = Vec4::new(rng.gen::<f32>(),rng.gen::<f32>(),rng.gen::<f32>(),1.0);
+                    if mat < 0.6{
+                        spheres.push(Sphere::new(
+                            center,0.2,albedo,Vec4::ZERO,0.0,rng.gen_range(0.0..0.8)
+                        ));
+                    }else{
+                        let emission = Vec4::new(rng.gen_range(0.2..1.0),rng.gen_range(0.2..1.0),rng.gen_range(0.2..1.0),1.0);
+                        spheres.push(Sphere::new(
+                            center - Vec3::new(0.0,0.1,0.0),0.1,albedo,emission,rng.gen_range(2.0..8.0),0.0
                         ));
                     }
                 }
@@ -170,7 +547,7 @@ impl Scene{
         ));
 
         spheres.push(Sphere::new(
-            Vec3::new(4.0,1.0,0.0),1.0,Vec4::new(0.7,0.6,0.5,1.0),Vec4::ZERO,0.0,0.9
+            Vec3::new(4.0,1.0,0.0),1.0,Vec4::new(0.7,0.6,0.5,1.0),Vec4::ZERO,0.0,1.0
         ));
 
         let vertices = vec![
@@ -179,7 +556,7 @@ impl Scene{
         let indices = vec![
             1u32,
         ];
-        let meshes = vec![
+        let mut meshes = vec![
             Mesh::new(
                 Vec3::new(0.0,0.0,0.0),
                 0, 0, 0,
@@ -187,12 +564,18 @@ impl Scene{
                 Vec4::new(1.0,1.0,1.0,1.0), 0.0, 0.5,
             ),
         ];
+        refit_meshes(&mut meshes, &vertices, &indices);
+        let light_bvh = LightBvh::build(&mut spheres, &mut meshes, &vertices, &indices);
         Self{
             camera,
             spheres,
             vertices,
             indices,
             meshes,
+            light_bvh,
+            camera_path: CameraPath::default(),
+            object_tracks: vec![],
+            rigs: vec![],
         }
     }
     pub fn room(device: &wgpu::Device,config: &wgpu::SurfaceConfiguration)->Self{
@@ -205,7 +588,7 @@ impl Scene{
             0.1,
         );
 
-        let spheres = vec![
+        let mut spheres = vec![
             Sphere::new(
                 Vec3::new(4.0,0.0,1.7),1.2, 
                 Vec4::new(1.0,1.0,1.0,1.0),
@@ -254,7 +637,6 @@ impl Scene{
             9u32, 10u32, 11u32,
             9u32, 11u32, 8u32,
         ];
-        #[allow(unused_mut)]
         let mut meshes = vec![
             Mesh::new(
                 Vec3::new(3.0,0.0,0.0),
@@ -300,12 +682,18 @@ impl Scene{
             ),
         ];
 
+        refit_meshes(&mut meshes, &vertices, &indices);
+        let light_bvh = LightBvh::build(&mut spheres, &mut meshes, &vertices, &indices);
         Self{
             camera,
             spheres,
             vertices,
             indices,
             meshes,
+            light_bvh,
+            camera_path: CameraPath::default(),
+            object_tracks: vec![],
+            rigs: vec![],
         }
     }
     pub fn metal(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration)->Self{
@@ -326,7 +714,7 @@ impl Scene{
             0.1,
         );
         println!("{:?}",camera);
-        let spheres = vec![
+        let mut spheres = vec![
             //floor
             Sphere::new(
                 Vec3::new(0.0, -100.5,-1.0),100.0, 
@@ -345,7 +733,7 @@ impl Scene{
                 Vec4::new(0.8,0.8,0.8,1.0),
                 Vec4::new(0.0,0.0,0.0,1.0),0.0,
                 -1.0,
-            ),
+            ).with_ior(Ior::SF11),
             Sphere::new(
                 Vec3::new(1.0, 0.0, -1.0),0.5, 
                 Vec4::new(0.8,0.6,0.2,1.0),
@@ -360,7 +748,7 @@ impl Scene{
         let indices = vec![
             1u32,
         ];
-        let meshes = vec![
+        let mut meshes = vec![
             Mesh::new(
                 Vec3::new(0.0,0.0,0.0),
                 0, 0, 0,
@@ -368,12 +756,18 @@ impl Scene{
                 Vec4::new(1.0,1.0,1.0,1.0), 0.0, 0.5,
             ),
         ];
+        refit_meshes(&mut meshes, &vertices, &indices);
+        let light_bvh = LightBvh::build(&mut spheres, &mut meshes, &vertices, &indices);
         Self{
             camera,
             spheres,
             vertices,
             indices,
             meshes,
+            light_bvh,
+            camera_path: CameraPath::default(),
+            object_tracks: vec![],
+            rigs: vec![],
         }
     }
     pub fn balls(device: &wgpu::Device,config: &wgpu::SurfaceConfiguration)->Self{
@@ -389,7 +783,7 @@ impl Scene{
 /*         let camera = Camera::new(&device,Vec3::new(-2.7,1.3,-8.0),Vec3::new(-2.6,1.0,-7.0),Vec3::new(0.0,1.0,0.0),28.0,config.width as f32/config.height as f32,0.1,100.0); */
         println!("{} {}",camera.pitch, camera.yaw);
 
-        let spheres = vec![
+        let mut spheres = vec![
             Sphere::new(
                 Vec3::new(-3.64,-0.42,0.8028),0.75, 
                 Vec4::new(1.0,1.0,1.0,1.0),
@@ -435,7 +829,7 @@ impl Scene{
         let indices = vec![
             1u32,
         ];
-        let meshes = vec![
+        let mut meshes = vec![
             Mesh::new(
                 Vec3::new(0.0,0.0,0.0),
                 0, 0, 0,
@@ -466,26 +860,42 @@ impl Scene{
         //load_model(Path::new("cube2.obj"),&mut vertices, &mut indices, &mut meshes).await.unwrap();
 
 
+        refit_meshes(&mut meshes, &vertices, &indices);
+        let light_bvh = LightBvh::build(&mut spheres, &mut meshes, &vertices, &indices);
         Self{
             camera,
             spheres,
             vertices,
             indices,
             meshes,
+            light_bvh,
+            camera_path: CameraPath::default(),
+            object_tracks: vec![],
+            rigs: vec![],
         }
     }
 
+    pub fn buffers(&self, device: &wgpu::Device) -> SceneBuffers{
+        SceneBuffers{
+            spheres: self.sphere_buffer(device),
+            vertices: self.vertex_buffer(device),
+            indices: self.index_buffer(device),
+            meshes: self.mesh_buffer(device),
+            lights: self.light_bvh.light_buffer(device),
+            light_nodes: self.light_bvh.node_buffer(device),
+        }
+    }
     pub fn sphere_buffer(&self, device: &wgpu::Device)->wgpu::Buffer{
         device.create_buffer_init(&wgpu::util::BufferInitDescriptor{
             label: Some("Sphere Buffer"),
-            contents: bytemuck::cast_slice(&self.spheres),
+            contents: storage_contents(&self.spheres),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST| wgpu::BufferUsages::STORAGE,
         })
     }
     pub fn vertex_buffer(&self, device: &wgpu::Device)->wgpu::Buffer{
         device.create_buffer_init(&wgpu::util::BufferInitDescriptor{
             label: Some("Vertex Buffer"),
-            contents: bytemuck::cast_slice(&self.vertices),
+            contents: storage_contents(&self.vertices),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST| wgpu::BufferUsages::STORAGE,
         })
     }
@@ -493,7 +903,7 @@ impl Scene{
     pub fn index_buffer(&self, device: &wgpu::Device)->wgpu::Buffer{
         device.create_buffer_init(&wgpu::util::BufferInitDescriptor{
             label: Some("Index Buffer"),
-            contents: bytemuck::cast_slice(&self.indices),
+            contents: storage_contents(&self.indices),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST| wgpu::BufferUsages::STORAGE,
         })
     }
@@ -501,8 +911,46 @@ impl Scene{
     pub fn mesh_buffer(&self, device: &wgpu::Device)->wgpu::Buffer{
         device.create_buffer_init(&wgpu::util::BufferInitDescriptor{
             label: Some("Mesh Buffer"),
-            contents: bytemuck::cast_slice(&self.meshes),
+            contents: storage_contents(&self.meshes),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST| wgpu::BufferUsages::STORAGE,
         })
     }
-}
\ No newline at end of file
+}
+
+//Storage buffers can't be empty, so an empty list uploads a single zeroed element instead. A zeroed
+//sphere has no radius and a zeroed mesh no triangles, so nothing ever hits them
+fn storage_contents<T: bytemuck::Pod>(data: &[T]) -> &[u8]{
+    const ZEROS: [u8; 256] = [0; 256];
+    if data.is_empty() {&ZEROS[..std::mem::size_of::<T>()]} else {bytemuck::cast_slice(data)}
+}
+
+//Kept by the ray tracer so an animated scene can be uploaded in place every frame
+pub struct SceneBuffers{
+    pub spheres: wgpu::Buffer,
+    pub vertices: wgpu::Buffer,
+    pub indices: wgpu::Buffer,
+    pub meshes: wgpu::Buffer,
+    pub lights: wgpu::Buffer,
+    pub light_nodes: wgpu::Buffer,
+}
+
+impl SceneBuffers{
+    //Returns false when a buffer no longer fits, e.g. a light was switched off, and they all have to be recreated
+    pub fn write(&self, queue: &wgpu::Queue, scene: &Scene) -> bool{
+        let uploads: [(&wgpu::Buffer, &[u8]); 6] = [
+            (&self.spheres, storage_contents(&scene.spheres)),
+            (&self.vertices, storage_contents(&scene.vertices)),
+            (&self.indices, storage_contents(&scene.indices)),
+            (&self.meshes, storage_contents(&scene.meshes)),
+            (&self.lights, bytemuck::cast_slice(&scene.light_bvh.lights)),
+            (&self.light_nodes, bytemuck::cast_slice(&scene.light_bvh.nodes)),
+        ];
+        if uploads.iter().any(|(buffer, data)| buffer.size() != data.len() as u64){
+            return false;
+        }
+        for (buffer, data) in uploads{
+            queue.write_buffer(buffer, 0, data);
+        }
+        true
+    }
+}
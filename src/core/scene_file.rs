@@ -0,0 +1,142 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context as _};
+use glam::Vec3;
+use serde::Deserialize;
+
+use super::{scene::{Scene, Sphere, SCENE_NAMES}, camera::Camera, light::LightBvh};
+
+//Scene description for headless renders, see assets/example.ron. Every field can be left out:
+//(
+//    base: Some("balls"),
+//    camera: Some((origin: (0.0, 2.0, -6.0), look_at: (0.0, 1.0, 0.0), fov: 40.0)),
+//    spheres: [(position: (0.0, 1.0, 0.0), radius: 1.0, color: (0.8, 0.2, 0.2), smoothness: -1.0)],
+//    models: [(path: "monkey.glb", position: (2.0, 0.0, 0.0), emission_color: (1.0, 1.0, 1.0), emission_strength: 4.0)],
+//)
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+pub struct SceneFile{
+    //One of SCENE_NAMES to add to, an empty scene when left out
+    pub base: Option<String>,
+    //Everything is framed when there's no camera and no base scene to take it from
+    pub camera: Option<CameraDescription>,
+    pub spheres: Vec<SphereDescription>,
+    pub models: Vec<ModelDescription>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CameraDescription{
+    pub origin: [f32;3],
+    pub look_at: [f32;3],
+    #[serde(default = "default_fov")]
+    pub fov: f32,
+    //0 is a pinhole
+    #[serde(default)]
+    pub aperture: f32,
+    #[serde(default = "default_focus_dist")]
+    pub focus_dist: f32,
+}
+
+//Smoothness -1 is glass, like Sphere::new
+#[derive(Debug, Deserialize)]
+pub struct SphereDescription{
+    pub position: [f32;3],
+    pub radius: f32,
+    #[serde(default = "default_color")]
+    pub color: [f32;3],
+    #[serde(default)]
+    pub emission_color: [f32;3],
+    #[serde(default)]
+    pub emission_strength: f32,
+    #[serde(default)]
+    pub smoothness: f32,
+}
+
+//glTF or obj file in the assets folder, every mesh in it gets the same position and material
+#[derive(Debug, Deserialize)]
+pub struct ModelDescription{
+    pub path: String,
+    #[serde(default)]
+    pub position: [f32;3],
+    #[serde(default = "default_color")]
+    pub color: [f32;3],
+    #[serde(default)]
+    pub emission_color: [f32;3],
+    #[serde(default)]
+    pub emission_strength: f32,
+    #[serde(default = "default_smoothness")]
+    pub smoothness: f32,
+}
+
+fn default_fov() -> f32{
+    45.0
+}
+
+fn default_focus_dist() -> f32{
+    10.0
+}
+
+fn default_color() -> [f32;3]{
+    [0.8, 0.8, 0.8]
+}
+
+fn default_smoothness() -> f32{
+    0.5
+}
+
+impl SceneFile{
+    pub fn load(path: &Path) -> anyhow::Result<Self>{
+        let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        ron::from_str(&text).with_context(|| format!("Invalid scene file {}", path.display()))
+    }
+
+    pub fn build(&self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> anyhow::Result<Scene>{
+        let mut scene = match &self.base{
+            Some(base) => {
+                let id = SCENE_NAMES.iter().position(|name| name == base)
+                    .ok_or_else(|| anyhow!("Unknown base scene {}, expected one of {}", base, SCENE_NAMES.join(", ")))?;
+                Scene::from_id(id as i32, device, config).ok_or_else(|| anyhow!("Unknown base scene {}", base))?
+            }
+            None => Scene::new(device, config),
+        };
+        for sphere in &self.spheres{
+            scene.spheres.push(Sphere::new(
+                Vec3::from(sphere.position),
+                sphere.radius,
+                Vec3::from(sphere.color).extend(1.0),
+                Vec3::from(sphere.emission_color).extend(1.0),
+                sphere.emission_strength,
+                sphere.smoothness,
+            ));
+        }
+        for model in &self.models{
+            let first = scene.meshes.len();
+            scene.load_model(&model.path)?;
+            for mesh in &mut scene.meshes[first..]{
+                mesh.pos = model.position;
+                mesh.color = Vec3::from(model.color).extend(1.0).to_array();
+                mesh.emission_color = Vec3::from(model.emission_color).extend(1.0).to_array();
+                mesh.emission_strength = model.emission_strength;
+                mesh.specular = model.smoothness.min(1.0);
+            }
+        }
+        //Materials and positions changed after the last model was loaded
+        scene.light_bvh = LightBvh::build(&mut scene.spheres, &mut scene.meshes, &scene.vertices, &scene.indices);
+
+        match &self.camera{
+            Some(camera) => {
+                scene.camera = Camera::new(device,
+                    Vec3::from(camera.origin),
+                    Vec3::from(camera.look_at),
+                    Vec3::Y, camera.fov,
+                    config.width as f32 / config.height as f32, 0.1, 100.0,
+                    camera.aperture,
+                    camera.focus_dist,
+                );
+            }
+            None if self.base.is_none() => scene.frame(None),
+            None => {}
+        }
+        Ok(scene)
+    }
+}
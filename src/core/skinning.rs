@@ -0,0 +1,209 @@
+use glam::{Mat4, Quat, Vec3};
+use gltf::animation::Interpolation;
+
+use super::scene::{Mesh, Vertex};
+
+#[derive(Debug, Copy, Clone)]
+pub struct Transform{
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Transform{
+    pub fn matrix(&self) -> Mat4{
+        Mat4::from_scale_rotation_translation(self.scale, self.rotation, self.translation)
+    }
+}
+
+pub struct Node{
+    pub parent: Option<usize>,
+    //Transform from the file, channels of the playing clip replace parts of it
+    pub rest: Transform,
+    pub morph_weights: Vec<f32>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Property{
+    Translation,
+    Rotation,
+    Scale,
+    MorphWeights,
+}
+
+pub struct Channel{
+    pub node: usize,
+    pub property: Property,
+    pub interpolation: Interpolation,
+    pub times: Vec<f32>,
+    //Flattened outputs, cubic splines store in tangent, value and out tangent for every key
+    pub values: Vec<f32>,
+}
+
+impl Channel{
+    //Number of floats in one value
+    fn width(&self) -> usize{
+        let keys = self.times.len() * if self.interpolation == Interpolation::CubicSpline {3} else {1};
+        self.values.len().checked_div(keys).unwrap_or(0)
+    }
+
+    fn key(&self, key: usize, part: usize) -> &[f32]{
+        let width = self.width();
+        let index = if self.interpolation == Interpolation::CubicSpline {key * 3 + part} else {key};
+        &self.values[index * width..(index + 1) * width]
+    }
+
+    pub fn sample(&self, time: f32) -> Vec<f32>{
+        let value = if self.interpolation == Interpolation::CubicSpline {1} else {0};
+        let last = self.times.len() - 1;
+        let i = self.times.partition_point(|t| *t <= time);
+        if i == 0{
+            return self.key(0, value).to_vec();
+        }
+        if i > last{
+            return self.key(last, value).to_vec();
+        }
+        let (k0, k1) = (i - 1, i);
+        let dt = self.times[k1] - self.times[k0];
+        let t = (time - self.times[k0]) / dt;
+        match self.interpolation{
+            Interpolation::Step => self.key(k0, 0).to_vec(),
+            Interpolation::Linear if self.property == Property::Rotation => {
+                let a = Quat::from_slice(self.key(k0, 0));
+                let b = Quat::from_slice(self.key(k1, 0));
+                a.slerp(b, t).to_array().to_vec()
+            }
+            Interpolation::Linear => {
+                self.key(k0, 0).iter().zip(self.key(k1, 0)).map(|(a, b)| a + (b - a) * t).collect()
+            }
+            //Hermite spline from the glTF spec, tangents are scaled by the key spacing
+            Interpolation::CubicSpline => {
+                let t2 = t * t;
+                let t3 = t2 * t;
+                let (p0, m0) = (self.key(k0, 1), self.key(k0, 2));
+                let (p1, m1) = (self.key(k1, 1), self.key(k1, 0));
+                let mut v: Vec<f32> = (0..p0.len()).map(|c|{
+                    (2.0 * t3 - 3.0 * t2 + 1.0) * p0[c]
+                        + (t3 - 2.0 * t2 + t) * dt * m0[c]
+                        + (-2.0 * t3 + 3.0 * t2) * p1[c]
+                        + (t3 - t2) * dt * m1[c]
+                }).collect();
+                if self.property == Property::Rotation{
+                    v = Quat::from_slice(&v).normalize().to_array().to_vec();
+                }
+                v
+            }
+        }
+    }
+}
+
+pub struct Clip{
+    pub name: String,
+    pub channels: Vec<Channel>,
+    pub duration: f32,
+}
+
+pub struct Skin{
+    pub joints: Vec<usize>,
+    pub inverse_bind: Vec<Mat4>,
+}
+
+//One primitive that was loaded into the scene's meshes and gets deformed every frame
+pub struct Deformed{
+    pub mesh: usize,
+    pub node: usize,
+    pub skin: Option<usize>,
+    //Vertices as loaded, posing always starts from these
+    pub rest: Vec<Vertex>,
+    pub joints: Vec<[u16;4]>,
+    pub weights: Vec<[f32;4]>,
+    //Position and normal offsets per morph target and vertex
+    pub targets: Vec<Vec<[Vec3;2]>>,
+}
+
+//Node hierarchy, skins and animation clips of one glTF file
+pub struct Rig{
+    pub nodes: Vec<Node>,
+    pub skins: Vec<Skin>,
+    pub primitives: Vec<Deformed>,
+    pub clips: Vec<Clip>,
+    pub clip: usize,
+    pub speed: f32,
+}
+
+impl Rig{
+    //Node transforms and morph weights of the current clip, looped over its duration
+    fn pose_nodes(&self, time: f32) -> (Vec<Mat4>, Vec<Vec<f32>>){
+        let mut locals: Vec<Transform> = self.nodes.iter().map(|n| n.rest).collect();
+        let mut morph_weights: Vec<Vec<f32>> = self.nodes.iter().map(|n| n.morph_weights.clone()).collect();
+        if let Some(clip) = self.clips.get(self.clip){
+            let time = if clip.duration > 0.0 {(time * self.speed).rem_euclid(clip.duration)} else {0.0};
+            for channel in clip.channels.iter().filter(|c| !c.times.is_empty()){
+                let v = channel.sample(time);
+                let local = &mut locals[channel.node];
+                match channel.property{
+                    Property::Translation => local.translation = Vec3::from_slice(&v),
+                    Property::Rotation => local.rotation = Quat::from_slice(&v).normalize(),
+                    Property::Scale => local.scale = Vec3::from_slice(&v),
+                    Property::MorphWeights => morph_weights[channel.node] = v,
+                }
+            }
+        }
+        let mut globals: Vec<Option<Mat4>> = vec![None; self.nodes.len()];
+        for i in 0..self.nodes.len(){
+            self.global(i, &locals, &mut globals);
+        }
+        (globals.into_iter().map(|g| g.unwrap_or(Mat4::IDENTITY)).collect(), morph_weights)
+    }
+
+    fn global(&self, node: usize, locals: &[Transform], globals: &mut [Option<Mat4>]) -> Mat4{
+        if let Some(global) = globals[node]{
+            return global;
+        }
+        let local = locals[node].matrix();
+        let global = match self.nodes[node].parent{
+            Some(parent) => self.global(parent, locals, globals) * local,
+            None => local,
+        };
+        globals[node] = Some(global);
+        global
+    }
+
+    //Applies morph targets then skinning to the rest vertices and writes them into the scene.
+    //Meshes without a skin are moved by their node instead
+    pub fn pose(&self, time: f32, vertices: &mut [Vertex], meshes: &[Mesh]){
+        let (globals, morph_weights) = self.pose_nodes(time);
+        let joint_matrices: Vec<Vec<Mat4>> = self.skins.iter().map(|skin|{
+            skin.joints.iter().zip(&skin.inverse_bind).map(|(joint, inverse_bind)| globals[*joint] * *inverse_bind).collect()
+        }).collect();
+        for primitive in &self.primitives{
+            let offset = meshes[primitive.mesh].offset as usize;
+            let weights = &morph_weights[primitive.node];
+            let node_normal_matrix = globals[primitive.node].inverse().transpose();
+            for (i, rest) in primitive.rest.iter().enumerate(){
+                let mut pos = Vec3::from(rest.pos);
+                let mut normal = Vec3::from(rest.normal);
+                for (target, weight) in primitive.targets.iter().zip(weights){
+                    pos += target[i][0] * *weight;
+                    normal += target[i][1] * *weight;
+                }
+                let (matrix, normal_matrix) = match primitive.skin{
+                    Some(skin) => {
+                        let joints = primitive.joints[i];
+                        let weights = primitive.weights[i];
+                        let matrix = (0..4).fold(Mat4::ZERO, |m, j| m + joint_matrices[skin][joints[j] as usize] * weights[j]);
+                        (matrix, matrix.inverse().transpose())
+                    }
+                    None => (globals[primitive.node], node_normal_matrix),
+                };
+                let vertex = &mut vertices[offset + i];
+                vertex.pos = matrix.transform_point3(pos).to_array();
+                vertex.normal = normal_matrix.transform_vector3(normal).normalize_or_zero().to_array();
+            }
+        }
+    }
+
+    pub fn duration(&self) -> f32{
+        self.clips.get(self.clip).map_or(0.0, |c| c.duration / self.speed.max(1e-3))
+    }
+}
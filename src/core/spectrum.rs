@@ -0,0 +1,47 @@
+//Indexed by the DISPERSION_* ids in ray_tracer.wgsl
+pub const DISPERSION_NONE: u32 = 0;
+pub const DISPERSION_CAUCHY: u32 = 1;
+pub const DISPERSION_SELLMEIER: u32 = 2;
+
+//Wavelengths are in micrometers for both models
+#[derive(Debug, Copy, Clone)]
+pub enum Ior{
+    Constant(f32),
+    //n = a + b/l^2 + c/l^4
+    Cauchy{a: f32, b: f32, c: f32},
+    //n^2 = 1 + sum(b_i*l^2 / (l^2 - c_i))
+    Sellmeier{b: [f32;3], c: [f32;3]},
+}
+
+impl Ior{
+    pub const GLASS: Ior = Ior::Constant(1.5);
+    //Schott N-BK7 crown glass
+    pub const BK7: Ior = Ior::Sellmeier{
+        b: [1.039_612, 0.231_792_34, 1.010_469_5],
+        c: [0.006_000_699, 0.020_017_914, 103.560_65],
+    };
+    //Schott SF11 dense flint, much stronger dispersion than BK7
+    pub const SF11: Ior = Ior::Sellmeier{
+        b: [1.737_597, 0.313_747_35, 1.898_781],
+        c: [0.013_188_707, 0.062_306_814, 155.236_3],
+    };
+    pub const WATER: Ior = Ior::Cauchy{a: 1.3199, b: 0.006878, c: -0.000_013_2};
+
+    //(DISPERSION_*, ior at the sodium d line, b coefficients, c coefficients)
+    pub fn coefficients(&self) -> (u32, f32, [f32;3], [f32;3]){
+        match *self{
+            Ior::Constant(ior) => (DISPERSION_NONE, ior, [0.0;3], [0.0;3]),
+            Ior::Cauchy{a, b, c} => (DISPERSION_CAUCHY, self.at(0.5876), [a, b, c], [0.0;3]),
+            Ior::Sellmeier{b, c} => (DISPERSION_SELLMEIER, self.at(0.5876), b, c),
+        }
+    }
+
+    pub fn at(&self, wavelength: f32) -> f32{
+        let l2 = wavelength * wavelength;
+        match *self{
+            Ior::Constant(ior) => ior,
+            Ior::Cauchy{a, b, c} => a + b / l2 + c / (l2 * l2),
+            Ior::Sellmeier{b, c} => (1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f32>()).sqrt(),
+        }
+    }
+}
@@ -1,5 +1,8 @@
 pub struct Texture {
+    pub texture: wgpu::Texture,
     texture_view: wgpu::TextureView,
+    pub width: u32,
+    pub height: u32,
 }
 
 impl Texture {
@@ -20,16 +23,67 @@ impl Texture {
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format,
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::COPY_DST,
             view_formats: &[],
         });
         let texture_view = texture.create_view(&wgpu::TextureViewDescriptor::default());
 
         Texture {
+            texture,
             texture_view,
+            width,
+            height,
         }
     }
 
+    //Blocks until the Rgba32Float texels are back on the cpu. The display quad shows the texture
+    //turned half way round, so the rows are returned top to bottom as seen on screen
+    pub fn read(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<f32>{
+        let pixel_size = 4 * std::mem::size_of::<f32>() as u32;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let bytes_per_row = (self.width * pixel_size).next_multiple_of(align);
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor{
+            label: Some("Texture Readback Buffer"),
+            size: (bytes_per_row * self.height) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor{
+            label: Some("Texture Readback Encoder")
+        });
+        encoder.copy_texture_to_buffer(
+            self.texture.as_image_copy(),
+            wgpu::ImageCopyBuffer{
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout{
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d{
+                width: self.width,
+                height: self.height,
+                depth_or_array_layers: 1,
+            },
+        );
+        queue.submit(Some(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |_| {});
+        device.poll(wgpu::Maintain::Wait);
+        let mut pixels = Vec::with_capacity((self.width * self.height * 4) as usize);
+        {
+            let data = slice.get_mapped_range();
+            for row in data.chunks(bytes_per_row as usize){
+                pixels.extend_from_slice(bytemuck::cast_slice::<u8, f32>(&row[..(self.width * pixel_size) as usize]));
+            }
+        }
+        buffer.unmap();
+        //Reversing every pixel flips both axes
+        pixels.chunks(4).rev().flatten().copied().collect()
+    }
+
     pub fn binding_resource(&self) -> wgpu::BindingResource {
         wgpu::BindingResource::TextureView(&self.texture_view)
     }
@@ -0,0 +1,58 @@
+use glam::{Mat3, Vec3};
+
+//Same order and curves as tone_map in render.wgsl, exported pngs go through these so they match the screen
+pub const TONEMAP_NONE: i32 = 0;
+pub const TONEMAP_REINHARD: i32 = 1;
+pub const TONEMAP_ACES: i32 = 2;
+pub const TONEMAP_AGX: i32 = 3;
+pub const TONEMAP_UNCHARTED2: i32 = 4;
+pub const TONEMAP_NAMES: [&str; 5] = ["None (clip)", "Reinhard", "ACES filmic", "AgX", "Uncharted 2"];
+
+//Exposed linear radiance to linear display values in [0, 1]. white_point is the radiance that maps
+//to white, AgX has a fixed range and ignores it
+pub fn tone_map(operator: i32, color: Vec3, white_point: f32) -> Vec3{
+    let white = white_point.max(1e-3);
+    let mapped = match operator{
+        //Extended Reinhard, per channel
+        TONEMAP_REINHARD => color * (Vec3::ONE + color / (white * white)) / (Vec3::ONE + color),
+        TONEMAP_ACES => aces(color) / aces(Vec3::splat(white)),
+        TONEMAP_AGX => agx(color),
+        TONEMAP_UNCHARTED2 => uncharted2(color * 2.0) / uncharted2(Vec3::splat(2.0 * white)),
+        _ => color,
+    };
+    mapped.clamp(Vec3::ZERO, Vec3::ONE)
+}
+
+//Narkowicz 2015 fit of the ACES reference rendering transform
+fn aces(x: Vec3) -> Vec3{
+    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+}
+
+//Hable 2010
+fn uncharted2(x: Vec3) -> Vec3{
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+//Sobotka's AgX with the polynomial contrast fit from Wrensch 2023
+fn agx(color: Vec3) -> Vec3{
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+    let inset = Mat3::from_cols_array(&[
+        0.84247906, 0.042328242, 0.042375655,
+        0.0784336, 0.87846864, 0.0784336,
+        0.079223745, 0.07916613, 0.879143,
+    ]);
+    let outset = Mat3::from_cols_array(&[
+        1.196879, -0.052896852, -0.052971636,
+        -0.09802088, 1.1519031, -0.09804345,
+        -0.09902974, -0.098961177, 1.1510737,
+    ]);
+    let v = inset * color.max(Vec3::splat(1e-10));
+    let x = (Vec3::new(v.x.log2(), v.y.log2(), v.z.log2()).clamp(Vec3::splat(MIN_EV), Vec3::splat(MAX_EV)) - MIN_EV) / (MAX_EV - MIN_EV);
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    let curve = 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - Vec3::splat(0.00232);
+    //The curve targets a 2.2 gamma display, the surface and png encode to srgb themselves
+    (outset * curve).max(Vec3::ZERO).powf(2.2)
+}
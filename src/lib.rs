@@ -7,11 +7,21 @@ use window::Window;
 
 mod core;
 
-pub async fn run(){
+fn init_logger(){
     env_logger::builder()
         .filter_module("ray_tracer", log::LevelFilter::Info)
         .filter_module("wgpu_core", log::LevelFilter::Warn)
         .init();
+}
+
+//Offline render without a window, args are everything after `ray_tracer render`
+pub async fn render(args: &[String]) -> anyhow::Result<()>{
+    init_logger();
+    headless::render(headless::RenderArgs::parse(args)?).await
+}
+
+pub async fn run(){
+    init_logger();
     log::info!("Starting Ray Tracer");
     log::info!("Creating Window: 800x600");
     let window: Window = Window::new().title("Ray Tracer").size(800,800).build();
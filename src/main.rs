@@ -1,6 +1,14 @@
-use ray_tracer::run;
+use ray_tracer::{run, render};
 
 #[tokio::main]
 async fn main() {
-    run().await;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("render"){
+        if let Err(e) = render(&args[1..]).await{
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }else{
+        run().await;
+    }
 }